@@ -0,0 +1,53 @@
+//! Headless plugin validator. Loads every plugin in a directory the same way the game does
+//! and prints what was found, without opening a window.
+//!
+//! Usage: openft-plugin-lint [--json] [plugin directory...]
+//!
+//! Each directory is a plugin root, searched in the order given. With no directories the
+//! game's default search paths are used.
+
+use openft::plugin_manager::plugin_manager::*;
+use std::{env, path::PathBuf, process::ExitCode};
+
+fn main() -> ExitCode {
+    let mut json = false;
+    let mut roots = Vec::new();
+
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("Usage: openft-plugin-lint [--json] [plugin directory...]");
+                return ExitCode::SUCCESS;
+            }
+            _ => roots.push(PathBuf::from(arg)),
+        }
+    }
+
+    let explicit = !roots.is_empty();
+    if !explicit {
+        roots = plugin_search_paths(&[]);
+    }
+
+    let plugin_dirs = enumerate_plugins(&roots);
+    if explicit && !plugin_dirs.missing_roots.is_empty() {
+        for root in &plugin_dirs.missing_roots {
+            eprintln!("Couldn't read plugin directory {}", root.display());
+        }
+        return ExitCode::from(2);
+    }
+
+    let (_, report) = load_plugins(plugin_dirs);
+
+    if json {
+        println!("{:#}", report.to_json());
+    } else {
+        print!("{}", report);
+    }
+
+    if report.has_errors() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
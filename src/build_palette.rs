@@ -0,0 +1,502 @@
+pub mod build_palette {
+    use crate::{
+        contribution_registry::contribution_registry::ContributionKey,
+        lighting::lighting::Light,
+        plugin_manager::plugin_manager::{ColorMapping, ColorMappingChannel},
+        texture_manager::texture_manager::{draw_entity, sprite_height},
+        tilemap_manager::tilemap_manager::{EntityInfo, TileMap},
+        util::util::{TILE_H_HALF, TILE_W_HALF},
+    };
+    use macroquad::prelude::*;
+    use std::ops::RangeInclusive;
+
+    const PANEL_TOP: f32 = 75.0;
+    const PANEL_WIDTH: f32 = 212.0;
+    const GAP: f32 = 4.0;
+    const THUMBNAIL: f32 = 48.0;
+    const COLUMNS: usize = 4;
+    const HEADER: f32 = 18.0;
+    const SWATCH: f32 = 16.0;
+    /// Room at the bottom of the panel for the selected structure's name, colours and height.
+    const CONTROLS: f32 = 96.0;
+    const BACKGROUND: Color = Color {
+        r: 0.1,
+        g: 0.1,
+        b: 0.12,
+        a: 0.85,
+    };
+
+    /// Something the player can build: the entity types one contribution was loaded as.
+    #[derive(Debug, Clone)]
+    pub struct PaletteEntry {
+        pub key: ContributionKey,
+        pub name: String,
+        /// The FreeTrain group the structure is listed under within its plugin.
+        pub group: String,
+        /// The entity types for each colour the contribution was loaded in.
+        pub colours: Vec<PaletteColour>,
+        /// How many storeys it can be built with, if it is multistorey.
+        pub heights: Option<RangeInclusive<usize>>,
+    }
+
+    /// One colour of a structure: the colour its sprites were mapped to, if any, and an
+    /// entity type per orientation.
+    #[derive(Debug, Clone)]
+    pub struct PaletteColour {
+        pub colour: Option<Color>,
+        pub orientations: Vec<usize>,
+    }
+
+    impl PaletteEntry {
+        /// An entry for the entity types made from a contribution. `types` pairs each
+        /// entity type with the index of the colour mapping it was loaded with; types with
+        /// the same mapping are orientations of the same colour, in order.
+        pub fn new(
+            key: ContributionKey,
+            name: &str,
+            group: &str,
+            types: impl IntoIterator<Item = (usize, usize)>,
+            color_mappings: &[ColorMapping],
+            heights: Option<RangeInclusive<usize>>,
+        ) -> PaletteEntry {
+            let mut mappings: Vec<usize> = Vec::new();
+            let mut colours: Vec<PaletteColour> = Vec::new();
+            for (t, mapping) in types {
+                match mappings.iter().position(|m| *m == mapping) {
+                    Some(i) => colours[i].orientations.push(t),
+                    None => {
+                        let colour = color_mappings
+                            .get(mapping)
+                            .filter(|m| !matches!(m.channel, ColorMappingChannel::None))
+                            .map(|m| m.target);
+                        mappings.push(mapping);
+                        colours.push(PaletteColour {
+                            colour,
+                            orientations: vec![t],
+                        });
+                    }
+                }
+            }
+
+            PaletteEntry {
+                key,
+                name: name.to_string(),
+                group: group.to_string(),
+                colours,
+                heights,
+            }
+        }
+
+        fn orientations(&self) -> usize {
+            self.colours
+                .iter()
+                .map(|c| c.orientations.len())
+                .max()
+                .unwrap_or(0)
+        }
+    }
+
+    /// A part of the palette: a header, a thumbnail, or one of the controls for the
+    /// selected structure.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Item {
+        /// The plugin title above the first entry from a plugin.
+        Plugin(usize),
+        /// The group name above the first entry in a group.
+        Group(usize),
+        Entry(usize),
+        Colour(usize),
+        Rotate,
+        Lower,
+        Higher,
+    }
+
+    fn order(entry: &PaletteEntry) -> (&str, &str, &str) {
+        (&entry.key.namespace, &entry.group, &entry.name)
+    }
+
+    /// The build palette: structures from every plugin, grouped by plugin and then by
+    /// group, shown as thumbnails down the left of the screen. Clicking a thumbnail picks
+    /// what a click on the map builds.
+    #[derive(Debug)]
+    pub struct BuildPalette {
+        entries: Vec<PaletteEntry>,
+        selected: Option<usize>,
+        colour: usize,
+        orientation: usize,
+        height: usize,
+        scroll: f32,
+        pub visible: bool,
+    }
+
+    impl Default for BuildPalette {
+        fn default() -> Self {
+            BuildPalette {
+                entries: Vec::new(),
+                selected: None,
+                colour: 0,
+                orientation: 0,
+                height: 1,
+                scroll: 0.0,
+                visible: true,
+            }
+        }
+    }
+
+    impl BuildPalette {
+        pub fn new() -> BuildPalette {
+            BuildPalette::default()
+        }
+
+        /// Add an entry, keeping entries sorted by plugin, group and name. Entries with no
+        /// entity types are left out.
+        pub fn add(&mut self, entry: PaletteEntry) {
+            if entry.colours.is_empty() {
+                return;
+            }
+            let at = self.entries.partition_point(|e| order(e) <= order(&entry));
+            if let Some(selected) = self.selected.as_mut().filter(|s| **s >= at) {
+                *selected += 1;
+            }
+            self.entries.insert(at, entry);
+        }
+
+        pub fn entries(&self) -> &[PaletteEntry] {
+            &self.entries
+        }
+
+        pub fn selected(&self) -> Option<&PaletteEntry> {
+            self.entries.get(self.selected?)
+        }
+
+        /// Pick an entry to build, in its first colour and orientation at its lowest height.
+        pub fn select(&mut self, index: usize) {
+            if let Some(entry) = self.entries.get(index) {
+                self.selected = Some(index);
+                self.colour = 0;
+                self.orientation = 0;
+                self.height = entry.heights.as_ref().map_or(1, |h| *h.start());
+            }
+        }
+
+        /// Pick a colour for the selected structure, keeping its orientation.
+        pub fn select_colour(&mut self, colour: usize) {
+            if let Some(entry) = self.selected() {
+                self.colour = colour.min(entry.colours.len() - 1);
+            }
+        }
+
+        /// Turn the selected structure to its next orientation.
+        pub fn rotate(&mut self) {
+            if let Some(entry) = self.selected() {
+                self.orientation = (self.orientation + 1) % entry.orientations();
+            }
+        }
+
+        /// Set how many storeys to build, within what the selected structure allows.
+        pub fn set_height(&mut self, height: usize) {
+            if let Some(heights) = self.selected().and_then(|e| e.heights.clone()) {
+                self.height = height.clamp(*heights.start(), *heights.end());
+            }
+        }
+
+        pub fn height(&self) -> usize {
+            self.height
+        }
+
+        /// The entity type a click on the map builds, and the info to build it with.
+        pub fn placement(&self) -> Option<(usize, Option<EntityInfo>)> {
+            let entry = self.selected()?;
+            let orientations = &entry.colours[self.colour].orientations;
+            let entity_type = orientations[self.orientation.min(orientations.len() - 1)];
+            let entity_info = entry.heights.as_ref().map(|_| EntityInfo {
+                height: self.height,
+            });
+            Some((entity_type, entity_info))
+        }
+
+        /// Whether a point on the screen is over the palette, so clicks there aren't for
+        /// the map.
+        pub fn contains(&self, point: Vec2) -> bool {
+            self.visible && point.x < PANEL_WIDTH && point.y >= PANEL_TOP
+        }
+
+        /// Scroll with the mouse wheel and handle clicks on the palette.
+        pub fn update(&mut self, mouse: Vec2) {
+            if !self.contains(mouse) {
+                return;
+            }
+
+            let (_, wheel) = mouse_wheel();
+            if wheel != 0.0 {
+                let most = (self.content_height() - list_height()).max(0.0);
+                let scroll = self.scroll - wheel.signum() * (THUMBNAIL + GAP);
+                self.scroll = scroll.clamp(0.0, most);
+            }
+
+            if !is_mouse_button_pressed(MouseButton::Left) {
+                return;
+            }
+            let in_list = mouse.y < PANEL_TOP + list_height();
+            let hit = self
+                .layout()
+                .into_iter()
+                .filter(|_| in_list)
+                .chain(self.controls())
+                .find(|(rect, _)| rect.contains(mouse));
+            match hit {
+                Some((_, Item::Entry(i))) => self.select(i),
+                Some((_, Item::Colour(c))) => self.select_colour(c),
+                Some((_, Item::Rotate)) => self.rotate(),
+                Some((_, Item::Lower)) => self.set_height(self.height.saturating_sub(1)),
+                Some((_, Item::Higher)) => self.set_height(self.height + 1),
+                _ => (),
+            }
+        }
+
+        pub fn draw(&self, map: &TileMap) {
+            if !self.visible {
+                return;
+            }
+            draw_rectangle(
+                0.0,
+                PANEL_TOP,
+                PANEL_WIDTH,
+                screen_height() - PANEL_TOP,
+                BACKGROUND,
+            );
+
+            // Anything partly scrolled out of the list is left out.
+            let bottom = PANEL_TOP + list_height();
+            for (rect, item) in self.layout() {
+                if rect.y < PANEL_TOP || rect.y + rect.h > bottom {
+                    continue;
+                }
+                let baseline = rect.y + rect.h - 4.0;
+                match item {
+                    Item::Plugin(i) => {
+                        let title = &self.entries[i].key.namespace;
+                        draw_text(title, rect.x, baseline, 20.0, WHITE);
+                    }
+                    Item::Group(i) => {
+                        let group = &self.entries[i].group;
+                        draw_text(group, rect.x, baseline, 16.0, LIGHTGRAY);
+                    }
+                    Item::Entry(i) => {
+                        let outline = match self.selected == Some(i) {
+                            true => YELLOW,
+                            false => DARKGRAY,
+                        };
+                        draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, 1.0, outline);
+                        let entity_type = self.entries[i].colours[0].orientations[0];
+                        draw_thumbnail(map, entity_type, &None, rect);
+                    }
+                    _ => (),
+                }
+            }
+
+            self.draw_controls(map);
+        }
+
+        fn draw_controls(&self, map: &TileMap) {
+            let top = screen_height() - CONTROLS;
+            draw_line(0.0, top, PANEL_WIDTH, top, 1.0, DARKGRAY);
+
+            let entry = match self.selected() {
+                Some(entry) => entry,
+                None => {
+                    draw_text("Pick something to build", GAP, top + HEADER, 16.0, GRAY);
+                    return;
+                }
+            };
+            draw_text(&entry.name, GAP, top + HEADER, 16.0, WHITE);
+
+            for (rect, item) in self.controls() {
+                match item {
+                    Item::Colour(c) => {
+                        let colour = entry.colours[c].colour.unwrap_or(GRAY);
+                        draw_rectangle(rect.x, rect.y, rect.w, rect.h, colour);
+                        if c == self.colour {
+                            draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, 2.0, YELLOW);
+                        }
+                    }
+                    Item::Rotate => {
+                        draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, 1.0, DARKGRAY);
+                        draw_text("Rotate", rect.x + 4.0, rect.y + 13.0, 16.0, WHITE);
+                    }
+                    Item::Lower => draw_text("-", rect.x + 4.0, rect.y + 13.0, 20.0, WHITE),
+                    Item::Higher => draw_text("+", rect.x + 4.0, rect.y + 13.0, 20.0, WHITE),
+                    _ => (),
+                }
+            }
+
+            if entry.heights.is_some() {
+                let text = format!("{} storeys", self.height);
+                let x = GAP + 2.0 * (SWATCH + GAP);
+                draw_text(&text, x, top + CONTROLS - GAP - 4.0, 16.0, WHITE);
+            }
+
+            // A bigger preview of exactly what will be built.
+            if let Some((entity_type, entity_info)) = self.placement() {
+                let x = PANEL_WIDTH - THUMBNAIL - GAP;
+                let preview = Rect::new(x, top + HEADER + GAP, THUMBNAIL, THUMBNAIL);
+                draw_thumbnail(map, entity_type, &entity_info, preview);
+            }
+        }
+
+        /// Where the headers and thumbnails go, allowing for the scroll position. Each
+        /// plugin and each group within it starts on a new row.
+        fn layout(&self) -> Vec<(Rect, Item)> {
+            let mut items = Vec::new();
+            let mut y = PANEL_TOP + GAP - self.scroll;
+            let mut column = 0;
+            let mut last: Option<&PaletteEntry> = None;
+            for (i, entry) in self.entries.iter().enumerate() {
+                let new_plugin = last.is_none_or(|l| l.key.namespace != entry.key.namespace);
+                let new_group = new_plugin || last.is_some_and(|l| l.group != entry.group);
+                if new_group {
+                    if last.is_some() {
+                        y += THUMBNAIL + GAP;
+                    }
+                    if new_plugin {
+                        items.push((Rect::new(GAP, y, PANEL_WIDTH, HEADER), Item::Plugin(i)));
+                        y += HEADER;
+                    }
+                    if !entry.group.is_empty() {
+                        items.push((Rect::new(2.0 * GAP, y, PANEL_WIDTH, HEADER), Item::Group(i)));
+                        y += HEADER;
+                    }
+                    column = 0;
+                } else if column == COLUMNS {
+                    y += THUMBNAIL + GAP;
+                    column = 0;
+                }
+                let x = GAP + column as f32 * (THUMBNAIL + GAP);
+                items.push((Rect::new(x, y, THUMBNAIL, THUMBNAIL), Item::Entry(i)));
+                column += 1;
+                last = Some(entry);
+            }
+            items
+        }
+
+        /// How tall the whole list is, scrolled to the top.
+        fn content_height(&self) -> f32 {
+            match self.layout().last() {
+                Some((rect, _)) => rect.y + rect.h + GAP + self.scroll - PANEL_TOP,
+                None => 0.0,
+            }
+        }
+
+        /// The colour swatches, rotate button and height buttons for the selected
+        /// structure. Swatches go in up to two rows beside the preview, and the rotate
+        /// button goes under it.
+        fn controls(&self) -> Vec<(Rect, Item)> {
+            let entry = match self.selected() {
+                Some(entry) => entry,
+                None => return Vec::new(),
+            };
+            let top = screen_height() - CONTROLS;
+            let mut controls = Vec::new();
+
+            if entry.colours.len() > 1 {
+                let per_row = ((PANEL_WIDTH - THUMBNAIL - 2.0 * GAP) / (SWATCH + GAP)) as usize;
+                for c in 0..entry.colours.len().min(2 * per_row) {
+                    let x = GAP + (c % per_row) as f32 * (SWATCH + GAP);
+                    let y = top + HEADER + GAP + (c / per_row) as f32 * (SWATCH + GAP);
+                    controls.push((Rect::new(x, y, SWATCH, SWATCH), Item::Colour(c)));
+                }
+            }
+            if entry.orientations() > 1 {
+                let x = PANEL_WIDTH - THUMBNAIL - GAP;
+                let y = top + CONTROLS - GAP - SWATCH;
+                controls.push((Rect::new(x, y, THUMBNAIL, SWATCH), Item::Rotate));
+            }
+            if entry.heights.is_some() {
+                let y = top + CONTROLS - GAP - SWATCH;
+                controls.push((Rect::new(GAP, y, SWATCH, SWATCH), Item::Lower));
+                let x = 2.0 * GAP + SWATCH;
+                controls.push((Rect::new(x, y, SWATCH, SWATCH), Item::Higher));
+            }
+            controls
+        }
+    }
+
+    /// The height of the scrolling part of the palette.
+    fn list_height() -> f32 {
+        (screen_height() - PANEL_TOP - CONTROLS).max(0.0)
+    }
+
+    /// Draw an entity type's sprite scaled to fit in `rect`.
+    fn draw_thumbnail(
+        map: &TileMap,
+        entity_type: usize,
+        entity_info: &Option<EntityInfo>,
+        rect: Rect,
+    ) {
+        let drawable = match map.entity_drawable(entity_type) {
+            Some(drawable) => drawable,
+            None => return,
+        };
+        let tiles = (drawable.size.x + drawable.size.y) as f32;
+        let above = sprite_height(drawable, entity_info);
+        let width = tiles * TILE_W_HALF as f32;
+        let height = tiles * TILE_H_HALF as f32 + above;
+        let scale = (rect.w / width).min(rect.h / height);
+
+        let destination = Vec2 {
+            x: rect.x + (rect.w - width * scale) / 2.0,
+            y: rect.y + (rect.h - height * scale) / 2.0 + above * scale,
+        };
+        draw_entity(
+            entity_info,
+            drawable,
+            0,
+            destination,
+            Light::day(WHITE),
+            scale,
+        );
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn mapping(target: Color) -> ColorMapping {
+            ColorMapping {
+                target,
+                channel: ColorMappingChannel::Red,
+            }
+        }
+
+        fn entry(types: Vec<(usize, usize)>) -> PaletteEntry {
+            let mappings = [mapping(RED), mapping(GREEN), mapping(BLUE)];
+            let key = ContributionKey::new("Plugin", "house");
+            PaletteEntry::new(key, "House", "Houses", types, &mappings, None)
+        }
+
+        #[test]
+        fn colours_come_from_the_mapping_each_type_was_loaded_with() {
+            // The green texture was missing, so only red and blue were loaded.
+            let entry = entry(vec![(10, 0), (11, 0), (12, 2), (13, 2)]);
+            let colours: Vec<_> = entry.colours.iter().map(|c| c.colour).collect();
+            assert_eq!(colours, vec![Some(RED), Some(BLUE)]);
+            assert_eq!(entry.colours[0].orientations, vec![10, 11]);
+            assert_eq!(entry.colours[1].orientations, vec![12, 13]);
+        }
+
+        #[test]
+        fn colour_and_orientation_are_picked_separately() {
+            let mut palette = BuildPalette::new();
+            palette.add(entry(vec![(10, 0), (11, 0), (12, 1), (13, 1)]));
+            palette.select(0);
+            assert_eq!(palette.placement().unwrap().0, 10);
+
+            palette.rotate();
+            assert_eq!(palette.placement().unwrap().0, 11);
+            palette.select_colour(1);
+            assert_eq!(palette.placement().unwrap().0, 13);
+            palette.rotate();
+            assert_eq!(palette.placement().unwrap().0, 12);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+pub mod clock {
+    use std::fmt;
+
+    /// Length of the simulation step in seconds. The simulation moves the same way
+    /// whatever the frame rate is.
+    pub const TICK_SECONDS: f32 = 1.0 / 30.0;
+
+    /// The most ticks run in one frame at normal speed, so a long frame doesn't stall
+    /// the game. Faster speeds allow proportionally more.
+    const MAX_TICKS_PER_FRAME: u32 = 8;
+
+    /// Game minutes that pass in one real second at normal speed.
+    pub const GAME_MINUTES_PER_SECOND: f64 = 1.0;
+
+    pub const MINUTES_PER_HOUR: u64 = 60;
+    pub const HOURS_PER_DAY: u64 = 24;
+    pub const DAYS_PER_SEASON: u64 = 30;
+    pub const SEASONS_PER_YEAR: u64 = 4;
+
+    pub const MINUTES_PER_DAY: u64 = MINUTES_PER_HOUR * HOURS_PER_DAY;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Season {
+        Spring,
+        Summer,
+        Autumn,
+        Winter,
+    }
+
+    impl Season {
+        pub const ALL: [Season; 4] = [
+            Season::Spring,
+            Season::Summer,
+            Season::Autumn,
+            Season::Winter,
+        ];
+
+        pub fn index(self) -> usize {
+            self as usize
+        }
+
+        pub fn parse(name: &str) -> Option<Season> {
+            Season::ALL
+                .into_iter()
+                .find(|x| x.name().eq_ignore_ascii_case(name))
+        }
+
+        pub fn name(self) -> &'static str {
+            match self {
+                Season::Spring => "spring",
+                Season::Summer => "summer",
+                Season::Autumn => "autumn",
+                Season::Winter => "winter",
+            }
+        }
+
+        pub fn next(self) -> Season {
+            Season::ALL[(self.index() + 1) % 4]
+        }
+    }
+
+    impl fmt::Display for Season {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.name())
+        }
+    }
+
+    /// How fast game time runs compared to real time.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum GameSpeed {
+        Normal,
+        Double,
+        Quadruple,
+        FastForward,
+    }
+
+    impl GameSpeed {
+        pub const ALL: [GameSpeed; 4] = [
+            GameSpeed::Normal,
+            GameSpeed::Double,
+            GameSpeed::Quadruple,
+            GameSpeed::FastForward,
+        ];
+
+        pub fn multiplier(self) -> u32 {
+            match self {
+                GameSpeed::Normal => 1,
+                GameSpeed::Double => 2,
+                GameSpeed::Quadruple => 4,
+                GameSpeed::FastForward => 16,
+            }
+        }
+    }
+
+    /// A moment on the game calendar. Days and years count from 1.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct GameTime {
+        pub year: u64,
+        pub season: Season,
+        pub day: u64,
+        pub hour: u64,
+        pub minute: u64,
+    }
+
+    impl GameTime {
+        pub fn from_minutes(minutes: u64) -> GameTime {
+            let days = minutes / MINUTES_PER_DAY;
+            let seasons = days / DAYS_PER_SEASON;
+            GameTime {
+                year: seasons / SEASONS_PER_YEAR + 1,
+                season: Season::ALL[(seasons % SEASONS_PER_YEAR) as usize],
+                day: days % DAYS_PER_SEASON + 1,
+                hour: minutes / MINUTES_PER_HOUR % HOURS_PER_DAY,
+                minute: minutes % MINUTES_PER_HOUR,
+            }
+        }
+    }
+
+    impl fmt::Display for GameTime {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "Year {}, {} day {}, {:02}:{:02}",
+                self.year, self.season, self.day, self.hour, self.minute
+            )
+        }
+    }
+
+    /// Game time, and the fixed-step clock that drives the simulation. Each frame,
+    /// `advance` says how many ticks to run; game time only moves on with ticks, so the
+    /// simulation comes out the same at any frame rate.
+    #[derive(Debug, Clone)]
+    pub struct GameClock {
+        /// Game minutes at tick 0.
+        start: u64,
+        ticks: u64,
+        pub speed: GameSpeed,
+        pub paused: bool,
+        /// Real time passed that hasn't been turned into ticks yet.
+        accumulator: f32,
+    }
+
+    impl GameClock {
+        /// A clock starting `minutes` into the first day of the first year.
+        pub fn starting_at(minutes: u64) -> GameClock {
+            GameClock {
+                start: minutes,
+                ticks: 0,
+                speed: GameSpeed::Normal,
+                paused: false,
+                accumulator: 0.0,
+            }
+        }
+
+        /// A clock that has already run `ticks` ticks from `start`, as when loading a game.
+        pub fn resume(start: u64, ticks: u64) -> GameClock {
+            GameClock {
+                ticks,
+                ..GameClock::starting_at(start)
+            }
+        }
+
+        /// Take `elapsed` real seconds, and return how many ticks to run for them. Time
+        /// left over is carried to the next call.
+        pub fn advance(&mut self, elapsed: f32) -> u32 {
+            if self.paused {
+                return 0;
+            }
+
+            let multiplier = self.speed.multiplier();
+            self.accumulator += elapsed * multiplier as f32;
+            let ticks = (self.accumulator / TICK_SECONDS) as u32;
+            let max = MAX_TICKS_PER_FRAME * multiplier;
+            if ticks > max {
+                self.accumulator = 0.0;
+                self.ticks += max as u64;
+                return max;
+            }
+
+            self.accumulator -= ticks as f32 * TICK_SECONDS;
+            self.ticks += ticks as u64;
+            ticks
+        }
+
+        pub fn toggle_pause(&mut self) {
+            self.paused = !self.paused;
+        }
+
+        /// Game minutes at tick 0.
+        pub fn start(&self) -> u64 {
+            self.start
+        }
+
+        /// Ticks run since the clock started.
+        pub fn ticks(&self) -> u64 {
+            self.ticks
+        }
+
+        /// Game minutes since the start of the first year, including the part of the
+        /// current minute that has passed.
+        pub fn total_minutes(&self) -> f64 {
+            self.start as f64 + self.ticks as f64 * TICK_SECONDS as f64 * GAME_MINUTES_PER_SECOND
+        }
+
+        pub fn time(&self) -> GameTime {
+            GameTime::from_minutes(self.total_minutes() as u64)
+        }
+
+        pub fn season(&self) -> Season {
+            self.time().season
+        }
+
+        /// Minutes since midnight, including the part of the current minute that has
+        /// passed.
+        pub fn minute_of_day(&self) -> f32 {
+            (self.total_minutes() % MINUTES_PER_DAY as f64) as f32
+        }
+    }
+}
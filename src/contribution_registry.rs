@@ -0,0 +1,282 @@
+pub mod contribution_registry {
+    use crate::plugin_manager::plugin_manager::Plugin;
+    use std::{
+        collections::{BTreeMap, HashMap},
+        fmt,
+        ops::Range,
+        path::PathBuf,
+    };
+
+    /// The namespace for things built into the game rather than loaded from a plugin.
+    pub const BUILTIN_NAMESPACE: &str = "openft";
+
+    /// Identifies a contribution independently of plugin load order. Contribution ids are
+    /// meant to be GUIDs, but plugins copy each other's XML often enough that the plugin
+    /// title is used as a namespace.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    pub struct ContributionKey {
+        pub namespace: String,
+        pub id: String,
+    }
+
+    impl ContributionKey {
+        pub fn new(namespace: &str, id: &str) -> ContributionKey {
+            ContributionKey {
+                namespace: namespace.to_string(),
+                id: id.to_string(),
+            }
+        }
+    }
+
+    impl fmt::Display for ContributionKey {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}/{}", self.namespace, self.id)
+        }
+    }
+
+    /// What a contribution turned into at runtime.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ContributionHandle {
+        /// A block of entity types in the `TileMap`, one per colour and direction variant.
+        EntityTypes(Range<usize>),
+        RoadType(usize),
+        RailType(usize),
+        CarType(usize),
+        TrainType(usize),
+        GroundType(usize),
+    }
+
+    #[derive(Debug)]
+    pub struct RegisteredContribution {
+        pub plugin: PathBuf,
+        pub handle: ContributionHandle,
+    }
+
+    /// The same contribution key, plugin title and id, used by more than one contribution.
+    #[derive(Debug, Clone)]
+    pub struct DuplicateContribution {
+        pub key: ContributionKey,
+        pub first: PathBuf,
+        pub second: PathBuf,
+    }
+
+    impl fmt::Display for DuplicateContribution {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "contribution '{}' is used in both {} and {}",
+                self.key,
+                self.first.display(),
+                self.second.display()
+            )
+        }
+    }
+
+    #[derive(Debug, Default)]
+    pub struct ContributionRegistry {
+        entries: BTreeMap<ContributionKey, RegisteredContribution>,
+        /// Reverse lookup from entity type index to the contribution that created it.
+        entity_types: Vec<Option<ContributionKey>>,
+    }
+
+    impl ContributionRegistry {
+        pub fn new() -> ContributionRegistry {
+            ContributionRegistry::default()
+        }
+
+        pub fn contains(&self, key: &ContributionKey) -> bool {
+            self.entries.contains_key(key)
+        }
+
+        /// Register a contribution's runtime handle. A key can only be registered once; the
+        /// first registration wins and later ones are returned as duplicates.
+        pub fn register(
+            &mut self,
+            key: ContributionKey,
+            plugin: PathBuf,
+            handle: ContributionHandle,
+        ) -> Result<(), DuplicateContribution> {
+            if let Some(existing) = self.entries.get(&key) {
+                return Err(DuplicateContribution {
+                    key,
+                    first: existing.plugin.clone(),
+                    second: plugin,
+                });
+            }
+
+            if let ContributionHandle::EntityTypes(types) = &handle {
+                if self.entity_types.len() < types.end {
+                    self.entity_types.resize(types.end, None);
+                }
+                for t in types.clone() {
+                    self.entity_types[t] = Some(key.clone());
+                }
+            }
+
+            self.entries
+                .insert(key, RegisteredContribution { plugin, handle });
+            Ok(())
+        }
+
+        pub fn get(&self, key: &ContributionKey) -> Option<&ContributionHandle> {
+            self.entries.get(key).map(|x| &x.handle)
+        }
+
+        /// Find which contribution an entity type belongs to, and which of its variants it is.
+        pub fn entity_type_key(&self, entity_type: usize) -> Option<(&ContributionKey, usize)> {
+            let key = self.entity_types.get(entity_type)?.as_ref()?;
+            match self.get(key)? {
+                ContributionHandle::EntityTypes(types) => Some((key, entity_type - types.start)),
+                _ => None,
+            }
+        }
+
+        /// Resolve a contribution key and variant back to an entity type index.
+        pub fn entity_type(&self, key: &ContributionKey, variant: usize) -> Option<usize> {
+            match self.get(key)? {
+                ContributionHandle::EntityTypes(types) => {
+                    let t = types.start + variant;
+                    types.contains(&t).then_some(t)
+                }
+                _ => None,
+            }
+        }
+
+        /// Find the contribution a handle was registered for.
+        pub fn key_for(&self, handle: &ContributionHandle) -> Option<&ContributionKey> {
+            self.entries
+                .iter()
+                .find(|(_, x)| x.handle == *handle)
+                .map(|(key, _)| key)
+        }
+
+        /// Whether anything is registered under a plugin's namespace.
+        pub fn has_namespace(&self, namespace: &str) -> bool {
+            self.entries.keys().any(|x| x.namespace == namespace)
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = (&ContributionKey, &RegisteredContribution)> {
+            self.entries.iter()
+        }
+    }
+
+    /// Find contribution keys that appear more than once across a set of plugins, the
+    /// same way `ContributionRegistry::register` would reject them. Plugins with different
+    /// titles may share ids.
+    pub fn find_duplicate_contributions(plugins: &[Plugin]) -> Vec<DuplicateContribution> {
+        let mut seen = HashMap::<ContributionKey, &PathBuf>::new();
+        let mut duplicates = Vec::new();
+
+        for plugin in plugins {
+            for contribution in &plugin.contributions {
+                let key = ContributionKey::new(&plugin.title, &contribution.id);
+                match seen.get(&key) {
+                    Some(first) => duplicates.push(DuplicateContribution {
+                        key,
+                        first: (*first).clone(),
+                        second: plugin.filename.clone(),
+                    }),
+                    None => {
+                        seen.insert(key, &plugin.filename);
+                    }
+                }
+            }
+        }
+
+        duplicates
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{
+            plugin_manager::plugin_manager::{parse_plugin_xml, PluginLoadReport},
+            vfs::vfs::VirtualDir,
+        };
+
+        fn key(namespace: &str, id: &str) -> ContributionKey {
+            ContributionKey::new(namespace, id)
+        }
+
+        #[test]
+        fn register_rejects_the_same_key_twice() {
+            let mut registry = ContributionRegistry::new();
+            let handle = ContributionHandle::RoadType(0);
+            registry
+                .register(key("A", "road"), PathBuf::from("a"), handle.clone())
+                .unwrap();
+            // The same id under another plugin title is a different contribution.
+            registry
+                .register(
+                    key("B", "road"),
+                    PathBuf::from("b"),
+                    ContributionHandle::RoadType(1),
+                )
+                .unwrap();
+
+            let duplicate = registry
+                .register(
+                    key("A", "road"),
+                    PathBuf::from("c"),
+                    ContributionHandle::RoadType(2),
+                )
+                .unwrap_err();
+            assert_eq!(duplicate.key, key("A", "road"));
+            assert_eq!(
+                (duplicate.first, duplicate.second),
+                ("a".into(), "c".into())
+            );
+            // The first registration wins.
+            assert_eq!(registry.get(&key("A", "road")), Some(&handle));
+            assert_eq!(
+                registry.key_for(&ContributionHandle::RoadType(1)),
+                Some(&key("B", "road"))
+            );
+        }
+
+        #[test]
+        fn entity_types_round_trip() {
+            let mut registry = ContributionRegistry::new();
+            let (house, shop) = (key("A", "house"), key("A", "shop"));
+            let types = ContributionHandle::EntityTypes;
+            registry
+                .register(house.clone(), "a".into(), types(1..5))
+                .unwrap();
+            registry
+                .register(shop.clone(), "a".into(), types(5..7))
+                .unwrap();
+
+            for t in 1..7 {
+                let (key, variant) = registry.entity_type_key(t).unwrap();
+                assert_eq!(registry.entity_type(key, variant), Some(t));
+            }
+            assert_eq!(registry.entity_type_key(4), Some((&house, 3)));
+            assert_eq!(registry.entity_type_key(5), Some((&shop, 0)));
+            assert_eq!(registry.entity_type_key(0), None);
+            assert_eq!(registry.entity_type_key(7), None);
+            assert_eq!(registry.entity_type(&shop, 2), None);
+            assert_eq!(registry.entity_type(&key("B", "shop"), 0), None);
+        }
+
+        #[test]
+        fn duplicates_are_keyed_by_title_and_id() {
+            let plugin = |dir: &str, title: &str| {
+                let xml = format!(
+                    "<plug-in><title>{}</title><author>Me</author>\
+                     <contribution type=\"train\" id=\"copied\">\
+                     <composition><body ref=\"car\"/></composition></contribution></plug-in>",
+                    title
+                );
+                let files = VirtualDir::Disk(PathBuf::from(dir));
+                parse_plugin_xml(files, xml.as_bytes(), &mut PluginLoadReport::default()).unwrap()
+            };
+            let plugins = [plugin("a", "A"), plugin("b", "B"), plugin("c", "A")];
+
+            let duplicates = find_duplicate_contributions(&plugins);
+            assert_eq!(duplicates.len(), 1);
+            assert_eq!(duplicates[0].key, key("A", "copied"));
+            assert_eq!(duplicates[0].first, PathBuf::from("a"));
+            assert_eq!(duplicates[0].second, PathBuf::from("c"));
+        }
+    }
+}
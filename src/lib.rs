@@ -0,0 +1,17 @@
+pub mod build_palette;
+pub mod clock;
+pub mod contribution_registry;
+pub mod lighting;
+pub mod plugin_manager;
+pub mod rail_graph;
+pub mod render_order;
+pub mod save;
+pub mod signal;
+pub mod station;
+pub mod texture_manager;
+pub mod tilemap_manager;
+pub mod timetable;
+pub mod track;
+pub mod train_manager;
+pub mod util;
+pub mod vfs;
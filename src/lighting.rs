@@ -0,0 +1,109 @@
+pub mod lighting {
+    use crate::clock::clock::MINUTES_PER_DAY;
+    use macroquad::prelude::{Color, WHITE};
+
+    /// Minutes since midnight when it is fully light.
+    pub const DAWN: f32 = 6.0 * 60.0;
+    /// Minutes since midnight when it is fully dark, and night sprites take over.
+    pub const DUSK: f32 = 19.0 * 60.0;
+    /// How long it takes to get light after dawn starts, or dark before dusk.
+    pub const TWILIGHT: f32 = 60.0;
+
+    /// The tint for everything drawn from a day picture in the middle of the night.
+    pub const NIGHT_TINT: Color = Color {
+        r: 0.3,
+        g: 0.35,
+        b: 0.6,
+        a: 1.0,
+    };
+
+    /// How to draw a sprite: the colour to tint it, and whether to use its night picture.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Light {
+        pub tint: Color,
+        pub night: bool,
+    }
+
+    impl Light {
+        /// Daytime pictures with the given tint.
+        pub fn day(tint: Color) -> Light {
+            Light { tint, night: false }
+        }
+    }
+
+    /// The light across the map at a time of day. It keeps its own time, which the app
+    /// can set from the game clock or move on by itself.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Lighting {
+        minute_of_day: f32,
+    }
+
+    impl Lighting {
+        pub fn new(minute_of_day: f32) -> Lighting {
+            let mut lighting = Lighting { minute_of_day: 0.0 };
+            lighting.set_time(minute_of_day);
+            lighting
+        }
+
+        pub fn minute_of_day(&self) -> f32 {
+            self.minute_of_day
+        }
+
+        pub fn set_time(&mut self, minute_of_day: f32) {
+            self.minute_of_day = minute_of_day.rem_euclid(MINUTES_PER_DAY as f32);
+        }
+
+        pub fn advance(&mut self, minutes: f32) {
+            self.set_time(self.minute_of_day + minutes);
+        }
+
+        /// How light it is, from 0 at night to 1 during the day.
+        pub fn daylight(&self) -> f32 {
+            let t = self.minute_of_day;
+            if !(DAWN - TWILIGHT..DUSK).contains(&t) {
+                0.0
+            } else if t < DAWN {
+                (t - (DAWN - TWILIGHT)) / TWILIGHT
+            } else if t < DUSK - TWILIGHT {
+                1.0
+            } else {
+                (DUSK - t) / TWILIGHT
+            }
+        }
+
+        /// Whether buildings should be drawn with their night pictures.
+        pub fn is_night(&self) -> bool {
+            self.daylight() == 0.0
+        }
+
+        /// The colour to tint day pictures with.
+        pub fn tint(&self) -> Color {
+            let d = self.daylight();
+            let mix = |night: f32, day: f32| night + (day - night) * d;
+            Color {
+                r: mix(NIGHT_TINT.r, WHITE.r),
+                g: mix(NIGHT_TINT.g, WHITE.g),
+                b: mix(NIGHT_TINT.b, WHITE.b),
+                a: 1.0,
+            }
+        }
+
+        /// A colour as it looks in the current light.
+        pub fn shade(&self, color: Color) -> Color {
+            let tint = self.tint();
+            Color {
+                r: color.r * tint.r,
+                g: color.g * tint.g,
+                b: color.b * tint.b,
+                a: color.a,
+            }
+        }
+
+        pub fn light(&self) -> Light {
+            Light {
+                tint: self.tint(),
+                night: self.is_night(),
+            }
+        }
+    }
+}
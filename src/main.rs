@@ -2,22 +2,107 @@ use macroquad::{
     prelude::*,
     rand::{gen_range, srand},
 };
-use plugin_manager::plugin_manager::*;
-use std::time::{SystemTime, UNIX_EPOCH};
-use texture_manager::texture_manager::*;
-use tilemap_manager::tilemap_manager::*;
-use util::util::*;
-
-mod plugin_manager;
-mod texture_manager;
-mod tilemap_manager;
-mod util;
+use openft::{
+    build_palette::build_palette::*, clock::clock::*,
+    contribution_registry::contribution_registry::*, lighting::lighting::*,
+    plugin_manager::plugin_manager::*, render_order::render_order::*, save::save::*,
+    texture_manager::texture_manager::*, tilemap_manager::tilemap_manager::*, track::track::*,
+    train_manager::train_manager::*, util::util::*, vfs::vfs::VirtualDir,
+};
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 const CAMERA_SPEED: f32 = 4.0;
 const MAP_SIZE: usize = 200;
 
+struct Options {
+    plugin_paths: Vec<PathBuf>,
+    /// Draw this season instead of the one the calendar is in.
+    season: Option<Season>,
+    /// Where F5 saves the game and F9 loads it from.
+    save_path: PathBuf,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        plugin_paths: Vec::new(),
+        season: None,
+        save_path: PathBuf::from("openft-save.json"),
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--plugin-path" => match args.next() {
+                Some(path) => options.plugin_paths.push(PathBuf::from(path)),
+                None => println!("Warning: --plugin-path needs a directory"),
+            },
+            "--season" => match args.next().as_deref().map(Season::parse) {
+                Some(Some(season)) => options.season = Some(season),
+                _ => println!("Warning: --season needs spring, summer, autumn or winter"),
+            },
+            "--save" => match args.next() {
+                Some(path) => options.save_path = PathBuf::from(path),
+                None => println!("Warning: --save needs a file name"),
+            },
+            other => println!("Warning: ignoring unknown argument '{}'", other),
+        }
+    }
+
+    options
+}
+
+/// One thing to draw this frame. Everything is queued first so that it can be drawn
+/// back to front.
+enum Drawing {
+    Ground(usize, usize, Vec2),
+    Road(usize, usize, Vec2),
+    Track(usize, usize, Vec2),
+    /// An entity, by its anchor tile.
+    Entity(usize, usize, Vec2),
+    Signal(Vec2, Color),
+    Car(CarPosition, Vec2),
+}
+
+/// The tile under a point in world xy coordinates, allowing for the height of the ground.
+fn pick_tile(map: &TileMap, point: Vec2) -> Tile {
+    xy_to_iso_walking_z(point, map.max_height() as i32, |t| {
+        map.in_bounds(t.x, t.y) && map.tile_height(t.x as usize, t.y as usize) as i32 == t.z
+    })
+}
+
+/// The fractional map position under a point in world xy coordinates, on the tile that
+/// `pick_tile` finds.
+fn pick_point(map: &TileMap, point: Vec2) -> (f32, f32) {
+    let tile = pick_tile(map, point);
+    xy_to_iso_point(Vec2 {
+        x: point.x,
+        y: point.y + (tile.z * TILE_Z) as f32,
+    })
+}
+
+/// Lay the track segment on tile `b` that joins its neighbours `a` and `c`.
+fn lay_track(map: &mut TileMap, a: (i32, i32), b: (i32, i32), c: (i32, i32)) {
+    if !map.in_bounds(b.0, b.1) {
+        return;
+    }
+    let from = Direction::from_offset(a.0 - b.0, a.1 - b.1);
+    let to = Direction::from_offset(c.0 - b.0, c.1 - b.1);
+    if let (Some(from), Some(to)) = (from, to) {
+        if let Some(segment) = TrackSegment::new(from, to, 0) {
+            map.add_track(b.0 as usize, b.1 as usize, 0, segment);
+        }
+    }
+}
+
 #[macroquad::main("OpenFT")]
 async fn main() {
+    let options = parse_args();
+
     srand(
         (SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -34,11 +119,17 @@ async fn main() {
         target: WHITE,
         channel: ColorMappingChannel::None,
     };
-    load_process_texture(&mut texture, "res/GroundSeasonal.png", no_mapping).await;
+    let res = VirtualDir::Disk(PathBuf::from("res"));
+    load_process_texture(&mut texture, &res, "GroundSeasonal.png", no_mapping)
+        .await
+        .expect("Couldn't load ground textures");
 
+    // The four columns are the same ground in spring, summer, autumn and winter.
+    let mut seasons = Vec::new();
     for i in 0..4 {
         let tile = DrawableTileData {
             texture: &texture,
+            night_texture: None,
             image_data: ImageData::SingleDrawable(Drawable {
                 offset: Vec2 { x: 0.0, y: 0.0 },
                 origin: Vec2 {
@@ -50,26 +141,117 @@ async fn main() {
             }),
             size: Tile { x: 1, y: 1, z: 1 },
         };
-        map.create_ground_type(tile);
+        seasons.push(tile);
     }
+    let builtin_ground = map.create_seasonal_ground_type(seasons).unwrap();
 
-    let plugin_dirs = enumerate_plugins().expect("Plugins not found!");
-    let plugins = load_plugins(plugin_dirs);
+    let plugin_dirs = enumerate_plugins(&plugin_search_paths(&options.plugin_paths));
+    let (plugins, report) = load_plugins(plugin_dirs);
+    print!("{}", report);
     let plugin_textures = load_plugin_textures(&plugins).await;
+    let alpha_masks = AlphaMasks::from_textures(plugin_textures.values());
+    let mut registry = ContributionRegistry::new();
+    registry
+        .register(
+            ContributionKey::new(BUILTIN_NAMESPACE, "ground"),
+            PathBuf::from("res"),
+            ContributionHandle::GroundType(builtin_ground),
+        )
+        .unwrap();
+    let mut trains = TrainCatalogue::new();
+    let mut palette = BuildPalette::new();
     for plugin in plugins {
         for contribution in plugin.contributions {
-            map.create_entity_types(&mut load_drawable_tile_data_from_contribution(
-                contribution,
-                &plugin.title,
-                &plugin_textures,
-            ));
+            let key = ContributionKey::new(&plugin.title, &contribution.id);
+            if registry.contains(&key) {
+                println!("Warning: skipping duplicate contribution {}", key);
+                continue;
+            }
+
+            let contribution_type = contribution.contribution_type;
+            let properties = contribution.properties.clone();
+            let size = contribution.size;
+            let color_mappings = contribution.color_mappings.clone();
+            let (mappings, mut drawables): (Vec<usize>, Vec<_>) =
+                load_drawable_tile_data_from_contribution(
+                    contribution,
+                    &plugin.title,
+                    &plugin_textures,
+                )
+                .into_iter()
+                .unzip();
+            let handle = match (contribution_type, properties) {
+                (ContributionType::GenericStructure, ContributionProperties::Structure(s)) => {
+                    let multistorey = drawables
+                        .iter()
+                        .any(|d| matches!(d.image_data, ImageData::MultistoreyDrawable(..)));
+                    let types = map.create_entity_types(&mut drawables);
+                    palette.add(PaletteEntry::new(
+                        key.clone(),
+                        &s.name,
+                        s.group.as_deref().unwrap_or(""),
+                        types.clone().zip(mappings),
+                        &color_mappings,
+                        multistorey.then_some(s.min_height..=s.max_height),
+                    ));
+                    ContributionHandle::EntityTypes(types)
+                }
+                (ContributionType::Road, _) => match drawables.pop() {
+                    Some(drawable) => ContributionHandle::RoadType(map.create_road_type(drawable)),
+                    None => continue,
+                },
+                (ContributionType::Rail, _) => match drawables.pop() {
+                    Some(drawable) => ContributionHandle::RailType(map.create_rail_type(drawable)),
+                    None => continue,
+                },
+                (ContributionType::TrainCar, ContributionProperties::TrainCar(car)) => {
+                    ContributionHandle::CarType(trains.add_car(key.clone(), car, drawables))
+                }
+                (ContributionType::Station, ContributionProperties::Station(station)) => {
+                    let types = map.create_station_types(&mut drawables, size, &station);
+                    palette.add(PaletteEntry::new(
+                        key.clone(),
+                        &station.name,
+                        "Stations",
+                        types.clone().zip(mappings),
+                        &color_mappings,
+                        None,
+                    ));
+                    ContributionHandle::EntityTypes(types)
+                }
+                (ContributionType::Land, ContributionProperties::Land(land)) => {
+                    let ground = match land.seasonal {
+                        true => map.create_seasonal_ground_type(drawables),
+                        false => drawables.pop().map(|x| map.create_ground_type(x)),
+                    };
+                    match ground {
+                        Some(ground) => ContributionHandle::GroundType(ground),
+                        None => continue,
+                    }
+                }
+                (ContributionType::Train, ContributionProperties::Train(train)) => {
+                    ContributionHandle::TrainType(trains.add_train(key.clone(), train))
+                }
+                _ => continue,
+            };
+            if let Err(err) = registry.register(key, plugin.filename.clone(), handle) {
+                println!("Warning: {}", err);
+            }
         }
     }
 
+    for missing in trains.missing_cars() {
+        println!("Warning: {}", missing);
+    }
+
     for x in 0..MAP_SIZE {
         for y in 0..MAP_SIZE {
             if x % 5 == 0 || y % 5 == 0 {
-                map.set_ground(x, y, 3);
+                continue;
+            }
+
+            // The demo scatter below picks from fixed ranges of the bundled plugins' types.
+            if map.entity_type_count() <= 80 {
                 continue;
             }
 
@@ -82,8 +264,7 @@ async fn main() {
                     height: gen_range(0, 4),
                 };
                 map.set_entity(x, y, t, Some(entity_info));
-            }
-            else if r < 200 {
+            } else if r < 200 {
                 let t = gen_range(60, 80);
                 map.set_entity(x, y, t, None);
             }
@@ -91,6 +272,13 @@ async fn main() {
     }
 
     let mut zoom_level: f32 = 2.0;
+    let mut track_drag: Vec<(i32, i32)> = Vec::new();
+    let mut bulldoze_from: Option<(i32, i32)> = None;
+    let mut simulation = TrainSimulation::new();
+    let mut clock = GameClock::starting_at(8 * MINUTES_PER_HOUR);
+    let mut season = options.season;
+    let mut lighting = Lighting::new(clock.minute_of_day());
+    let mut deadlocks = Vec::new();
 
     let water = Color {
         r: 81.0 / 255.0,
@@ -100,7 +288,9 @@ async fn main() {
     };
 
     loop {
-        clear_background(water);
+        lighting.set_time(clock.minute_of_day());
+        let light = lighting.light();
+        clear_background(lighting.shade(water));
 
         let frame_time = get_frame_time();
         let speed: f32 = CAMERA_SPEED * (60.0 * frame_time);
@@ -135,24 +325,234 @@ async fn main() {
             y: mouse_position().1,
         };
 
-        if is_mouse_button_pressed(MouseButton::Left) {
+        // P shows or hides the build palette.
+        if is_key_pressed(KeyCode::P) {
+            palette.visible = !palette.visible;
+        }
+        palette.update(mouse_pos);
+
+        // Hold R to lay roads by clicking and dragging.
+        if is_key_down(KeyCode::R) {
+            if is_mouse_button_down(MouseButton::Left) && map.road_type_count() > 0 {
+                let mouse_xy = screen_to_xy(mouse_pos, camera, zoom_level);
+                let mouse_iso = pick_tile(&map, mouse_xy);
+
+                if mouse_iso.x >= 0 && mouse_iso.y >= 0 {
+                    map.set_road(mouse_iso.x as usize, mouse_iso.y as usize, 0);
+                }
+            }
+        } else if is_key_down(KeyCode::L) {
+            // Hold L to paint the most recently loaded kind of ground.
+            if is_mouse_button_down(MouseButton::Left) {
+                let mouse_iso = pick_tile(&map, screen_to_xy(mouse_pos, camera, zoom_level));
+                if map.in_bounds(mouse_iso.x, mouse_iso.y) {
+                    let ground = map.ground_type_count() - 1;
+                    map.set_ground(mouse_iso.x as usize, mouse_iso.y as usize, ground);
+                }
+            }
+        } else if is_key_down(KeyCode::T) {
+            // Hold T to lay track by dragging. Each tile gets the segment joining the tile
+            // the drag came from to the tile it went on to.
+            if is_mouse_button_down(MouseButton::Left) && map.rail_type_count() > 0 {
+                let mouse_iso = pick_tile(&map, screen_to_xy(mouse_pos, camera, zoom_level));
+                let next = (mouse_iso.x, mouse_iso.y);
+
+                match track_drag.last() {
+                    Some(&last) if last == next => (),
+                    Some(&last)
+                        if Direction::from_offset(next.0 - last.0, next.1 - last.1)
+                            .map(|x| x.offset())
+                            != Some((next.0 - last.0, next.1 - last.1)) =>
+                    {
+                        // The mouse skipped a tile, so start a new run of track.
+                        track_drag.clear();
+                        track_drag.push(next);
+                    }
+                    _ => {
+                        track_drag.push(next);
+                        if let [.., a, b, c] = track_drag[..] {
+                            lay_track(&mut map, a, b, c);
+                        } else if let [a, b] = track_drag[..] {
+                            // Extend the first tile straight back so the run has an end.
+                            let behind = (2 * a.0 - b.0, 2 * a.1 - b.1);
+                            lay_track(&mut map, behind, a, b);
+                        }
+                    }
+                }
+            } else if let [.., a, b] = track_drag[..] {
+                let ahead = (2 * b.0 - a.0, 2 * b.1 - a.1);
+                lay_track(&mut map, a, b, ahead);
+                track_drag.clear();
+            } else {
+                track_drag.clear();
+            }
+        } else if is_key_down(KeyCode::H) {
+            // Hold H and click near a tile corner to raise it, or right-click to lower it.
+            let raise = is_mouse_button_pressed(MouseButton::Left);
+            if raise || is_mouse_button_pressed(MouseButton::Right) {
+                let (fx, fy) = pick_point(&map, screen_to_xy(mouse_pos, camera, zoom_level));
+                let (vx, vy) = (fx.round() as i32, fy.round() as i32);
+                let (size_x, size_y) = map.size();
+                if vx >= 0 && vy >= 0 && vx as usize <= size_x && vy as usize <= size_y {
+                    let (vx, vy) = (vx as usize, vy as usize);
+                    let height = map.vertex_height(vx, vy);
+                    let height = match raise {
+                        true => height + 1,
+                        false => height.saturating_sub(1),
+                    };
+                    if !map.set_vertex_height(vx, vy, height) {
+                        println!("Couldn't change the ground at {} {}", vx, vy);
+                    }
+                }
+            }
+        } else if is_key_down(KeyCode::B) {
+            // Hold B and drag over an area to bulldoze it, or click to clear one tile.
+            let mouse_iso = pick_tile(&map, screen_to_xy(mouse_pos, camera, zoom_level));
+            let here = (mouse_iso.x, mouse_iso.y);
+            if is_mouse_button_down(MouseButton::Left) {
+                bulldoze_from.get_or_insert(here);
+            } else if let Some(from) = bulldoze_from.take() {
+                map.remove_area(from, here);
+            }
+        } else if is_mouse_button_pressed(MouseButton::Left) && !palette.contains(mouse_pos) {
+            // Click the map to build what is picked on the palette.
+            // Clicking off the map does nothing.
             let mouse_xy = screen_to_xy(mouse_pos, camera, zoom_level);
-            let mouse_iso = xy_to_iso(mouse_xy);
+            let pick = map.pick(mouse_xy, &alpha_masks);
+            if let (Some(pick), Some((t, entity_info))) = (pick, palette.placement()) {
+                let tile = pick.tile();
+                let (x_dest, y_dest) = (tile.x as usize, tile.y as usize);
 
-            let x_dest = mouse_iso.x.max(0) as usize;
-            let y_dest = mouse_iso.y.max(0) as usize;
+                let res = map.set_entity(x_dest, y_dest, t, entity_info);
 
-            let t = gen_range(0, map.entity_type_count());
-            let entity_info = EntityInfo {
-                height: gen_range(1, 5),
-            };
-            let res = map.set_entity(x_dest, y_dest, t, Some(entity_info));
+                if !res {
+                    println!("Couldn't create at {} {}", x_dest, y_dest);
+                }
+            }
+        }
+
+        if !is_key_down(KeyCode::B) {
+            bulldoze_from = None;
+        }
+
+        // Press N over a piece of track to put a train on it.
+        if is_key_pressed(KeyCode::N) && !trains.trains().is_empty() {
+            let mouse_iso = pick_tile(&map, screen_to_xy(mouse_pos, camera, zoom_level));
+            let t = gen_range(0, trains.trains().len());
+            let res = simulation.spawn(&map, &trains, t, 4, 0, (mouse_iso.x, mouse_iso.y));
+
+            if let Err(err) = res {
+                println!(
+                    "Couldn't place a train at {} {}: {}",
+                    mouse_iso.x, mouse_iso.y, err
+                );
+            }
+        }
+
+        // Press S near the end of a piece of track to put up or take down a signal there.
+        if is_key_pressed(KeyCode::S) {
+            let (fx, fy) = pick_point(&map, screen_to_xy(mouse_pos, camera, zoom_level));
+            let (x, y) = (fx.floor() as i32, fy.floor() as i32);
+            let (u, v) = (fx - x as f32 - 0.5, fy - y as f32 - 0.5);
+
+            let tracks = map.tracks(x, y);
+            let side = Direction::ALL
+                .into_iter()
+                .filter(|d| tracks.connects(*d))
+                .max_by(|a, b| {
+                    let along = |d: &Direction| {
+                        let (dx, dy) = d.offset();
+                        (u * dx as f32 + v * dy as f32) / ((dx * dx + dy * dy) as f32).sqrt()
+                    };
+                    along(a).total_cmp(&along(b))
+                });
+
+            if let Some(side) = side {
+                let (x, y) = (x as usize, y as usize);
+                if !map.remove_signal(x, y, side) {
+                    map.add_signal(x, y, side);
+                }
+            }
+        }
+
+        // Press G over a piece of track to send every train there.
+        if is_key_pressed(KeyCode::G) {
+            let mouse_iso = pick_tile(&map, screen_to_xy(mouse_pos, camera, zoom_level));
+            let destination = (mouse_iso.x, mouse_iso.y);
 
-            if !res {
-                println!("Couldn't create at {} {}", x_dest, y_dest);
+            for train in &mut simulation.trains {
+                let head = train.head();
+                match map.rail_graph().route(&map, (head.x, head.y), destination) {
+                    Some(route) => train.set_route(&route),
+                    None => println!("No route to {} {}", destination.0, destination.1),
+                }
             }
         }
 
+        // Space pauses; 1 to 4 pick the game speed.
+        if is_key_pressed(KeyCode::Space) {
+            clock.toggle_pause();
+        }
+        for (key, speed) in [
+            (KeyCode::Key1, GameSpeed::Normal),
+            (KeyCode::Key2, GameSpeed::Double),
+            (KeyCode::Key3, GameSpeed::Quadruple),
+            (KeyCode::Key4, GameSpeed::FastForward),
+        ] {
+            if is_key_pressed(key) {
+                clock.speed = speed;
+            }
+        }
+
+        for _ in 0..clock.advance(frame_time) {
+            simulation.tick(&map);
+        }
+
+        // F5 saves the game and F9 loads it back.
+        if is_key_pressed(KeyCode::F5) {
+            let res = save_game(&map, &registry, &clock, &simulation)
+                .map_err(|x| x.to_string())
+                .and_then(|x| fs::write(&options.save_path, x).map_err(|x| x.to_string()));
+            match res {
+                Ok(()) => println!("Saved to {}", options.save_path.display()),
+                Err(err) => println!("Warning: couldn't save: {}", err),
+            }
+        }
+        if is_key_pressed(KeyCode::F9) {
+            let res = fs::read_to_string(&options.save_path)
+                .map_err(|x| x.to_string())
+                .and_then(|x| {
+                    load_game(&x, &mut map, &registry, &trains).map_err(|x| x.to_string())
+                });
+            match res {
+                Ok(loaded) => {
+                    for warning in &loaded.warnings {
+                        println!("Warning: {}", warning);
+                    }
+                    clock = loaded.clock;
+                    simulation = loaded.simulation;
+                    track_drag.clear();
+                    println!("Loaded {}", options.save_path.display());
+                }
+                Err(err) => println!("Warning: couldn't load: {}", err),
+            }
+        }
+
+        // Press V to step through the seasons instead of following the calendar.
+        if is_key_pressed(KeyCode::V) {
+            season = Some(map.season().next());
+        }
+        map.set_season(season.unwrap_or_else(|| clock.season()));
+
+        let stuck = simulation.deadlocks(&map);
+        if stuck != deadlocks {
+            for deadlock in &stuck {
+                println!("Warning: {}", deadlock);
+            }
+            deadlocks = stuck;
+        }
+        let owners = simulation.block_owners(&map);
+
         let screen_xy_origin = screen_to_xy(Vec2 { x: 0.0, y: 0.0 }, camera, zoom_level);
         let screen_xy_extent = screen_to_xy(
             Vec2 {
@@ -162,59 +562,187 @@ async fn main() {
             camera,
             zoom_level,
         );
-
-        let (lower, upper) = min_iso_bounding_box_for_xy((screen_xy_extent, screen_xy_origin));
+        // Raised ground and tall sprites further down the map can show at the bottom of
+        // the screen.
+        let reach = (map.max_height() as i32 * TILE_Z) as f32 + map.max_sprite_height();
+        let (lower, upper) = visible_iso_bounds((screen_xy_extent, screen_xy_origin), reach);
         let x0 = lower.x.max(0).min(MAP_SIZE as i32) as usize;
         let x1 = upper.x.max(0).min(MAP_SIZE as i32) as usize;
         let y0 = lower.y.max(0).min(MAP_SIZE as i32) as usize;
         let y1 = upper.y.max(0).min(MAP_SIZE as i32) as usize;
 
+        let mut queue = RenderQueue::new();
+        let mut seen_entities = HashSet::new();
         for tx in x0..x1 {
             for ty in y0..y1 {
                 let tile = Tile {
                     x: tx as i32,
                     y: ty as i32,
-                    z: 0,
+                    z: map.tile_height(tx, ty) as i32,
                 };
+                let pos_screen = xy_to_screen(iso_to_xy(&tile), camera, zoom_level);
+                let (x, y, z) = (tile.x, tile.y, tile.z);
 
-                let pos_xy = iso_to_xy(&tile);
-                let pos_screen = xy_to_screen(pos_xy, camera, zoom_level);
+                queue.push(
+                    DepthKey::tile(x, y, z, Layer::Ground),
+                    Drawing::Ground(tx, ty, pos_screen),
+                );
+                if map.get_road(tx, ty).is_some() {
+                    queue.push(
+                        DepthKey::tile(x, y, z, Layer::Surface),
+                        Drawing::Road(tx, ty, pos_screen),
+                    );
+                }
+                if map.get_rail(tx, ty).is_some() {
+                    queue.push(
+                        DepthKey::tile(x, y, z, Layer::Surface),
+                        Drawing::Track(tx, ty, pos_screen),
+                    );
+                }
 
-                match map.get_entity(tx, ty) {
-                    Some((entity, drawable, offset)) => {
-                        if is_key_down(KeyCode::X) {
-                            draw_tile(map.get_ground(tx, ty), pos_screen, MAGENTA, zoom_level);
-                            draw_entity(
-                                &Some(EntityInfo { height: 0 }),
-                                drawable,
-                                offset,
-                                pos_screen,
-                                WHITE,
-                                zoom_level,
-                            );
-                        } else if is_key_down(KeyCode::Z) {
-                            draw_tile(map.get_ground(tx, ty), pos_screen, MAGENTA, zoom_level);
-                        } else {
+                // Each entity is drawn once, whole, from its anchor tile.
+                if let Some((entity, drawable, _)) = map.get_entity(tx, ty) {
+                    if seen_entities.insert(map.get(tx, ty).entity_id) {
+                        let anchor = Tile {
+                            x: entity.x0 as i32,
+                            y: entity.y0 as i32,
+                            z: map.tile_height(entity.x0, entity.y0) as i32,
+                        };
+                        let x1 = anchor.x + drawable.size.x - 1;
+                        let pos_screen = xy_to_screen(iso_to_xy(&anchor), camera, zoom_level);
+                        queue.push(
+                            DepthKey::entity(x1, anchor.y, anchor.z),
+                            Drawing::Entity(entity.x0, entity.y0, pos_screen),
+                        );
+                    }
+                }
+
+                // Signals show red when the block beyond them is taken.
+                for side in map.get_signals(tx, ty) {
+                    let (dx, dy) = side.offset();
+                    let color = match map.block_at(x + dx, y + dy) {
+                        Some(block) if owners.contains_key(&block) => RED,
+                        _ => GREEN,
+                    };
+                    let (px, py) = (
+                        x as f32 + 0.5 + dx as f32 * 0.4,
+                        y as f32 + 0.5 + dy as f32 * 0.4,
+                    );
+                    let pos_xy = iso_point_to_xy(px, py, z as f32);
+                    queue.push(
+                        DepthKey::point(px, py, z as f32, Layer::Vehicle),
+                        Drawing::Signal(xy_to_screen(pos_xy, camera, zoom_level), color),
+                    );
+                }
+            }
+        }
+
+        for train in &simulation.trains {
+            for car in train.car_positions() {
+                let (x, y) = xy_to_iso_point(car.xy);
+                let z = map.surface_height(x, y);
+                let pos_screen = xy_to_screen(iso_point_to_xy(x, y, z), camera, zoom_level);
+                queue.push(
+                    DepthKey::point(x, y, z, Layer::Vehicle),
+                    Drawing::Car(car, pos_screen),
+                );
+            }
+        }
+
+        // X shows entities without their upper storeys, and Z hides them, with the
+        // ground under them marked.
+        let marked = is_key_down(KeyCode::X) || is_key_down(KeyCode::Z);
+        for drawing in queue.drain_sorted() {
+            match drawing {
+                Drawing::Ground(tx, ty, pos) => {
+                    let (ground, connections) = map.get_ground(tx, ty);
+                    let tint = match marked && map.get(tx, ty).entity_id != 0 {
+                        true => MAGENTA,
+                        false => light.tint,
+                    };
+                    let slope = map.slope(tx, ty);
+                    draw_sloped_tile(ground, connections, slope, pos, tint, zoom_level);
+                }
+                Drawing::Road(tx, ty, pos) => {
+                    if let Some((road, connections)) = map.get_road(tx, ty) {
+                        draw_tile(road, connections, pos, light.tint, zoom_level);
+                    }
+                }
+                Drawing::Track(tx, ty, pos) => {
+                    if let Some((rail, tracks)) = map.get_rail(tx, ty) {
+                        draw_track(rail, tracks, pos, light.tint, zoom_level);
+                    }
+                }
+                Drawing::Entity(tx, ty, pos) => {
+                    if let Some((entity, drawable, _)) = map.get_entity(tx, ty) {
+                        let ground_floor = Some(EntityInfo { height: 0 });
+                        let entity_info = match is_key_down(KeyCode::X) {
+                            true => &ground_floor,
+                            false => &entity.entity_info,
+                        };
+                        if !is_key_down(KeyCode::Z) {
                             draw_entity(
-                                &entity.entity_info,
+                                entity_info,
                                 drawable,
-                                offset,
-                                pos_screen,
-                                WHITE,
+                                entity.connections,
+                                pos,
+                                light,
                                 zoom_level,
                             );
                         }
                     }
-                    None => {
-                        draw_tile(map.get_ground(tx, ty), pos_screen, WHITE, zoom_level);
+                }
+                Drawing::Signal(pos, color) => {
+                    draw_circle(pos.x, pos.y, 1.5 * zoom_level, color);
+                }
+                Drawing::Car(car, pos) => {
+                    if let Some(sprites) = trains.car(car.car).variants.get(car.variant) {
+                        draw_directional(sprites, car.heading, pos, light.tint, zoom_level);
                     }
-                };
+                }
             }
         }
 
+        for (_, station) in map.stations() {
+            let (x, y) = station.platform[station.platform.len() / 2];
+            let z = map.tile_height(x as usize, y as usize) as i32;
+            let pos_xy = iso_to_xy(&Tile { x, y, z });
+            let pos_screen = xy_to_screen(pos_xy, camera, zoom_level);
+            draw_text(
+                &station.name,
+                pos_screen.x,
+                pos_screen.y,
+                10.0 * zoom_level,
+                WHITE,
+            );
+        }
+
+        // Outline the area being bulldozed.
+        if let Some(from) = bulldoze_from {
+            let to = pick_tile(&map, screen_to_xy(mouse_pos, camera, zoom_level));
+            let (x0, x1) = (from.0.min(to.x) as f32, from.0.max(to.x) as f32 + 1.0);
+            let (y0, y1) = (from.1.min(to.y) as f32, from.1.max(to.y) as f32 + 1.0);
+            let corners = [(x0, y0), (x1, y0), (x1, y1), (x0, y1)].map(|(x, y)| {
+                let pos_xy = iso_point_to_xy(x, y, map.surface_height(x, y));
+                xy_to_screen(pos_xy, camera, zoom_level)
+            });
+            for i in 0..4 {
+                let (a, b) = (corners[i], corners[(i + 1) % 4]);
+                draw_line(a.x, a.y, b.x, b.y, zoom_level, RED);
+            }
+        }
+
+        palette.draw(&map);
+
         let str = format!("fps: {:.2}", get_fps());
         draw_text(&str, 10.0, 30.0, 30.0, WHITE);
 
+        let str = match clock.paused {
+            true => format!("{} (paused)", clock.time()),
+            false => format!("{} ({}x)", clock.time(), clock.speed.multiplier()),
+        };
+        draw_text(&str, 10.0, 60.0, 30.0, WHITE);
+
         if is_key_down(KeyCode::Escape) {
             break;
         }
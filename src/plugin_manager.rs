@@ -1,13 +1,31 @@
 pub mod plugin_manager {
-    use crate::tilemap_manager::tilemap_manager::Tile;
+    use crate::{
+        contribution_registry::contribution_registry::{
+            find_duplicate_contributions, DuplicateContribution,
+        },
+        tilemap_manager::tilemap_manager::{AutotileNeighbours, AutotileRule, Tile},
+        track::track::{Direction, TrackSegment},
+        util::util::TILE_W,
+        vfs::vfs::{is_zip, VirtualDir},
+    };
     use encoding_rs::*;
     use macroquad::prelude::{Color, BLACK};
-    use roxmltree::{Error, Node, ParsingOptions};
-    use std::{collections::HashMap, fs, io, path::PathBuf};
+    use roxmltree::{Error, Node, ParsingOptions, TextPos};
+    use serde_json::json;
+    use std::{
+        borrow::Cow,
+        collections::{BTreeMap, HashMap, HashSet},
+        env, fmt, fs, io,
+        path::{Path, PathBuf},
+        str::FromStr,
+    };
 
     #[derive(Debug)]
     pub struct Plugin {
         pub filename: PathBuf,
+        pub files: VirtualDir,
+        /// The encoding plugin.xml was decoded with.
+        pub encoding: &'static Encoding,
         pub title: String,
         pub author: String,
         pub contributions: Vec<Contribution>,
@@ -15,17 +33,55 @@ pub mod plugin_manager {
 
     #[derive(Debug)]
     pub struct Contribution {
+        /// The contribution's `id` attribute, usually a GUID. Only unique together with the
+        /// plugin title; see `ContributionKey`.
+        pub id: String,
+        pub contribution_type: ContributionType,
         pub size: Tile,
         pub image_ref: String,
+        /// A picture to draw instead of `image_ref` at night, with the same layout.
+        pub night_image_ref: Option<String>,
         pub image_data: Vec<ContributionImageData>,
         pub color_mappings: Vec<ColorMapping>,
+        pub properties: ContributionProperties,
+    }
+
+    /// Number of pieces in a road sprite sheet, one per combination of N/E/S/W connections.
+    pub const ROAD_PIECES: usize = 16;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ContributionType {
+        GenericStructure,
+        Road,
+        Rail,
+        Train,
+        TrainCar,
+        Station,
+        Land,
+    }
+
+    impl ContributionType {
+        /// The `type` attribute this contribution type is declared with in plugin.xml.
+        pub fn name(&self) -> &'static str {
+            match self {
+                ContributionType::GenericStructure => "GenericStructure",
+                ContributionType::Road => "road",
+                ContributionType::Rail => "rail",
+                ContributionType::Train => "train",
+                ContributionType::TrainCar => "trainCar",
+                ContributionType::Station => "station",
+                ContributionType::Land => "land",
+            }
+        }
     }
 
     #[derive(Debug)]
     pub enum ContributionImageData {
         ContributionSprite(ContributionSprite),
         ContributionMultistorey(ContributionMultistorey),
-        ContributionAutotile(ContributionAutotile, usize),
+        ContributionAutotile(ContributionAutotile),
+        ContributionTrack(ContributionTrack),
+        ContributionDirectional(ContributionDirectional),
     }
 
     #[derive(Debug)]
@@ -35,8 +91,120 @@ pub mod plugin_manager {
         pub bottom: ContributionSprite,
     }
 
+    /// Sprites for a tile that changes with its neighbours, e.g. roads, fences or coastlines.
     #[derive(Debug)]
-    pub struct ContributionAutotile {}
+    pub struct ContributionAutotile {
+        pub rule: AutotileRule,
+        pub sprites: Vec<ContributionSprite>,
+    }
+
+    /// Sprites for each kind of track segment a rail style can draw.
+    #[derive(Debug)]
+    pub struct ContributionTrack {
+        pub sprites: Vec<(TrackSegment, ContributionSprite)>,
+    }
+
+    /// One frame per direction a train car can face, starting at north and going clockwise.
+    /// Frames are `width` by `height` and are drawn centred on the car's position.
+    #[derive(Debug)]
+    pub struct ContributionDirectional {
+        pub width: i32,
+        pub height: i32,
+        pub sprites: Vec<ContributionSprite>,
+    }
+
+    /// Non-graphical data that some contribution types carry.
+    #[derive(Debug, Clone)]
+    pub enum ContributionProperties {
+        None,
+        Train(TrainProperties),
+        TrainCar(TrainCarProperties),
+        Station(StationProperties),
+        Land(LandProperties),
+        Structure(StructureProperties),
+    }
+
+    /// What the build palette shows for a `GenericStructure`.
+    #[derive(Debug, Clone)]
+    pub struct StructureProperties {
+        pub name: String,
+        /// The FreeTrain `group` (or `category`) the structure is listed under, if any.
+        pub group: Option<String>,
+        /// How many storeys a multistorey structure can be built with, from `minHeight` and
+        /// `maxHeight`.
+        pub min_height: usize,
+        pub max_height: usize,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct LandProperties {
+        pub name: String,
+        /// Whether there is a sprite for each season rather than one for the whole year.
+        pub seasonal: bool,
+    }
+
+    /// Number of sprites in a seasonal land sheet: spring, summer, autumn and winter.
+    pub const LAND_SEASONS: usize = 4;
+
+    #[derive(Debug, Clone)]
+    pub struct StationProperties {
+        pub name: String,
+        /// How far from the station, in tiles, passengers and freight come from.
+        pub catchment_radius: u32,
+        /// The side of the building the platform runs along. Always a cardinal direction.
+        pub platform_side: Direction,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct TrainCarProperties {
+        pub name: String,
+        /// Passengers, or units of freight, the car carries.
+        pub capacity: u32,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct TrainProperties {
+        pub name: String,
+        pub company: String,
+        pub description: String,
+        pub price: u32,
+        /// Top speed in km/h.
+        pub speed: f32,
+        /// Acceleration in km/h per second.
+        pub acceleration: f32,
+        pub composition: TrainComposition,
+    }
+
+    /// Which cars a train is made of, by contribution id. A train of length `n` is the head
+    /// car, then body cars, then the tail car.
+    #[derive(Debug, Clone)]
+    pub struct TrainComposition {
+        pub head: Option<String>,
+        pub body: String,
+        pub tail: Option<String>,
+        pub min_length: usize,
+        pub max_length: usize,
+    }
+
+    impl TrainComposition {
+        /// The car ids of a train with `length` cars, clamped to the allowed lengths.
+        pub fn cars(&self, length: usize) -> Vec<&str> {
+            let length = length.clamp(self.min_length, self.max_length);
+            let mut cars = vec![self.body.as_str(); length];
+            if let Some(head) = &self.head {
+                cars[0] = head;
+            }
+            if let Some(tail) = &self.tail {
+                if length > 1 || self.head.is_none() {
+                    cars[length - 1] = tail;
+                }
+            }
+            cars
+        }
+    }
+
+    /// Number of directions a train car sprite sheet can have.
+    pub const CAR_DIRECTIONS: [usize; 2] = [8, 16];
 
     #[derive(Debug)]
     pub struct ContributionSprite {
@@ -60,57 +228,550 @@ pub mod plugin_manager {
         Blue,
     }
 
+    /// Where in a plugin a problem was found.
+    #[derive(Debug, Clone)]
+    pub struct ErrorLocation {
+        pub plugin: PathBuf,
+        pub contribution_id: Option<String>,
+        pub position: TextPos,
+    }
+
     #[derive(Debug)]
     pub enum PluginError {
-        ParseError(Error),
+        MissingRootFile(PathBuf, io::Error),
+        ParseError(PathBuf, Error),
+        MissingPluginElement(PathBuf),
+        MissingMetadata {
+            location: ErrorLocation,
+            field: String,
+        },
+        MissingElement {
+            location: ErrorLocation,
+            element: String,
+        },
+        MissingAttribute {
+            location: ErrorLocation,
+            element: String,
+            attribute: String,
+        },
+        BadNumber {
+            location: ErrorLocation,
+            field: String,
+            value: String,
+        },
+        BadBoolean {
+            location: ErrorLocation,
+            field: String,
+            value: String,
+        },
+        MissingContributionType(ErrorLocation),
+        UnknownContributionType {
+            location: ErrorLocation,
+            contribution_type: String,
+        },
+        MissingPictureRef {
+            location: ErrorLocation,
+            image_ref: String,
+        },
+        ConflictingImageRefs {
+            location: ErrorLocation,
+            first: String,
+            second: String,
+        },
+        MissingImageData(ErrorLocation),
+        ConflictingImageData(ErrorLocation),
+        InvalidTrack {
+            location: ErrorLocation,
+            from: String,
+            to: String,
+            rise: i8,
+        },
+        BadDirection {
+            location: ErrorLocation,
+            field: String,
+            value: String,
+        },
+    }
+
+    impl PluginError {
+        pub fn plugin(&self) -> &Path {
+            match self {
+                PluginError::MissingRootFile(path, _)
+                | PluginError::ParseError(path, _)
+                | PluginError::MissingPluginElement(path) => path,
+                other => &other.location().unwrap().plugin,
+            }
+        }
+
+        pub fn location(&self) -> Option<&ErrorLocation> {
+            match self {
+                PluginError::MissingRootFile(_, _)
+                | PluginError::ParseError(_, _)
+                | PluginError::MissingPluginElement(_) => None,
+                PluginError::MissingMetadata { location, .. }
+                | PluginError::MissingElement { location, .. }
+                | PluginError::MissingAttribute { location, .. }
+                | PluginError::BadNumber { location, .. }
+                | PluginError::BadBoolean { location, .. }
+                | PluginError::UnknownContributionType { location, .. }
+                | PluginError::MissingPictureRef { location, .. }
+                | PluginError::ConflictingImageRefs { location, .. }
+                | PluginError::InvalidTrack { location, .. }
+                | PluginError::BadDirection { location, .. } => Some(location),
+                PluginError::MissingContributionType(location)
+                | PluginError::MissingImageData(location)
+                | PluginError::ConflictingImageData(location) => Some(location),
+            }
+        }
+
+        /// Description of the problem without the location prefix.
+        pub fn message(&self) -> String {
+            match self {
+                PluginError::MissingRootFile(_, err) => {
+                    format!("plugin.xml could not be read ({})", err)
+                }
+                PluginError::ParseError(_, err) => err.to_string(),
+                PluginError::MissingPluginElement(_) => "no <plug-in> element".to_string(),
+                PluginError::MissingMetadata { field, .. } => {
+                    format!("missing metadata field <{}>", field)
+                }
+                PluginError::MissingElement { element, .. } => {
+                    format!("missing <{}> element", element)
+                }
+                PluginError::MissingAttribute {
+                    element, attribute, ..
+                } => format!("<{}> is missing the '{}' attribute", element, attribute),
+                PluginError::BadNumber { field, value, .. } => {
+                    format!("'{}' is not a valid number for '{}'", value, field)
+                }
+                PluginError::BadBoolean { field, value, .. } => {
+                    format!("'{}' is not a valid boolean for '{}'", value, field)
+                }
+                PluginError::MissingContributionType(_) => {
+                    "contribution has no 'type' attribute".to_string()
+                }
+                PluginError::UnknownContributionType {
+                    contribution_type, ..
+                } => format!("unknown contribution type '{}'", contribution_type),
+                PluginError::MissingPictureRef { image_ref, .. } => {
+                    format!("picture '{}' is not defined", image_ref)
+                }
+                PluginError::ConflictingImageRefs { first, second, .. } => format!(
+                    "sprites refer to different pictures ('{}' and '{}')",
+                    first, second
+                ),
+                PluginError::MissingImageData(_) => "no image data found".to_string(),
+                PluginError::ConflictingImageData(_) => {
+                    "more than one of <sprite>, <pictures> and <autotile> found".to_string()
+                }
+                PluginError::InvalidTrack { from, to, rise, .. } => format!(
+                    "there is no track from '{}' to '{}' rising {}",
+                    from, to, rise
+                ),
+                PluginError::BadDirection { field, value, .. } => {
+                    format!("'{}' is not a valid direction for '{}'", value, field)
+                }
+            }
+        }
     }
 
-    pub fn enumerate_plugins() -> Result<Vec<PathBuf>, io::Error> {
-        Ok(fs::read_dir("./plugin")?
-            .into_iter()
-            .filter(|r| r.is_ok())
-            .map(|r| r.unwrap().path())
-            .filter(|r| r.is_dir())
-            .collect::<Vec<PathBuf>>())
+    impl fmt::Display for PluginError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self.location() {
+                Some(location) => {
+                    write!(
+                        f,
+                        "{}:{}:{}: ",
+                        location.plugin.display(),
+                        location.position.row,
+                        location.position.col
+                    )?;
+                    if let Some(id) = &location.contribution_id {
+                        write!(f, "contribution '{}': ", id)?;
+                    }
+                }
+                None => write!(f, "{}: ", self.plugin().display())?,
+            }
+
+            write!(f, "{}", self.message())
+        }
     }
 
-    pub fn load_plugins(plugin_paths: Vec<PathBuf>) -> Vec<Plugin> {
-        let mut plugins = Vec::new();
+    /// Information shared by every parser working on one plugin.xml file.
+    struct ParseContext<'a> {
+        plugin: &'a Path,
+    }
 
-        for path in plugin_paths {
-            let mut xml = path.clone();
-            xml.push("plugin.xml");
+    impl<'a> ParseContext<'a> {
+        fn location(&self, node: Node) -> ErrorLocation {
+            let contribution_id = node
+                .ancestors()
+                .find(|x| x.has_tag_name("contribution"))
+                .and_then(|x| x.attribute("id"))
+                .map(|x| x.to_string());
+
+            ErrorLocation {
+                plugin: self.plugin.to_path_buf(),
+                contribution_id,
+                position: node.document().text_pos_at(node.position()),
+            }
+        }
 
-            let xml_bytes = match fs::read(xml) {
-                Ok(file) => file,
+        fn attribute<'n>(&self, node: Node<'n, '_>, name: &str) -> Result<&'n str, PluginError> {
+            node.attribute(name)
+                .ok_or_else(|| PluginError::MissingAttribute {
+                    location: self.location(node),
+                    element: node.tag_name().name().to_string(),
+                    attribute: name.to_string(),
+                })
+        }
+
+        fn child<'n, 'i>(
+            &self,
+            node: Node<'n, 'i>,
+            name: &str,
+        ) -> Result<Node<'n, 'i>, PluginError> {
+            node.children()
+                .find(|x| x.is_element() && x.has_tag_name(name))
+                .ok_or_else(|| PluginError::MissingElement {
+                    location: self.location(node),
+                    element: name.to_string(),
+                })
+        }
+
+        fn number<T: FromStr>(
+            &self,
+            node: Node,
+            field: &str,
+            value: &str,
+        ) -> Result<T, PluginError> {
+            value.trim().parse().map_err(|_| PluginError::BadNumber {
+                location: self.location(node),
+                field: field.to_string(),
+                value: value.to_string(),
+            })
+        }
+
+        fn boolean(&self, node: Node, field: &str, value: &str) -> Result<bool, PluginError> {
+            value.trim().parse().map_err(|_| PluginError::BadBoolean {
+                location: self.location(node),
+                field: field.to_string(),
+                value: value.to_string(),
+            })
+        }
+
+        /// Split a comma-separated pair such as `"2,3"` into two numbers.
+        fn number_pair<T: FromStr>(
+            &self,
+            node: Node,
+            field: &str,
+            value: &str,
+        ) -> Result<(T, T), PluginError> {
+            let values: Vec<_> = value.split(',').collect();
+            if values.len() != 2 {
+                return Err(PluginError::BadNumber {
+                    location: self.location(node),
+                    field: field.to_string(),
+                    value: value.to_string(),
+                });
+            }
+            Ok((
+                self.number(node, field, values[0])?,
+                self.number(node, field, values[1])?,
+            ))
+        }
+    }
+
+    /// Summary of everything that happened while loading a set of plugins.
+    #[derive(Debug, Default)]
+    pub struct PluginLoadReport {
+        pub plugins_found: usize,
+        pub plugins_loaded: Vec<LoadedPlugin>,
+        pub contributions_parsed: BTreeMap<String, usize>,
+        pub failed_plugins: Vec<PluginError>,
+        pub skipped_contributions: Vec<PluginError>,
+        pub missing_textures: Vec<PathBuf>,
+        pub shadowed_plugins: Vec<PathBuf>,
+        pub duplicate_contributions: Vec<DuplicateContribution>,
+    }
+
+    #[derive(Debug)]
+    pub struct LoadedPlugin {
+        pub filename: PathBuf,
+        pub title: String,
+        pub encoding: &'static Encoding,
+    }
+
+    impl PluginLoadReport {
+        pub fn has_errors(&self) -> bool {
+            !self.failed_plugins.is_empty()
+                || !self.skipped_contributions.is_empty()
+                || !self.missing_textures.is_empty()
+                || !self.duplicate_contributions.is_empty()
+        }
+
+        pub fn to_json(&self) -> serde_json::Value {
+            let error_json = |err: &PluginError| match err.location() {
+                Some(location) => json!({
+                    "plugin": err.plugin().display().to_string(),
+                    "contribution_id": location.contribution_id,
+                    "line": location.position.row,
+                    "column": location.position.col,
+                    "message": err.message(),
+                }),
+                None => json!({
+                    "plugin": err.plugin().display().to_string(),
+                    "message": err.message(),
+                }),
+            };
+
+            json!({
+                "plugins_found": self.plugins_found,
+                "plugins_loaded": self
+                    .plugins_loaded
+                    .iter()
+                    .map(|x| json!({
+                        "plugin": x.filename.display().to_string(),
+                        "title": x.title,
+                        "encoding": x.encoding.name(),
+                    }))
+                    .collect::<Vec<_>>(),
+                "contributions_parsed": self.contributions_parsed,
+                "failed_plugins": self.failed_plugins.iter().map(error_json).collect::<Vec<_>>(),
+                "skipped_contributions": self
+                    .skipped_contributions
+                    .iter()
+                    .map(error_json)
+                    .collect::<Vec<_>>(),
+                "missing_textures": self
+                    .missing_textures
+                    .iter()
+                    .map(|x| x.display().to_string())
+                    .collect::<Vec<_>>(),
+                "duplicate_contributions": self
+                    .duplicate_contributions
+                    .iter()
+                    .map(|x| json!({
+                        "title": x.key.namespace,
+                        "id": x.key.id,
+                        "first": x.first.display().to_string(),
+                        "second": x.second.display().to_string(),
+                    }))
+                    .collect::<Vec<_>>(),
+                "shadowed_plugins": self
+                    .shadowed_plugins
+                    .iter()
+                    .map(|x| x.display().to_string())
+                    .collect::<Vec<_>>(),
+            })
+        }
+    }
+
+    impl fmt::Display for PluginLoadReport {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(
+                f,
+                "Loaded {} of {} plugins",
+                self.plugins_loaded.len(),
+                self.plugins_found
+            )?;
+            for plugin in &self.plugins_loaded {
+                writeln!(
+                    f,
+                    "  '{}' ({}) from {}",
+                    plugin.title,
+                    plugin.encoding.name(),
+                    plugin.filename.display()
+                )?;
+            }
+            for (contribution_type, count) in &self.contributions_parsed {
+                writeln!(f, "  {}: {}", contribution_type, count)?;
+            }
+            for err in &self.failed_plugins {
+                writeln!(f, "Error: {}", err)?;
+            }
+            for err in &self.skipped_contributions {
+                writeln!(f, "Warning: skipped contribution: {}", err)?;
+            }
+            for texture in &self.missing_textures {
+                writeln!(f, "Warning: missing texture {}", texture.display())?;
+            }
+            for duplicate in &self.duplicate_contributions {
+                writeln!(f, "Warning: {}", duplicate)?;
+            }
+            for plugin in &self.shadowed_plugins {
+                writeln!(
+                    f,
+                    "Note: {} is shadowed by a plugin of the same name",
+                    plugin.display()
+                )?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Environment variable holding extra plugin roots, separated like `PATH`.
+    pub const PLUGIN_PATH_ENV: &str = "OPENFT_PLUGIN_PATH";
+
+    /// The directories plugins are loaded from, in order of precedence: paths given on the
+    /// command line, then `OPENFT_PLUGIN_PATH`, then the per-user data directory, then the
+    /// directory bundled next to the executable, and finally `./plugin` for running from a
+    /// source checkout. Roots that don't exist are skipped when enumerating.
+    pub fn plugin_search_paths(extra_paths: &[PathBuf]) -> Vec<PathBuf> {
+        let mut roots = extra_paths.to_vec();
+
+        if let Some(paths) = env::var_os(PLUGIN_PATH_ENV) {
+            roots.extend(env::split_paths(&paths).filter(|x| !x.as_os_str().is_empty()));
+        }
+
+        if let Some(data_dir) = dirs::data_dir() {
+            roots.push(data_dir.join("openft").join("plugin"));
+        }
+
+        if let Some(exe_dir) = env::current_exe()
+            .ok()
+            .and_then(|x| x.parent().map(Path::to_path_buf))
+        {
+            roots.push(exe_dir.join("plugin"));
+        }
+
+        roots.push(PathBuf::from("./plugin"));
+
+        // The same directory may be reachable through several of the rules above.
+        let mut unique: Vec<PathBuf> = Vec::new();
+        for root in roots {
+            let canonical = root.canonicalize().unwrap_or_else(|_| root.clone());
+            if !unique
+                .iter()
+                .any(|x| x.canonicalize().unwrap_or_else(|_| x.clone()) == canonical)
+            {
+                unique.push(root);
+            }
+        }
+        unique
+    }
+
+    /// Plugin folders found across a set of search roots.
+    #[derive(Debug, Default)]
+    pub struct PluginEnumeration {
+        pub plugins: Vec<PathBuf>,
+        /// Plugins hidden by a folder or zip of the same name in an earlier root.
+        pub shadowed: Vec<PathBuf>,
+        pub missing_roots: Vec<PathBuf>,
+    }
+
+    /// List the plugin folders and zip archives in each root. When the same plugin name
+    /// appears in more than one root, the first root wins; `foo.zip` counts as `foo`.
+    /// Folders within a root are sorted by name so that load order doesn't depend on the
+    /// filesystem.
+    pub fn enumerate_plugins(roots: &[PathBuf]) -> PluginEnumeration {
+        let mut enumeration = PluginEnumeration::default();
+        let mut seen = HashSet::new();
+
+        for root in roots {
+            let mut dirs = match fs::read_dir(root) {
+                Ok(entries) => entries
+                    .filter_map(|r| r.ok())
+                    .map(|r| r.path())
+                    .filter(|r| r.is_dir() || is_zip(r))
+                    .collect::<Vec<PathBuf>>(),
                 Err(_) => {
-                    println!(
-                        "Warning: Plugin {} does not have a root file",
-                        path.display()
-                    );
+                    enumeration.missing_roots.push(root.clone());
                     continue;
                 }
             };
-
-            let (mut xml_data, _, had_errors) = UTF_8.decode(&xml_bytes);
-            if had_errors {
-                (xml_data, _, _) = SHIFT_JIS.decode(&xml_bytes);
+            dirs.sort();
+
+            for dir in dirs {
+                // foo.zip is the same plugin as a foo folder.
+                let name = match is_zip(&dir) {
+                    true => dir.file_stem(),
+                    false => dir.file_name(),
+                };
+                if seen.insert(name.map(|x| x.to_os_string()).unwrap_or_default()) {
+                    enumeration.plugins.push(dir);
+                } else {
+                    enumeration.shadowed.push(dir);
+                }
             }
+        }
 
-            let res = parse_plugin_xml(path, &xml_data);
+        enumeration
+    }
 
-            match res {
-                Ok(plugin) => plugins.push(plugin),
+    pub fn load_plugins(enumeration: PluginEnumeration) -> (Vec<Plugin>, PluginLoadReport) {
+        let mut plugins = Vec::new();
+        let mut report = PluginLoadReport {
+            plugins_found: enumeration.plugins.len(),
+            shadowed_plugins: enumeration.shadowed,
+            ..Default::default()
+        };
+
+        for path in enumeration.plugins {
+            let files = match VirtualDir::open(&path) {
+                Ok(Some(files)) => files,
+                Ok(None) => continue,
+                Err(err) => {
+                    report
+                        .failed_plugins
+                        .push(PluginError::MissingRootFile(path, err));
+                    continue;
+                }
+            };
+
+            let xml_bytes = match files.read("plugin.xml") {
+                Ok(file) => file,
                 Err(err) => {
-                    println!("Error: {:?}", err);
+                    report
+                        .failed_plugins
+                        .push(PluginError::MissingRootFile(path, err));
+                    continue;
+                }
+            };
+
+            match parse_plugin_xml(files, &xml_bytes, &mut report) {
+                Ok(plugin) => {
+                    // Contributions without pictures of their own, like trains, have no image_ref.
+                    let image_refs = plugin
+                        .contributions
+                        .iter()
+                        .flat_map(|x| Some(&x.image_ref).into_iter().chain(&x.night_image_ref))
+                        .filter(|x| !x.is_empty());
+                    for image_ref in image_refs {
+                        let texture_path = plugin.filename.join(image_ref);
+                        if !plugin.files.exists(image_ref)
+                            && !report.missing_textures.contains(&texture_path)
+                        {
+                            report.missing_textures.push(texture_path);
+                        }
+                    }
+                    report.plugins_loaded.push(LoadedPlugin {
+                        filename: plugin.filename.clone(),
+                        title: plugin.title.clone(),
+                        encoding: plugin.encoding,
+                    });
+                    plugins.push(plugin);
                 }
+                Err(err) => report.failed_plugins.push(err),
             }
         }
 
-        plugins
+        report.duplicate_contributions = find_duplicate_contributions(&plugins);
+
+        (plugins, report)
     }
 
-    pub fn parse_plugin_xml(filename: PathBuf, data: &str) -> Result<Plugin, PluginError> {
+    /// Parse a plugin.xml document. Contributions that fail to parse are left out of the
+    /// plugin and recorded in the report; only problems with the plugin as a whole
+    /// (malformed XML, missing title or author) fail the entire plugin.
+    pub fn parse_plugin_xml(
+        files: VirtualDir,
+        xml_bytes: &[u8],
+        report: &mut PluginLoadReport,
+    ) -> Result<Plugin, PluginError> {
+        let filename = files.path().to_path_buf();
+        let (data, encoding) = decode_plugin_xml(xml_bytes);
+
         let options = ParsingOptions {
             allow_dtd: true,
             ..Default::default()
@@ -118,104 +779,204 @@ pub mod plugin_manager {
 
         let doc = match roxmltree::Document::parse_with_options(&data, options) {
             Ok(doc) => doc,
-            Err(err) => return Err(PluginError::ParseError(err)),
+            Err(err) => return Err(PluginError::ParseError(filename, err)),
         };
 
-        let mut metadata = HashMap::new();
-        let mut contributions = Vec::new();
+        let ctx = ParseContext { plugin: &filename };
 
-        let root = doc.descendants().find(|x| x.tag_name().name() == "plug-in");
-        match root {
-            Some(root) => {
-                let metadata_nodes = root
-                    .children()
-                    .filter(|x| x.is_element() && x.tag_name().name() != "contribution");
+        let root = match doc.descendants().find(|x| x.has_tag_name("plug-in")) {
+            Some(root) => root,
+            None => return Err(PluginError::MissingPluginElement(filename)),
+        };
 
-                for node in metadata_nodes {
-                    let (k, v) = parse_metadata_field(node);
-                    metadata.insert(k, v);
-                }
+        let mut metadata = HashMap::new();
+        let metadata_nodes = root
+            .children()
+            .filter(|x| x.is_element() && x.tag_name().name() != "contribution");
 
-                println!(
-                    "Found plugin '{}' by {}",
-                    metadata["title"], metadata["author"]
-                );
+        for node in metadata_nodes {
+            let (k, v) = parse_metadata_field(node);
+            metadata.insert(k, v);
+        }
 
-                let pictures = root
-                    .children()
-                    .filter(|x| x.tag_name().name() == "contribution")
-                    .filter(|y| y.attribute("type").unwrap_or("") == "picture");
+        let title = required_metadata(&ctx, root, &metadata, "title")?;
+        let author = required_metadata(&ctx, root, &metadata, "author")?;
 
-                let mut picture_contributions = HashMap::new();
-                for picture in pictures {
-                    let (k, v) = parse_picture_contribution(picture);
+        let pictures = root
+            .children()
+            .filter(|x| x.has_tag_name("contribution"))
+            .filter(|y| y.attribute("type").unwrap_or("") == "picture");
+
+        let mut picture_contributions = HashMap::new();
+        let mut night_pictures = HashMap::new();
+        for picture in pictures {
+            match parse_picture_contribution(&ctx, picture) {
+                Ok((k, v, night)) => {
+                    if let Some(night) = night {
+                        night_pictures.insert(v.clone(), night);
+                    }
                     picture_contributions.insert(k, v);
+                    *report
+                        .contributions_parsed
+                        .entry("picture".to_string())
+                        .or_default() += 1;
                 }
+                Err(err) => report.skipped_contributions.push(err),
+            }
+        }
 
-                let other_contributions = root
-                    .children()
-                    .filter(|x| x.tag_name().name() == "contribution")
-                    .filter(|y| y.attribute("type").unwrap_or("picture") != "picture");
+        let other_contributions = root
+            .children()
+            .filter(|x| x.has_tag_name("contribution"))
+            .filter(|y| y.attribute("type").unwrap_or("") != "picture");
 
-                for other in other_contributions {
-                    contributions.push(parse_contribution(other));
+        let mut contributions = Vec::new();
+        for other in other_contributions {
+            match parse_contribution(&ctx, other, &picture_contributions) {
+                Ok(mut contribution) => {
+                    contribution.night_image_ref =
+                        night_pictures.get(&contribution.image_ref).cloned();
+                    *report
+                        .contributions_parsed
+                        .entry(contribution.contribution_type.name().to_string())
+                        .or_default() += 1;
+                    contributions.push(contribution);
                 }
-
-                resolve_contribution_refs(&picture_contributions, &mut contributions);
-            }
-            None => {
-                println!("Plugin not found for {}", filename.display());
+                Err(err) => report.skipped_contributions.push(err),
             }
         }
 
-        let title = metadata["title"].to_string();
-        let author = metadata["author"].to_string();
-
         Ok(Plugin {
             filename,
+            files,
+            encoding,
             title,
             author,
             contributions,
         })
     }
 
-    fn parse_metadata_field(node: Node) -> (String, String) {
-        let key = node.tag_name().name();
-        let value = node.first_child().unwrap().text().unwrap_or("").trim();
-        return (key.to_string(), value.to_string());
+    /// Decode a plugin.xml file. A byte order mark takes precedence, then the encoding named
+    /// in the XML declaration. Files that declare nothing are tried as UTF-8 and then as
+    /// Shift-JIS, which covers almost every FreeTrain plugin.
+    pub fn decode_plugin_xml(bytes: &[u8]) -> (Cow<'_, str>, &'static Encoding) {
+        if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+            let (data, _) = encoding.decode_with_bom_removal(bytes);
+            return (data, encoding);
+        }
+
+        if let Some(encoding) = declared_encoding(bytes) {
+            let (data, _) = encoding.decode_without_bom_handling(bytes);
+            return (data, encoding);
+        }
+
+        let (data, had_errors) = UTF_8.decode_without_bom_handling(bytes);
+        if !had_errors {
+            return (data, UTF_8);
+        }
+        (SHIFT_JIS.decode_without_bom_handling(bytes).0, SHIFT_JIS)
     }
 
-    fn parse_picture_contribution(node: Node) -> (String, String) {
-        let id = node.attribute("id").unwrap().to_string();
+    /// Read the `encoding` pseudo-attribute from the XML declaration, if there is one.
+    fn declared_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
+        // UTF-16 without a byte order mark is recognisable from how '<?' is laid out.
+        if bytes.starts_with(&[b'<', 0, b'?', 0]) {
+            return Some(UTF_16LE);
+        }
+        if bytes.starts_with(&[0, b'<', 0, b'?']) {
+            return Some(UTF_16BE);
+        }
 
-        let picture_node = node.children().find(|x| x.tag_name().name() == "picture");
-        if picture_node.is_none() {
-            //error
-            todo!()
+        if !bytes.starts_with(b"<?xml") {
+            return None;
         }
 
-        let picture_node = picture_node.unwrap();
-        if !picture_node.has_attribute("src") {
-            //error
-            todo!()
+        let end = bytes.windows(2).position(|x| x == b"?>")?;
+        let declaration = std::str::from_utf8(&bytes[..end]).ok()?;
+
+        let start = declaration.find("encoding")? + "encoding".len();
+        let rest = declaration[start..]
+            .trim_start()
+            .strip_prefix('=')?
+            .trim_start();
+        let quote = rest.chars().next().filter(|x| *x == '"' || *x == '\'')?;
+        let label = &rest[1..];
+        let label = &label[..label.find(quote)?];
+
+        match Encoding::for_label(label.as_bytes()) {
+            // The declaration was readable as ASCII, so the file can't really be UTF-16.
+            Some(encoding) if encoding == UTF_16LE || encoding == UTF_16BE => None,
+            other => other,
         }
+    }
 
-        let src = picture_node.attribute("src").unwrap().to_string();
-        (id, src)
+    fn required_metadata(
+        ctx: &ParseContext,
+        node: Node,
+        metadata: &HashMap<String, String>,
+        field: &str,
+    ) -> Result<String, PluginError> {
+        match metadata.get(field) {
+            Some(value) => Ok(value.clone()),
+            None => Err(PluginError::MissingMetadata {
+                location: ctx.location(node),
+                field: field.to_string(),
+            }),
+        }
     }
 
-    fn parse_contribution(node: Node) -> Contribution {
+    fn parse_metadata_field(node: Node) -> (String, String) {
+        let key = node.tag_name().name();
+        let value = node.text().unwrap_or("").trim();
+        (key.to_string(), value.to_string())
+    }
+
+    /// A picture that other contributions refer to by id, and optionally the picture to
+    /// draw in its place at night:
+    ///
+    /// ```xml
+    /// <contribution type="picture" id="...">
+    ///   <picture src="day.bmp"/>
+    ///   <night src="night.bmp"/>
+    /// </contribution>
+    /// ```
+    fn parse_picture_contribution(
+        ctx: &ParseContext,
+        node: Node,
+    ) -> Result<(String, String, Option<String>), PluginError> {
+        let id = ctx.attribute(node, "id")?.to_string();
+        let picture_node = ctx.child(node, "picture")?;
+        let src = ctx.attribute(picture_node, "src")?.to_string();
+        let night = match node
+            .children()
+            .find(|x| x.is_element() && x.has_tag_name("night"))
+        {
+            Some(night) => Some(ctx.attribute(night, "src")?.to_string()),
+            None => None,
+        };
+        Ok((id, src, night))
+    }
+
+    fn parse_contribution(
+        ctx: &ParseContext,
+        node: Node,
+        pictures: &HashMap<String, String>,
+    ) -> Result<Contribution, PluginError> {
         match node.attribute("type") {
             Some(contrib_type) => match contrib_type {
-                "GenericStructure" => {
-                    return parse_generic_structure_contribution(node);
-                }
-                "road" => {
-                    return parse_road_contribution(node);
-                }
-                other => panic!("Invalid or unimplemented contribution type '{}'", other),
+                "GenericStructure" => parse_generic_structure_contribution(ctx, node, pictures),
+                "road" => parse_road_contribution(ctx, node, pictures),
+                "rail" => parse_rail_contribution(ctx, node, pictures),
+                "train" => parse_train_contribution(ctx, node),
+                "trainCar" => parse_train_car_contribution(ctx, node, pictures),
+                "station" => parse_station_contribution(ctx, node, pictures),
+                "land" => parse_land_contribution(ctx, node, pictures),
+                other => Err(PluginError::UnknownContributionType {
+                    location: ctx.location(node),
+                    contribution_type: other.to_string(),
+                }),
             },
-            None => todo!(),
+            None => Err(PluginError::MissingContributionType(ctx.location(node))),
         }
     }
 
@@ -232,57 +993,84 @@ pub mod plugin_manager {
         metadata
     }
 
-    fn parse_generic_structure_contribution(node: Node) -> Contribution {
+    fn parse_generic_structure_contribution(
+        ctx: &ParseContext,
+        node: Node,
+        pictures: &HashMap<String, String>,
+    ) -> Result<Contribution, PluginError> {
         let metadata = parse_metadata(node);
 
-        let mut color_mappings = parse_hue_transform_nodes(node);
-        if color_mappings.len() == 0 {
+        let mut color_mappings = parse_hue_transform_nodes(ctx, node)?;
+        if color_mappings.is_empty() {
             color_mappings.push(ColorMapping {
                 channel: ColorMappingChannel::None,
                 target: BLACK,
             });
         };
 
-        let (sprites, sprite_ref) = parse_generic_structure_sprite(node);
-        let (pictures, picture_ref) = parse_generic_structure_multi(node);
-
-        let image_data = match (sprites.len(), pictures.len()) {
-            (_, 0) => sprites
-                .into_iter()
-                .map(|x| ContributionImageData::ContributionSprite(x))
-                .collect::<Vec<ContributionImageData>>(),
-
-            (0, _) => pictures
-                .into_iter()
-                .map(|x| ContributionImageData::ContributionMultistorey(x))
-                .collect::<Vec<ContributionImageData>>(),
-
-            _ => panic!("No image data found"),
+        let (sprites, sprite_ref) = parse_generic_structure_sprite(ctx, node)?;
+        let (multis, multi_ref) = parse_generic_structure_multi(ctx, node)?;
+        let autotile = parse_autotile(ctx, node, pictures)?;
+
+        let (image_data, image_ref) = match (sprites.len(), multis.len(), autotile) {
+            (0, 0, None) => return Err(PluginError::MissingImageData(ctx.location(node))),
+            (_, 0, None) => (
+                sprites
+                    .into_iter()
+                    .map(ContributionImageData::ContributionSprite)
+                    .collect::<Vec<ContributionImageData>>(),
+                resolve_picture_ref(ctx, node, sprite_ref, pictures)?,
+            ),
+            (0, _, None) => (
+                multis
+                    .into_iter()
+                    .map(ContributionImageData::ContributionMultistorey)
+                    .collect::<Vec<ContributionImageData>>(),
+                resolve_picture_ref(ctx, node, multi_ref, pictures)?,
+            ),
+            (0, 0, Some((autotile, image_ref))) => (
+                vec![ContributionImageData::ContributionAutotile(autotile)],
+                image_ref,
+            ),
+            _ => return Err(PluginError::ConflictingImageData(ctx.location(node))),
         };
 
-        let image_ref = match (sprite_ref.is_empty(), picture_ref.is_empty()) {
-            (false, true) => sprite_ref,
-            (true, false) => picture_ref,
-            (false, false) => panic!("Too many image reference data types"),
-            (true, true) => panic!("No image reference data found"),
+        let size = match metadata.get("size") {
+            Some(size) => size,
+            None => {
+                return Err(PluginError::MissingMetadata {
+                    location: ctx.location(node),
+                    field: "size".to_string(),
+                })
+            }
         };
 
-        let size = &metadata["size"];
-        let sizes: Vec<_> = size.split(",").collect();
-
         // x and y are usually flipped in our coordinate system relative to the plugins.
-        let size_x: i32 = sizes[1].parse().unwrap_or(0);
-        let size_y: i32 = sizes[0].parse().unwrap_or(0);
-
-        let height = match metadata.get("height") {
-            Some(h) => h.parse().unwrap_or(1),
-            None => match metadata.get("maxHeight") {
-                Some(h) => h.parse().unwrap_or(1),
-                None => 1,
-            },
+        let (size_y, size_x): (i32, i32) = ctx.number_pair(node, "size", size)?;
+
+        let height = match metadata.get("height").or_else(|| metadata.get("maxHeight")) {
+            Some(h) => ctx.number(node, "height", h)?,
+            None => 1,
+        };
+        let min_height: usize = match metadata.get("minHeight") {
+            Some(h) => ctx.number(node, "minHeight", h)?,
+            None => 1,
+        };
+        let id = ctx.attribute(node, "id")?.to_string();
+
+        let properties = StructureProperties {
+            name: metadata.get("name").cloned().unwrap_or_else(|| id.clone()),
+            group: metadata
+                .get("group")
+                .or_else(|| metadata.get("category"))
+                .cloned(),
+            min_height: min_height.max(1),
+            max_height: (height.max(1) as usize).max(min_height.max(1)),
         };
 
-        Contribution {
+        Ok(Contribution {
+            id,
+            contribution_type: ContributionType::GenericStructure,
             size: Tile {
                 x: size_x,
                 y: size_y,
@@ -290,269 +1078,926 @@ pub mod plugin_manager {
             },
             image_data,
             image_ref,
+            night_image_ref: None,
             color_mappings,
-        }
+            properties: ContributionProperties::Structure(properties),
+        })
     }
 
-    fn parse_road_contribution(node: Node) -> Contribution {
-        // let metadata = parse_metadata(node);
+    /// A station building. Its sprites and size are given as for a `GenericStructure`,
+    /// along with:
+    ///
+    /// ```xml
+    /// <name>...</name>
+    /// <operationRange>8</operationRange>
+    /// <platform side="S"/>
+    /// ```
+    ///
+    /// The platform runs the length of the building on the given side (N, E, S or W), and
+    /// `operationRange` is how many tiles around the station it draws passengers from.
+    fn parse_station_contribution(
+        ctx: &ParseContext,
+        node: Node,
+        pictures: &HashMap<String, String>,
+    ) -> Result<Contribution, PluginError> {
+        let mut contribution = parse_generic_structure_contribution(ctx, node, pictures)?;
+        let metadata = parse_metadata(node);
+
+        let catchment_radius = match metadata.get("operationRange") {
+            Some(radius) => ctx.number(node, "operationRange", radius)?,
+            None => 6,
+        };
 
-        let sprite_node = node
+        let platform_side = match node
             .children()
-            .find(|x| x.is_element() && x.tag_name().name() == "picture");
+            .find(|x| x.is_element() && x.has_tag_name("platform"))
+        {
+            Some(platform) => {
+                let side = platform.attribute("side").unwrap_or("S");
+                match Direction::parse(side) {
+                    Some(d) if !d.is_diagonal() => d,
+                    _ => {
+                        return Err(PluginError::BadDirection {
+                            location: ctx.location(platform),
+                            field: "side".to_string(),
+                            value: side.to_string(),
+                        })
+                    }
+                }
+            }
+            None => Direction::South,
+        };
 
-        let image_ref: String;
+        contribution.contribution_type = ContributionType::Station;
+        contribution.properties = ContributionProperties::Station(StationProperties {
+            name: metadata
+                .get("name")
+                .cloned()
+                .unwrap_or_else(|| contribution.id.clone()),
+            catchment_radius,
+            platform_side,
+        });
+        Ok(contribution)
+    }
+
+    /// A road is an autotile. Besides a full `<autotile>` element, the older form of a single
+    /// `<picture>` sheet is accepted: `ROAD_PIECES` pieces laid out four to a row, where a
+    /// piece's index is the road's connection mask (see `tilemap_manager::CONNECT_NORTH` and
+    /// friends). `size` is the size of one piece and `offset` how far it extends above the tile.
+    fn parse_road_contribution(
+        ctx: &ParseContext,
+        node: Node,
+        pictures: &HashMap<String, String>,
+    ) -> Result<Contribution, PluginError> {
+        let (autotile, image_ref) = match parse_autotile(ctx, node, pictures)? {
+            Some(autotile) => autotile,
+            None => {
+                let sprite = ctx.child(node, "picture")?;
+                let image_ref = ctx.attribute(sprite, "src")?.to_owned();
 
-        match sprite_node {
-            Some(sprite) => {
-                let src = sprite.attribute("src").expect("No image data found!");
                 let size = sprite.attribute("size").unwrap_or("32,16");
+                let (w, h): (i32, i32) = ctx.number_pair(sprite, "size", size)?;
                 let offset = sprite.attribute("offset").unwrap_or("0");
+                let offset: i32 = ctx.number(sprite, "offset", offset)?;
+
+                let sprites = (0..ROAD_PIECES as i32)
+                    .map(|i| ContributionSprite {
+                        origin_x: (i % 4) * w,
+                        origin_y: (i / 4) * (h + offset),
+                        offset,
+                        opposite: false,
+                    })
+                    .collect();
+
+                let autotile = ContributionAutotile {
+                    rule: AutotileRule::identity(AutotileNeighbours::Four, ROAD_PIECES),
+                    sprites,
+                };
+                (autotile, image_ref)
+            }
+        };
+
+        Ok(Contribution {
+            id: ctx.attribute(node, "id")?.to_string(),
+            contribution_type: ContributionType::Road,
+            size: Tile { x: 1, y: 1, z: 1 },
+            image_ref,
+            night_image_ref: None,
+            image_data: vec![ContributionImageData::ContributionAutotile(autotile)],
+            color_mappings: vec![ColorMapping {
+                channel: ColorMappingChannel::None,
+                target: BLACK,
+            }],
+            properties: ContributionProperties::None,
+        })
+    }
+
+    /// A rail style: one picture and a `<pattern>` for each track segment it can draw.
+    ///
+    /// ```xml
+    /// <contribution type="rail" id="...">
+    ///   <picture ref="..."/>
+    ///   <pattern from="N" to="S" origin="0,0" offset="0"/>
+    ///   <pattern from="S" to="N" rise="1" origin="32,0" offset="8"/>
+    /// </contribution>
+    /// ```
+    ///
+    /// `from` and `to` are FreeTrain directions (N, NE, E, ..., NW) and `rise` is how many
+    /// levels the track climbs from `from` to `to`. Segments without a pattern aren't drawn.
+    fn parse_rail_contribution(
+        ctx: &ParseContext,
+        node: Node,
+        pictures: &HashMap<String, String>,
+    ) -> Result<Contribution, PluginError> {
+        let picture_node = ctx.child(node, "picture")?;
+        let image_ref = match picture_node.attribute("src") {
+            Some(src) => src.to_string(),
+            None => {
+                let picture_ref = ctx.attribute(picture_node, "ref")?.to_string();
+                resolve_picture_ref(ctx, node, Some((picture_ref, picture_node)), pictures)?
+            }
+        };
+
+        let pattern_nodes = node
+            .children()
+            .filter(|x| x.is_element() && x.has_tag_name("pattern"));
+
+        let mut sprites = Vec::new();
+        for pattern in pattern_nodes {
+            let from = ctx.attribute(pattern, "from")?;
+            let to = ctx.attribute(pattern, "to")?;
+            let rise: i8 = ctx.number(pattern, "rise", pattern.attribute("rise").unwrap_or("0"))?;
+
+            let segment = Direction::parse(from)
+                .zip(Direction::parse(to))
+                .and_then(|(a, b)| TrackSegment::new(a, b, rise));
+
+            let segment = match segment {
+                Some(segment) => segment,
+                None => {
+                    return Err(PluginError::InvalidTrack {
+                        location: ctx.location(pattern),
+                        from: from.to_string(),
+                        to: to.to_string(),
+                        rise,
+                    })
+                }
+            };
+
+            sprites.push((segment, parse_origin_offset_and_opposite(ctx, pattern)?));
+        }
+
+        if sprites.is_empty() {
+            return Err(PluginError::MissingImageData(ctx.location(node)));
+        }
+
+        Ok(Contribution {
+            id: ctx.attribute(node, "id")?.to_string(),
+            contribution_type: ContributionType::Rail,
+            size: Tile { x: 1, y: 1, z: 1 },
+            image_ref,
+            night_image_ref: None,
+            image_data: vec![ContributionImageData::ContributionTrack(
+                ContributionTrack { sprites },
+            )],
+            color_mappings: vec![ColorMapping {
+                channel: ColorMappingChannel::None,
+                target: BLACK,
+            }],
+            properties: ContributionProperties::None,
+        })
+    }
+
+    /// A kind of ground that can cover a tile:
+    ///
+    /// ```xml
+    /// <contribution type="land" id="...">
+    ///   <name>...</name>
+    ///   <sprite origin="0,0" offset="0" seasonal="true">
+    ///     <picture ref="..."/>
+    ///   </sprite>
+    /// </contribution>
+    /// ```
+    ///
+    /// A seasonal sprite is `LAND_SEASONS` tiles side by side from `origin`, spring first,
+    /// as in `res/GroundSeasonal.png`.
+    fn parse_land_contribution(
+        ctx: &ParseContext,
+        node: Node,
+        pictures: &HashMap<String, String>,
+    ) -> Result<Contribution, PluginError> {
+        let metadata = parse_metadata(node);
+        let id = ctx.attribute(node, "id")?.to_string();
+
+        let sprite = ctx.child(node, "sprite")?;
+        let picture_node = ctx.child(sprite, "picture")?;
+        let image_ref = match picture_node.attribute("src") {
+            Some(src) => src.to_string(),
+            None => {
+                let picture_ref = ctx.attribute(picture_node, "ref")?.to_string();
+                resolve_picture_ref(ctx, node, Some((picture_ref, picture_node)), pictures)?
+            }
+        };
+
+        let (origin_x, origin_y): (i32, i32) = ctx.number_pair(
+            sprite,
+            "origin",
+            sprite.attribute("origin").unwrap_or("0,0"),
+        )?;
+        let offset = ctx.number(sprite, "offset", sprite.attribute("offset").unwrap_or("0"))?;
+        let seasonal = sprite.attribute("seasonal").unwrap_or("false");
+        let seasonal = ctx.boolean(sprite, "seasonal", seasonal)?;
+
+        let sprites = match seasonal {
+            true => LAND_SEASONS as i32,
+            false => 1,
+        };
+        let image_data = (0..sprites)
+            .map(|i| {
+                ContributionImageData::ContributionSprite(ContributionSprite {
+                    origin_x: origin_x + i * TILE_W,
+                    origin_y,
+                    offset,
+                    opposite: false,
+                })
+            })
+            .collect();
+
+        Ok(Contribution {
+            contribution_type: ContributionType::Land,
+            size: Tile { x: 1, y: 1, z: 1 },
+            image_ref,
+            night_image_ref: None,
+            image_data,
+            color_mappings: vec![ColorMapping {
+                channel: ColorMappingChannel::None,
+                target: BLACK,
+            }],
+            properties: ContributionProperties::Land(LandProperties {
+                name: metadata.get("name").cloned().unwrap_or_else(|| id.clone()),
+                seasonal,
+            }),
+            id,
+        })
+    }
 
-                image_ref = src.to_owned();
-                println!("{} {} {}", src, size, offset);
+    /// A car that trains are made up of:
+    ///
+    /// ```xml
+    /// <contribution type="trainCar" id="...">
+    ///   <name>...</name>
+    ///   <capacity>80</capacity>
+    ///   <sprite directions="8" size="32,24" origin="0,0" offset="4">
+    ///     <picture ref="..."/>
+    ///   </sprite>
+    /// </contribution>
+    /// ```
+    ///
+    /// The sheet has one frame per direction (8 or 16) laid out left to right from
+    /// `origin`, starting with the car facing north and turning clockwise. `offset` raises
+    /// the frames above the track. Hue transforms give colour variants as for structures.
+    fn parse_train_car_contribution(
+        ctx: &ParseContext,
+        node: Node,
+        pictures: &HashMap<String, String>,
+    ) -> Result<Contribution, PluginError> {
+        let metadata = parse_metadata(node);
+        let id = ctx.attribute(node, "id")?.to_string();
+
+        let sprite = ctx.child(node, "sprite")?;
+        let picture_node = ctx.child(sprite, "picture")?;
+        let image_ref = match picture_node.attribute("src") {
+            Some(src) => src.to_string(),
+            None => {
+                let picture_ref = ctx.attribute(picture_node, "ref")?.to_string();
+                resolve_picture_ref(ctx, node, Some((picture_ref, picture_node)), pictures)?
             }
-            None => panic!("No image data found!"),
+        };
+
+        let directions = sprite.attribute("directions").unwrap_or("8");
+        let directions: usize = ctx.number(sprite, "directions", directions)?;
+        if !CAR_DIRECTIONS.contains(&directions) {
+            return Err(PluginError::BadNumber {
+                location: ctx.location(sprite),
+                field: "directions".to_string(),
+                value: directions.to_string(),
+            });
         }
 
-        Contribution {
+        let (width, height): (i32, i32) =
+            ctx.number_pair(sprite, "size", ctx.attribute(sprite, "size")?)?;
+        let (origin_x, origin_y): (i32, i32) = ctx.number_pair(
+            sprite,
+            "origin",
+            sprite.attribute("origin").unwrap_or("0,0"),
+        )?;
+        let offset = ctx.number(sprite, "offset", sprite.attribute("offset").unwrap_or("0"))?;
+
+        let sprites = (0..directions as i32)
+            .map(|i| ContributionSprite {
+                origin_x: origin_x + i * width,
+                origin_y,
+                offset,
+                opposite: false,
+            })
+            .collect();
+
+        let capacity = match metadata.get("capacity") {
+            Some(capacity) => ctx.number(node, "capacity", capacity)?,
+            None => 0,
+        };
+
+        let mut color_mappings = parse_hue_transform_nodes(ctx, node)?;
+        if color_mappings.is_empty() {
+            color_mappings.push(ColorMapping {
+                channel: ColorMappingChannel::None,
+                target: BLACK,
+            });
+        }
+
+        Ok(Contribution {
+            contribution_type: ContributionType::TrainCar,
             size: Tile { x: 1, y: 1, z: 1 },
             image_ref,
+            night_image_ref: None,
+            image_data: vec![ContributionImageData::ContributionDirectional(
+                ContributionDirectional {
+                    width,
+                    height,
+                    sprites,
+                },
+            )],
+            color_mappings,
+            properties: ContributionProperties::TrainCar(TrainCarProperties {
+                name: metadata.get("name").cloned().unwrap_or_else(|| id.clone()),
+                capacity,
+            }),
+            id,
+        })
+    }
+
+    /// A train type, made up of cars defined by `trainCar` contributions:
+    ///
+    /// ```xml
+    /// <contribution type="train" id="...">
+    ///   <name>...</name>
+    ///   <company>...</company>
+    ///   <description>...</description>
+    ///   <speed>fast</speed>
+    ///   <price>5000</price>
+    ///   <composition minLength="2" maxLength="8">
+    ///     <head ref="..."/>
+    ///     <body ref="..."/>
+    ///     <tail ref="..."/>
+    ///   </composition>
+    /// </contribution>
+    /// ```
+    ///
+    /// `speed` is in km/h or one of FreeTrain's `slow`, `medium`, `fast` and `fastest`.
+    /// `head` and `tail` are optional; without them every car is a body car.
+    fn parse_train_contribution(
+        ctx: &ParseContext,
+        node: Node,
+    ) -> Result<Contribution, PluginError> {
+        let metadata = parse_metadata(node);
+        let id = ctx.attribute(node, "id")?.to_string();
+        let field = |name: &str| metadata.get(name).cloned().unwrap_or_default();
+
+        let speed: f32 = match metadata.get("speed").map(|x| x.as_str()) {
+            Some("slow") => 60.0,
+            Some("medium") | None => 90.0,
+            Some("fast") => 120.0,
+            Some("fastest") => 160.0,
+            Some(speed) => ctx.number(node, "speed", speed)?,
+        };
+        let acceleration = match metadata.get("acceleration") {
+            Some(acceleration) => ctx.number(node, "acceleration", acceleration)?,
+            None => speed / 10.0,
+        };
+        // Trains that can't move, or can't brake, would never reach their next stop.
+        for (field, value) in [("speed", speed), ("acceleration", acceleration)] {
+            if !(value.is_finite() && value > 0.0) {
+                return Err(PluginError::BadNumber {
+                    location: ctx.location(node),
+                    field: field.to_string(),
+                    value: value.to_string(),
+                });
+            }
+        }
+        let price = match metadata.get("price") {
+            Some(price) => ctx.number(node, "price", price)?,
+            None => 0,
+        };
+
+        let composition_node = ctx.child(node, "composition")?;
+        let car = |name: &str| -> Result<Option<String>, PluginError> {
+            match composition_node
+                .children()
+                .find(|x| x.is_element() && x.has_tag_name(name))
+            {
+                Some(car) => Ok(Some(ctx.attribute(car, "ref")?.to_string())),
+                None => Ok(None),
+            }
+        };
+        let head = car("head")?;
+        let tail = car("tail")?;
+        let body = match car("body")? {
+            Some(body) => body,
+            None => {
+                return Err(PluginError::MissingElement {
+                    location: ctx.location(composition_node),
+                    element: "body".to_string(),
+                })
+            }
+        };
+
+        let min_length = composition_node.attribute("minLength").unwrap_or("1");
+        let min_length: usize = ctx.number(composition_node, "minLength", min_length)?;
+        let max_length = composition_node.attribute("maxLength").unwrap_or("10");
+        let max_length: usize = ctx.number(composition_node, "maxLength", max_length)?;
+        if min_length == 0 || max_length < min_length {
+            return Err(PluginError::BadNumber {
+                location: ctx.location(composition_node),
+                field: "maxLength".to_string(),
+                value: max_length.to_string(),
+            });
+        }
+
+        Ok(Contribution {
+            contribution_type: ContributionType::Train,
+            size: Tile { x: 1, y: 1, z: 1 },
+            image_ref: String::new(),
+            night_image_ref: None,
             image_data: vec![],
             color_mappings: vec![],
+            properties: ContributionProperties::Train(TrainProperties {
+                name: metadata.get("name").cloned().unwrap_or_else(|| id.clone()),
+                company: field("company"),
+                description: field("description"),
+                price,
+                speed,
+                acceleration,
+                composition: TrainComposition {
+                    head,
+                    body,
+                    tail,
+                    min_length,
+                    max_length,
+                },
+            }),
+            id,
+        })
+    }
+
+    /// Parse an `<autotile>` element, if the contribution has one:
+    ///
+    /// ```xml
+    /// <autotile neighbours="8">
+    ///   <picture ref="..."/>
+    ///   <piece origin="0,0" offset="8" connects="0,1,4,5"/>
+    ///   ...
+    /// </autotile>
+    /// ```
+    ///
+    /// `neighbours` is 4 (edges only) or 8 (edges and corners). Each piece lists the
+    /// connection masks it is drawn for; the first piece is also used for any mask that
+    /// isn't listed. The picture may be given by `ref` or directly by `src`.
+    fn parse_autotile(
+        ctx: &ParseContext,
+        node: Node,
+        pictures: &HashMap<String, String>,
+    ) -> Result<Option<(ContributionAutotile, String)>, PluginError> {
+        let autotile_node = match node
+            .children()
+            .find(|x| x.is_element() && x.has_tag_name("autotile"))
+        {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let neighbours = match autotile_node.attribute("neighbours").unwrap_or("4") {
+            "4" => AutotileNeighbours::Four,
+            "8" => AutotileNeighbours::Eight,
+            other => {
+                return Err(PluginError::BadNumber {
+                    location: ctx.location(autotile_node),
+                    field: "neighbours".to_string(),
+                    value: other.to_string(),
+                })
+            }
+        };
+
+        let picture_node = ctx.child(autotile_node, "picture")?;
+        let image_ref = match picture_node.attribute("src") {
+            Some(src) => src.to_string(),
+            None => {
+                let picture_ref = ctx.attribute(picture_node, "ref")?.to_string();
+                resolve_picture_ref(ctx, node, Some((picture_ref, picture_node)), pictures)?
+            }
+        };
+
+        let mut sprites = Vec::new();
+        let mut table = HashMap::new();
+        let pieces = autotile_node
+            .children()
+            .filter(|x| x.is_element() && x.has_tag_name("piece"));
+
+        for piece in pieces {
+            let connects = ctx.attribute(piece, "connects")?;
+            for mask in connects.split(',').filter(|x| !x.trim().is_empty()) {
+                table.insert(ctx.number(piece, "connects", mask)?, sprites.len());
+            }
+            sprites.push(parse_origin_offset_and_opposite(ctx, piece)?);
+        }
+
+        if sprites.is_empty() {
+            return Err(PluginError::MissingElement {
+                location: ctx.location(autotile_node),
+                element: "piece".to_string(),
+            });
         }
+
+        let autotile = ContributionAutotile {
+            rule: AutotileRule { neighbours, table },
+            sprites,
+        };
+        Ok(Some((autotile, image_ref)))
     }
 
-    fn parse_hue_transform_nodes(node: Node) -> Vec<ColorMapping> {
+    /// Turn a `<picture ref="...">` into the file it names.
+    fn resolve_picture_ref(
+        ctx: &ParseContext,
+        node: Node,
+        picture_ref: PictureRef,
+        pictures: &HashMap<String, String>,
+    ) -> Result<String, PluginError> {
+        let (picture_ref, picture_node) = match picture_ref {
+            Some(r) => r,
+            None => {
+                return Err(PluginError::MissingElement {
+                    location: ctx.location(node),
+                    element: "picture".to_string(),
+                })
+            }
+        };
+
+        match pictures.get(&picture_ref) {
+            Some(src) => Ok(src.clone()),
+            None => Err(PluginError::MissingPictureRef {
+                location: ctx.location(picture_node),
+                image_ref: picture_ref,
+            }),
+        }
+    }
+
+    /// Colour variants, from `<spriteType name="hueTransform">` elements each holding a
+    /// `<map from="..." to="r,g,b"/>`. `from` names the channel that is recoloured, either
+    /// as `red`, `green` or `blue`, or as three values with `*` in the channel's place.
+    fn parse_hue_transform_nodes(
+        ctx: &ParseContext,
+        node: Node,
+    ) -> Result<Vec<ColorMapping>, PluginError> {
         let hue_transform_nodes = node.children().filter(|x| {
             x.is_element()
                 && x.tag_name().name() == "spriteType"
-                && x.has_attribute("name")
-                && x.attribute("name").unwrap() == "hueTransform"
+                && x.attribute("name") == Some("hueTransform")
         });
 
         let mut color_mappings = Vec::new();
 
         for hue_transform_node in hue_transform_nodes {
-            let mapping = match hue_transform_node
-                .children()
-                .find(|x| x.tag_name().name() == "map")
-            {
-                Some(map) => {
-                    if map.has_attribute("from") && map.has_attribute("to") {
-                        let from = map.attribute("from").unwrap();
-                        let to = map.attribute("to").unwrap();
-
-                        let from_elements: Vec<_> = from.split(",").collect();
-                        let to_elements: Vec<_> = to.split(",").collect();
-
-                        match from_elements.len() {
-                            3 => match to_elements.len() {
-                                3 => {
-                                    let channel = match from_elements.iter().position(|x| *x == "*")
-                                    {
-                                        Some(idx) => match idx {
-                                            0 => ColorMappingChannel::Red,
-                                            1 => ColorMappingChannel::Green,
-                                            2 => ColorMappingChannel::Blue,
-                                            _ => continue,
-                                        },
-                                        None => continue,
-                                    };
-
-                                    let target = match parse_target_color_for_mapping(&to_elements)
-                                    {
-                                        Ok(t) => t,
-                                        Err(_) => continue,
-                                    };
-
-                                    ColorMapping { channel, target }
-                                }
-                                _ => continue,
-                            },
-                            1 => {
-                                let channel = match from {
-                                    "red" | "Red" | "r" | "R" => ColorMappingChannel::Red,
-                                    "blue" | "Blue" | "b" | "B" => ColorMappingChannel::Blue,
-                                    "green" | "Green" | "g" | "G" => ColorMappingChannel::Green,
-                                    _ => continue,
-                                };
-
-                                let target = match parse_target_color_for_mapping(&to_elements) {
-                                    Ok(t) => t,
-                                    Err(_) => continue,
-                                };
-
-                                match to_elements.len() {
-                                    3 => ColorMapping { channel, target },
-                                    _ => continue,
-                                }
-                            }
-                            _ => continue,
-                        }
-                    } else {
-                        println!(
-                            "A hue transform mapping doesn't have 'from' and 'to' properties."
-                        );
-                        continue;
-                    }
-                }
-                None => {
-                    println!("A hue transform node doesn't have a <map> element.");
-                    continue;
-                }
+            let map = ctx.child(hue_transform_node, "map")?;
+            let from = ctx.attribute(map, "from")?;
+            let to = ctx.attribute(map, "to")?;
+
+            let from_elements: Vec<_> = from.split(',').map(|x| x.trim()).collect();
+            let channel = match from_elements[..] {
+                ["red" | "Red" | "r" | "R"] => Some(ColorMappingChannel::Red),
+                ["green" | "Green" | "g" | "G"] => Some(ColorMappingChannel::Green),
+                ["blue" | "Blue" | "b" | "B"] => Some(ColorMappingChannel::Blue),
+                ["*", _, _] => Some(ColorMappingChannel::Red),
+                [_, "*", _] => Some(ColorMappingChannel::Green),
+                [_, _, "*"] => Some(ColorMappingChannel::Blue),
+                _ => None,
             };
-
-            color_mappings.push(mapping);
+            let channel = channel.ok_or_else(|| PluginError::BadNumber {
+                location: ctx.location(map),
+                field: "from".to_string(),
+                value: from.to_string(),
+            })?;
+
+            let target = parse_target_color_for_mapping(ctx, map, to)?;
+            color_mappings.push(ColorMapping { channel, target });
         }
 
-        color_mappings
+        Ok(color_mappings)
     }
 
+    /// Parse an `r,g,b` colour with components from 0 to 255.
     fn parse_target_color_for_mapping(
-        elements: &Vec<&str>,
-    ) -> Result<Color, std::num::ParseFloatError> {
-        let r = elements[0].parse::<f32>()? / 255.0;
-        let g = elements[1].parse::<f32>()? / 255.0;
-        let b = elements[2].parse::<f32>()? / 255.0;
+        ctx: &ParseContext,
+        node: Node,
+        value: &str,
+    ) -> Result<Color, PluginError> {
+        let elements: Vec<_> = value.split(',').collect();
+        if elements.len() != 3 {
+            return Err(PluginError::BadNumber {
+                location: ctx.location(node),
+                field: "to".to_string(),
+                value: value.to_string(),
+            });
+        }
 
-        Ok(Color { r, g, b, a: 1.0 })
+        let component = |x: &str| ctx.number::<f32>(node, "to", x).map(|x| x / 255.0);
+        Ok(Color {
+            r: component(elements[0])?,
+            g: component(elements[1])?,
+            b: component(elements[2])?,
+            a: 1.0,
+        })
     }
 
-    fn parse_origin_offset_and_opposite(node: Node) -> (i32, i32, i32, bool) {
-        let origin = node.attribute("origin").unwrap(); //todo
-        let origins: Vec<_> = origin.split(",").collect();
-        let origin_x: i32 = origins[0].trim().parse().unwrap_or(0);
-        let origin_y: i32 = origins[1].trim().parse().unwrap_or(0);
+    fn parse_origin_offset_and_opposite(
+        ctx: &ParseContext,
+        node: Node,
+    ) -> Result<ContributionSprite, PluginError> {
+        let origin = ctx.attribute(node, "origin")?;
+        let (origin_x, origin_y) = ctx.number_pair(node, "origin", origin)?;
 
-        let offset = node.attribute("offset").unwrap(); //todo
-        let offset = offset.parse().unwrap();
+        let offset = ctx.attribute(node, "offset")?;
+        let offset = ctx.number(node, "offset", offset)?;
 
         let opposite = node.attribute("opposite").unwrap_or("false");
-        let opposite = opposite.parse().unwrap();
+        let opposite = ctx.boolean(node, "opposite", opposite)?;
+
+        Ok(ContributionSprite {
+            origin_x,
+            origin_y,
+            offset,
+            opposite,
+        })
+    }
 
-        (origin_x, origin_y, offset, opposite)
+    /// Check a `<picture ref="...">` against the ref already seen on this contribution.
+    /// All sprites of a contribution must come from the same picture.
+    fn check_picture_ref<'n, 'i>(
+        ctx: &ParseContext,
+        picture_node: Node<'n, 'i>,
+        image_ref: &mut Option<(String, Node<'n, 'i>)>,
+    ) -> Result<(), PluginError> {
+        let x = ctx.attribute(picture_node, "ref")?;
+        match image_ref {
+            None => *image_ref = Some((x.to_string(), picture_node)),
+            Some((first, _)) => {
+                if x != first {
+                    return Err(PluginError::ConflictingImageRefs {
+                        location: ctx.location(picture_node),
+                        first: first.clone(),
+                        second: x.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(())
     }
 
-    fn parse_generic_structure_sprite(node: Node) -> (Vec<ContributionSprite>, String) {
+    type PictureRef<'n, 'i> = Option<(String, Node<'n, 'i>)>;
+
+    fn parse_generic_structure_sprite<'n, 'i>(
+        ctx: &ParseContext,
+        node: Node<'n, 'i>,
+    ) -> Result<(Vec<ContributionSprite>, PictureRef<'n, 'i>), PluginError> {
         let sprite_nodes = node
             .children()
-            .filter(|x| x.is_element() && x.tag_name().name() == "sprite");
+            .filter(|x| x.is_element() && x.has_tag_name("sprite"));
 
-        let mut image_ref: String = String::new();
+        let mut image_ref = None;
 
         let mut sprites = Vec::new();
         for sprite_node in sprite_nodes {
-            let (origin_x, origin_y, offset, opposite) =
-                parse_origin_offset_and_opposite(sprite_node);
-
-            let picture_node = sprite_node
-                .children()
-                .find(|x| x.is_element() && x.tag_name().name() == "picture")
-                .unwrap();
-
-            match picture_node.attribute("ref") {
-                Some(x) => {
-                    if image_ref.is_empty() {
-                        image_ref = x.to_string()
-                    } else {
-                        if x != image_ref {
-                            panic!("Sprites on a contribution have different image refs.");
-                        }
-                    }
-                }
-                None => (),
-            }
-
-            sprites.push(ContributionSprite {
-                origin_x,
-                origin_y,
-                offset,
-                opposite,
-            });
+            let sprite = parse_origin_offset_and_opposite(ctx, sprite_node)?;
+            let picture_node = ctx.child(sprite_node, "picture")?;
+            check_picture_ref(ctx, picture_node, &mut image_ref)?;
+            sprites.push(sprite);
         }
-        (sprites, image_ref)
+        Ok((sprites, image_ref))
     }
 
-    fn parse_generic_structure_multi(node: Node) -> (Vec<ContributionMultistorey>, String) {
+    fn parse_generic_structure_multi<'n, 'i>(
+        ctx: &ParseContext,
+        node: Node<'n, 'i>,
+    ) -> Result<(Vec<ContributionMultistorey>, PictureRef<'n, 'i>), PluginError> {
         let picture_nodes = node
             .children()
-            .filter(|x| x.is_element() && x.tag_name().name() == "pictures");
+            .filter(|x| x.is_element() && x.has_tag_name("pictures"));
 
-        let mut image_ref: String = String::new();
+        let mut image_ref = None;
 
         let mut sprites = Vec::new();
         for picture_node in picture_nodes {
-            let top_node = picture_node
-                .children()
-                .find(|x| x.has_tag_name("top"))
-                .unwrap();
-            let top = parse_origin_offset_and_opposite(top_node);
-
-            let middle_node = picture_node
-                .children()
-                .find(|x| x.has_tag_name("middle"))
-                .unwrap();
-            let middle = parse_origin_offset_and_opposite(middle_node);
-
-            let bottom_node = picture_node
-                .children()
-                .find(|x| x.has_tag_name("bottom"))
-                .unwrap();
-            let bottom = parse_origin_offset_and_opposite(bottom_node);
+            let mut storey = |name: &str| -> Result<ContributionSprite, PluginError> {
+                let storey_node = ctx.child(picture_node, name)?;
+                let sprite = parse_origin_offset_and_opposite(ctx, storey_node)?;
+                check_picture_ref(ctx, ctx.child(storey_node, "picture")?, &mut image_ref)?;
+                Ok(sprite)
+            };
 
-            let top_picture = top_node
-                .children()
-                .find(|x| x.has_tag_name("picture"))
-                .unwrap();
-
-            match top_picture.attribute("ref") {
-                Some(x) => {
-                    if image_ref.is_empty() {
-                        image_ref = x.to_string()
-                    } else {
-                        if x != image_ref {
-                            //TODO: check the middle and bottom refs
-                            panic!("Sprites on a contribution have different image refs.");
-                        }
-                    }
-                }
-                None => (),
-            }
+            let top = storey("top")?;
+            let middle = storey("middle")?;
+            let bottom = storey("bottom")?;
 
             sprites.push(ContributionMultistorey {
-                top: ContributionSprite {
-                    origin_x: top.0,
-                    origin_y: top.1,
-                    offset: top.2,
-                    opposite: top.3,
-                },
-                middle: ContributionSprite {
-                    origin_x: middle.0,
-                    origin_y: middle.1,
-                    offset: middle.2,
-                    opposite: middle.3,
-                },
-                bottom: ContributionSprite {
-                    origin_x: bottom.0,
-                    origin_y: bottom.1,
-                    offset: bottom.2,
-                    opposite: bottom.3,
-                },
+                top,
+                middle,
+                bottom,
             });
         }
 
-        (sprites, image_ref)
+        Ok((sprites, image_ref))
     }
 
-    fn resolve_contribution_refs(
-        picture_contributions: &HashMap<String, String>,
-        contributions: &mut Vec<Contribution>,
-    ) {
-        for contribution in contributions {
-            if picture_contributions.contains_key(&contribution.image_ref) {
-                contribution.image_ref = picture_contributions[&contribution.image_ref].clone();
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn parse(xml: &str) -> (Result<Plugin, PluginError>, PluginLoadReport) {
+            let mut report = PluginLoadReport::default();
+            let files = VirtualDir::Disk(PathBuf::from("test"));
+            let plugin = parse_plugin_xml(files, xml.as_bytes(), &mut report);
+            (plugin, report)
+        }
+
+        /// The line, column and contribution id an error was reported at.
+        fn located(err: &PluginError) -> (u32, u32, Option<&str>) {
+            let location = err.location().expect("the error has no location");
+            (
+                location.position.row,
+                location.position.col,
+                location.contribution_id.as_deref(),
+            )
+        }
+
+        const PLUGIN: &str = r#"<?xml version="1.0"?>
+<plug-in>
+  <title>Test</title>
+  <author>Me</author>
+  <contribution type="train" id="good">
+    <composition><body ref="car"/></composition>
+  </contribution>
+  <contribution type="train" id="no-ref">
+    <composition><body/></composition>
+  </contribution>
+  <contribution type="train" id="bad-price">
+    <price>lots</price>
+    <composition><body ref="car"/></composition>
+  </contribution>
+  <contribution type="monorail" id="odd"/>
+</plug-in>
+"#;
+
+        #[test]
+        fn bad_contributions_are_skipped_and_located() {
+            let (plugin, report) = parse(PLUGIN);
+            let plugin = plugin.unwrap();
+            assert_eq!(plugin.title, "Test");
+            let ids: Vec<_> = plugin.contributions.iter().map(|x| x.id.as_str()).collect();
+            assert_eq!(ids, vec!["good"]);
+            assert_eq!(report.contributions_parsed.get("train"), Some(&1));
+
+            let skipped = &report.skipped_contributions;
+            assert_eq!(skipped.len(), 3);
+
+            assert!(matches!(
+                &skipped[0],
+                PluginError::MissingAttribute { element, attribute, .. }
+                    if element == "body" && attribute == "ref"
+            ));
+            assert_eq!(located(&skipped[0]), (9, 18, Some("no-ref")));
+
+            assert!(matches!(
+                &skipped[1],
+                PluginError::BadNumber { field, value, .. } if field == "price" && value == "lots"
+            ));
+            assert_eq!(located(&skipped[1]), (11, 3, Some("bad-price")));
+
+            assert!(matches!(
+                &skipped[2],
+                PluginError::UnknownContributionType { contribution_type, .. }
+                    if contribution_type == "monorail"
+            ));
+            assert_eq!(located(&skipped[2]), (15, 3, Some("odd")));
+            assert_eq!(
+                skipped[2].to_string(),
+                "test:15:3: contribution 'odd': unknown contribution type 'monorail'"
+            );
+        }
+
+        #[test]
+        fn zips_shadow_folders_of_the_same_name() {
+            let base = env::temp_dir().join(format!("openft-enumerate-{}", std::process::id()));
+            let (first, second) = (base.join("first"), base.join("second"));
+            fs::create_dir_all(first.join("foo")).unwrap();
+            fs::create_dir_all(second.join("bar")).unwrap();
+            fs::write(second.join("foo.zip"), []).unwrap();
+            fs::write(first.join("bar.ZIP"), []).unwrap();
+
+            let enumeration = enumerate_plugins(&[first.clone(), second.clone()]);
+            fs::remove_dir_all(&base).unwrap();
+
+            assert_eq!(
+                enumeration.plugins,
+                vec![first.join("bar.ZIP"), first.join("foo")]
+            );
+            assert_eq!(
+                enumeration.shadowed,
+                vec![second.join("bar"), second.join("foo.zip")]
+            );
+        }
+
+        const XML: &str = "<?xml version=\"1.0\"?><plug-in><title>駅</title></plug-in>";
+
+        fn utf16(text: &str, little_endian: bool) -> Vec<u8> {
+            text.encode_utf16()
+                .flat_map(|x| match little_endian {
+                    true => x.to_le_bytes(),
+                    false => x.to_be_bytes(),
+                })
+                .collect()
+        }
+
+        #[test]
+        fn decode_honours_a_byte_order_mark() {
+            for (bom, little_endian, expected) in [
+                ([0xFF, 0xFE], true, UTF_16LE),
+                ([0xFE, 0xFF], false, UTF_16BE),
+            ] {
+                let bytes = [&bom[..], &utf16(XML, little_endian)].concat();
+                let (text, encoding) = decode_plugin_xml(&bytes);
+                assert_eq!(encoding, expected);
+                assert_eq!(text, XML);
+            }
+        }
+
+        #[test]
+        fn decode_recognises_utf16_without_a_bom() {
+            for (little_endian, expected) in [(true, UTF_16LE), (false, UTF_16BE)] {
+                let bytes = utf16(XML, little_endian);
+                let (text, encoding) = decode_plugin_xml(&bytes);
+                assert_eq!(encoding, expected);
+                assert_eq!(text, XML);
+            }
+        }
+
+        #[test]
+        fn decode_uses_the_declared_encoding() {
+            for (declaration, expected) in [
+                ("<?xml version=\"1.0\" encoding=\"EUC-JP\"?>", EUC_JP),
+                ("<?xml version='1.0' encoding='ISO-2022-JP'?>", ISO_2022_JP),
+                ("<?xml version=\"1.0\" encoding = 'Shift_JIS' ?>", SHIFT_JIS),
+            ] {
+                let xml = format!("{}<title>駅</title>", declaration);
+                let (bytes, _, _) = expected.encode(&xml);
+                let (text, encoding) = decode_plugin_xml(&bytes);
+                assert_eq!(encoding, expected);
+                assert_eq!(text, xml);
             }
         }
+
+        #[test]
+        fn decode_ignores_a_utf16_declaration_in_ascii() {
+            let xml = "<?xml version=\"1.0\" encoding=\"UTF-16\"?><title>駅</title>";
+            let (text, encoding) = decode_plugin_xml(xml.as_bytes());
+            assert_eq!(encoding, UTF_8);
+            assert_eq!(text, xml);
+        }
+
+        #[test]
+        fn decode_falls_back_to_shift_jis() {
+            let xml = "<plug-in><title>駅</title></plug-in>";
+            let (text, encoding) = decode_plugin_xml(xml.as_bytes());
+            assert_eq!((text.as_ref(), encoding), (xml, UTF_8));
+
+            let (bytes, _, _) = SHIFT_JIS.encode(xml);
+            let (text, encoding) = decode_plugin_xml(&bytes);
+            assert_eq!((text.as_ref(), encoding), (xml, SHIFT_JIS));
+
+            // An XML declaration without an encoding says nothing either.
+            let xml = format!("<?xml version=\"1.0\"?>{}", xml);
+            let (bytes, _, _) = SHIFT_JIS.encode(&xml);
+            assert_eq!(decode_plugin_xml(&bytes).1, SHIFT_JIS);
+        }
+
+        #[test]
+        fn missing_title_fails_the_plugin() {
+            let xml = "<plug-in>\n  <author>Me</author>\n</plug-in>";
+            let (plugin, report) = parse(xml);
+            let err = plugin.unwrap_err();
+            assert!(matches!(
+                &err,
+                PluginError::MissingMetadata { field, .. } if field == "title"
+            ));
+            assert_eq!(located(&err), (1, 1, None));
+            assert!(report.skipped_contributions.is_empty());
+        }
     }
 }
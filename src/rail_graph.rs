@@ -0,0 +1,727 @@
+pub mod rail_graph {
+    use crate::track::track::{Direction, TrackLayout};
+    use std::{
+        cmp::Ordering,
+        collections::{BinaryHeap, HashMap, HashSet},
+    };
+
+    /// Longest run of plain track followed when tracing an edge. Stops a broken layout from
+    /// looping forever.
+    const MAX_EDGE_TILES: usize = 100_000;
+
+    /// A run of track between two nodes. `a` and `b` are node tiles, and `a_side`/`b_side`
+    /// the sides of those tiles the track leaves through. `tiles` are the plain track tiles
+    /// in between, from `a` to `b`.
+    #[derive(Debug, Clone)]
+    pub struct RailEdge {
+        pub a: (i32, i32),
+        pub a_side: Direction,
+        pub b: (i32, i32),
+        pub b_side: Direction,
+        pub tiles: Vec<(i32, i32)>,
+        /// Distance from the centre of `a` to the centre of each tile in `tiles`.
+        pub offsets: Vec<f32>,
+        /// Distance between the centres of `a` and `b`, in tiles.
+        pub length: f32,
+        /// Height levels gained going from `a` to `b`.
+        pub rise: i32,
+    }
+
+    impl RailEdge {
+        /// Average climb per tile going from `a` to `b`.
+        pub fn gradient(&self) -> f32 {
+            self.rise as f32 / self.length
+        }
+    }
+
+    /// Where a tile sits in the graph.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum RailLocation {
+        Node((i32, i32)),
+        /// The tile is `index` into the edge's `tiles`.
+        Edge(usize, usize),
+    }
+
+    /// A path through the rail network, tile by tile, including both ends.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Route {
+        pub tiles: Vec<(i32, i32)>,
+        pub length: f32,
+    }
+
+    /// The connectivity of the track on a map. Nodes are tiles where track branches,
+    /// crosses, ends, or runs through a station; edges are the runs of plain track between
+    /// them. A loop of track with none of those has no nodes and isn't part of the graph.
+    #[derive(Debug, Default, Clone)]
+    pub struct RailGraph {
+        nodes: HashSet<(i32, i32)>,
+        edges: HashMap<usize, RailEdge>,
+        next_edge: usize,
+        /// The edge leaving each node through each side.
+        ends: HashMap<((i32, i32), Direction), usize>,
+        /// The edges each tile is part of, as an end or in between.
+        tile_edges: HashMap<(i32, i32), Vec<usize>>,
+    }
+
+    impl RailGraph {
+        pub fn new() -> RailGraph {
+            RailGraph::default()
+        }
+
+        /// Build the graph for every tile in `tiles` from scratch.
+        pub fn build(
+            layout: &impl TrackLayout,
+            tiles: impl Iterator<Item = (i32, i32)>,
+        ) -> RailGraph {
+            let mut graph = RailGraph::new();
+            for tile in tiles {
+                if is_node(layout, tile) {
+                    graph.nodes.insert(tile);
+                }
+            }
+            let nodes: Vec<_> = graph.nodes.iter().copied().collect();
+            for node in nodes {
+                graph.trace_node(layout, node);
+            }
+            graph
+        }
+
+        pub fn nodes(&self) -> impl Iterator<Item = &(i32, i32)> {
+            self.nodes.iter()
+        }
+
+        pub fn edges(&self) -> impl Iterator<Item = &RailEdge> {
+            self.edges.values()
+        }
+
+        pub fn edge(&self, edge: usize) -> Option<&RailEdge> {
+            self.edges.get(&edge)
+        }
+
+        pub fn is_node(&self, tile: (i32, i32)) -> bool {
+            self.nodes.contains(&tile)
+        }
+
+        pub fn locate(&self, tile: (i32, i32)) -> Option<RailLocation> {
+            if self.nodes.contains(&tile) {
+                return Some(RailLocation::Node(tile));
+            }
+            let edge = *self.tile_edges.get(&tile)?.first()?;
+            let index = self.edges[&edge].tiles.iter().position(|x| *x == tile)?;
+            Some(RailLocation::Edge(edge, index))
+        }
+
+        /// Bring the graph up to date after the track or station on a tile changed. Only
+        /// the tile and its neighbours can change whether they are nodes, so only edges
+        /// touching them are traced again.
+        pub fn update(&mut self, layout: &impl TrackLayout, x: i32, y: i32) {
+            let affected: Vec<(i32, i32)> = [(0, 0)]
+                .into_iter()
+                .chain(Direction::ALL.map(|d| d.offset()))
+                .map(|(dx, dy)| (x + dx, y + dy))
+                .collect();
+
+            let mut stale = HashSet::new();
+            for tile in &affected {
+                if let Some(edges) = self.tile_edges.get(tile) {
+                    stale.extend(edges.iter().copied());
+                }
+            }
+
+            // Edges reaching outside the affected tiles are traced again from their far end.
+            let mut seeds = Vec::new();
+            for edge in stale {
+                let edge = self.remove_edge(edge);
+                seeds.push(edge.a);
+                seeds.push(edge.b);
+            }
+
+            for tile in &affected {
+                match is_node(layout, *tile) {
+                    true => self.nodes.insert(*tile),
+                    false => self.nodes.remove(tile),
+                };
+            }
+
+            seeds.extend(affected);
+            for node in seeds {
+                if self.nodes.contains(&node) {
+                    self.trace_node(layout, node);
+                }
+            }
+        }
+
+        fn remove_edge(&mut self, id: usize) -> RailEdge {
+            let edge = self.edges.remove(&id).unwrap();
+            self.ends.remove(&(edge.a, edge.a_side));
+            self.ends.remove(&(edge.b, edge.b_side));
+            for tile in edge.tiles.iter().chain([&edge.a, &edge.b]) {
+                if let Some(edges) = self.tile_edges.get_mut(tile) {
+                    edges.retain(|x| *x != id);
+                    if edges.is_empty() {
+                        self.tile_edges.remove(tile);
+                    }
+                }
+            }
+            edge
+        }
+
+        /// Trace every edge leaving a node that isn't already in the graph.
+        fn trace_node(&mut self, layout: &impl TrackLayout, node: (i32, i32)) {
+            let tracks = layout.tracks(node.0, node.1);
+            for side in Direction::ALL {
+                if !tracks.connects(side) || self.ends.contains_key(&(node, side)) {
+                    continue;
+                }
+                if let Some(edge) = trace_edge(layout, node, side) {
+                    self.insert_edge(edge);
+                }
+            }
+        }
+
+        fn insert_edge(&mut self, edge: RailEdge) {
+            let id = self.next_edge;
+            self.next_edge += 1;
+
+            self.ends.insert((edge.a, edge.a_side), id);
+            self.ends.insert((edge.b, edge.b_side), id);
+            let mut tiles: Vec<_> = edge.tiles.iter().chain([&edge.a, &edge.b]).collect();
+            tiles.dedup();
+            for tile in tiles {
+                let edges = self.tile_edges.entry(*tile).or_default();
+                if !edges.contains(&id) {
+                    edges.push(id);
+                }
+            }
+            self.edges.insert(id, edge);
+        }
+
+        /// Find the shortest route between two tiles with track, using A*. Trains can't
+        /// reverse at junctions, so a route only passes through a node along one of its
+        /// track segments. The route may leave `from` in either direction.
+        pub fn route(
+            &self,
+            layout: &impl TrackLayout,
+            from: (i32, i32),
+            to: (i32, i32),
+        ) -> Option<Route> {
+            let start = self.locate(from)?;
+            let goal = self.locate(to)?;
+            if from == to {
+                return Some(Route {
+                    tiles: vec![from],
+                    length: 0.0,
+                });
+            }
+
+            let mut search = Search {
+                open: BinaryHeap::new(),
+                best: HashMap::new(),
+                came_from: HashMap::new(),
+                to,
+            };
+
+            match start {
+                RailLocation::Node(node) => search.push(State::Node(node, None), 0.0, None),
+                RailLocation::Edge(id, index) => {
+                    let edge = &self.edges[&id];
+                    let offset = edge.offsets[index];
+                    let backward = Link {
+                        edge: id,
+                        forward: false,
+                    };
+                    let forward = Link {
+                        edge: id,
+                        forward: true,
+                    };
+                    search.push(
+                        State::Node(edge.a, Some(edge.a_side)),
+                        offset,
+                        Some((State::Start, backward)),
+                    );
+                    search.push(
+                        State::Node(edge.b, Some(edge.b_side)),
+                        edge.length - offset,
+                        Some((State::Start, forward)),
+                    );
+
+                    // Both ends on the same stretch of track.
+                    if let RailLocation::Edge(goal_id, goal_index) = goal {
+                        if goal_id == id {
+                            let link = Link {
+                                edge: id,
+                                forward: goal_index > index,
+                            };
+                            let cost = (edge.offsets[goal_index] - offset).abs();
+                            search.push(State::Goal, cost, Some((State::Start, link)));
+                        }
+                    }
+                }
+            }
+
+            let mut finished = None;
+            while let Some(Open { cost, state, .. }) = search.open.pop() {
+                if search.best.get(&state).map(|x| cost > *x).unwrap_or(false) {
+                    continue;
+                }
+
+                let (node, arrived) = match state {
+                    State::Goal => {
+                        finished = Some(cost);
+                        break;
+                    }
+                    State::Node(node, arrived) => (node, arrived),
+                    State::Start => continue,
+                };
+                if goal == RailLocation::Node(node) {
+                    search.push(State::Goal, cost, Some((state, Link::NONE)));
+                    continue;
+                }
+
+                let tracks = layout.tracks(node.0, node.1);
+                for side in Direction::ALL {
+                    let allowed = match arrived {
+                        None => tracks.connects(side),
+                        Some(arrived) => tracks.iter().any(|s| s.exit(arrived) == Some(side)),
+                    };
+                    let id = match self.ends.get(&(node, side)) {
+                        Some(id) if allowed => *id,
+                        _ => continue,
+                    };
+
+                    let edge = &self.edges[&id];
+                    let forward = edge.a == node && edge.a_side == side;
+                    let link = Link { edge: id, forward };
+
+                    if let RailLocation::Edge(goal_id, goal_index) = goal {
+                        if goal_id == id {
+                            let offset = edge.offsets[goal_index];
+                            let partial = if forward {
+                                offset
+                            } else {
+                                edge.length - offset
+                            };
+                            search.push(State::Goal, cost + partial, Some((state, link)));
+                            continue;
+                        }
+                    }
+
+                    let next = match forward {
+                        true => State::Node(edge.b, Some(edge.b_side)),
+                        false => State::Node(edge.a, Some(edge.a_side)),
+                    };
+                    search.push(next, cost + edge.length, Some((state, link)));
+                }
+            }
+
+            let length = finished?;
+            Some(Route {
+                tiles: self.route_tiles(&search.came_from, start, goal, from),
+                length,
+            })
+        }
+
+        /// Follow `came_from` back from the goal and list the tiles passed on the way.
+        fn route_tiles(
+            &self,
+            came_from: &HashMap<State, (State, Link)>,
+            start: RailLocation,
+            goal: RailLocation,
+            from: (i32, i32),
+        ) -> Vec<(i32, i32)> {
+            let mut links = Vec::new();
+            let mut state = State::Goal;
+            while let Some((previous, link)) = came_from.get(&state) {
+                links.push(*link);
+                state = *previous;
+            }
+            links.reverse();
+
+            let mut tiles = vec![from];
+            let count = links.len();
+            for (i, link) in links.into_iter().enumerate() {
+                if link.edge == usize::MAX {
+                    continue;
+                }
+                let edge = &self.edges[&link.edge];
+
+                // The first and last links may start or end part way along their edge.
+                let mut range = 0..edge.tiles.len();
+                if i == 0 {
+                    if let RailLocation::Edge(_, index) = start {
+                        range = match link.forward {
+                            true => index + 1..range.end,
+                            false => 0..index,
+                        };
+                    }
+                }
+                let last = i == count - 1;
+                let goal_index = match goal {
+                    RailLocation::Edge(id, index) if last && id == link.edge => Some(index),
+                    _ => None,
+                };
+                if let Some(index) = goal_index {
+                    range = match link.forward {
+                        true => range.start..index + 1,
+                        false => index..range.end.min(edge.tiles.len()),
+                    };
+                }
+
+                let part = &edge.tiles[range];
+                match link.forward {
+                    true => tiles.extend(part.iter()),
+                    false => tiles.extend(part.iter().rev()),
+                }
+                if goal_index.is_none() {
+                    tiles.push(if link.forward { edge.b } else { edge.a });
+                }
+            }
+            tiles
+        }
+    }
+
+    /// A search state: being at a node having come in through a side (or not having moved
+    /// yet), or the start and goal tiles themselves.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum State {
+        Start,
+        Node((i32, i32), Option<Direction>),
+        Goal,
+    }
+
+    /// The A* open set and what is known about each state reached so far.
+    struct Search {
+        open: BinaryHeap<Open>,
+        best: HashMap<State, f32>,
+        came_from: HashMap<State, (State, Link)>,
+        to: (i32, i32),
+    }
+
+    impl Search {
+        /// Record a way of reaching `state`, if it is cheaper than any found before.
+        fn push(&mut self, state: State, cost: f32, previous: Option<(State, Link)>) {
+            if self.best.get(&state).map(|x| cost >= *x).unwrap_or(false) {
+                return;
+            }
+            self.best.insert(state, cost);
+            if let Some(previous) = previous {
+                self.came_from.insert(state, previous);
+            }
+
+            let heuristic = match state {
+                State::Node(tile, _) => octile_distance(tile, self.to),
+                _ => 0.0,
+            };
+            self.open.push(Open {
+                estimate: cost + heuristic,
+                cost,
+                state,
+            });
+        }
+    }
+
+    /// The edge taken between two states, and in which direction.
+    #[derive(Debug, Clone, Copy)]
+    struct Link {
+        edge: usize,
+        forward: bool,
+    }
+
+    impl Link {
+        /// Arriving at a goal node doesn't take an edge.
+        const NONE: Link = Link {
+            edge: usize::MAX,
+            forward: true,
+        };
+    }
+
+    /// An entry in the A* open set, ordered so the heap pops the lowest estimate first.
+    #[derive(Debug)]
+    struct Open {
+        estimate: f32,
+        cost: f32,
+        state: State,
+    }
+
+    impl PartialEq for Open {
+        fn eq(&self, other: &Self) -> bool {
+            self.estimate == other.estimate
+        }
+    }
+
+    impl Eq for Open {}
+
+    impl PartialOrd for Open {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Open {
+        fn cmp(&self, other: &Self) -> Ordering {
+            other.estimate.total_cmp(&self.estimate)
+        }
+    }
+
+    /// Track from a tile's centre to one of its sides: half a tile, or half a diagonal.
+    fn half_length(side: Direction) -> f32 {
+        match side.is_diagonal() {
+            true => std::f32::consts::SQRT_2 / 2.0,
+            false => 0.5,
+        }
+    }
+
+    /// The shortest possible track distance between two tile centres, moving straight or
+    /// diagonally.
+    fn octile_distance(a: (i32, i32), b: (i32, i32)) -> f32 {
+        let dx = (a.0 - b.0).abs();
+        let dy = (a.1 - b.1).abs();
+        let (long, short) = (dx.max(dy), dx.min(dy));
+        (long - short) as f32 + short as f32 * std::f32::consts::SQRT_2
+    }
+
+    /// A tile is a node if its track branches or crosses, runs through a station, or ends
+    /// without carrying on into the next tile.
+    pub fn is_node(layout: &impl TrackLayout, tile: (i32, i32)) -> bool {
+        let tracks = layout.tracks(tile.0, tile.1);
+        if tracks.is_empty() {
+            return false;
+        }
+        if tracks.len() != 1 || layout.station_at(tile.0, tile.1).is_some() {
+            return true;
+        }
+
+        let segment = tracks.iter().next().unwrap();
+        [segment.a, segment.b].into_iter().any(|side| {
+            let (dx, dy) = side.offset();
+            !layout
+                .tracks(tile.0 + dx, tile.1 + dy)
+                .connects(side.opposite())
+        })
+    }
+
+    /// Follow the track out of a node through `side` until it reaches another node.
+    fn trace_edge(
+        layout: &impl TrackLayout,
+        node: (i32, i32),
+        side: Direction,
+    ) -> Option<RailEdge> {
+        let mut tiles = Vec::new();
+        let mut offsets = Vec::new();
+        let mut length = half_length(side);
+        let mut rise = 0;
+
+        let mut exit = side;
+        let mut tile = node;
+        while tiles.len() < MAX_EDGE_TILES {
+            let (dx, dy) = exit.offset();
+            tile = (tile.0 + dx, tile.1 + dy);
+            let entry = exit.opposite();
+
+            let tracks = layout.tracks(tile.0, tile.1);
+            if !tracks.connects(entry) {
+                return None;
+            }
+            length += half_length(entry);
+
+            if is_node(layout, tile) {
+                return Some(RailEdge {
+                    a: node,
+                    a_side: side,
+                    b: tile,
+                    b_side: entry,
+                    tiles,
+                    offsets,
+                    length,
+                    rise,
+                });
+            }
+
+            let segment = tracks.iter().next().unwrap();
+            exit = segment.exit(entry).unwrap();
+            rise += segment.rise_from(entry) as i32;
+            tiles.push(tile);
+            offsets.push(length);
+            length += half_length(exit);
+        }
+        None
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::track::track::{TrackSegment, TrackSet};
+        use Direction::*;
+
+        #[derive(Default)]
+        struct Layout {
+            tracks: HashMap<(i32, i32), TrackSet>,
+        }
+
+        impl Layout {
+            /// Lay flat track from `start`, making each move in turn. The first tile is
+            /// entered straight on.
+            fn lay(&mut self, start: (i32, i32), moves: &[Direction]) {
+                let mut tile = start;
+                let mut entry = moves[0].opposite();
+                for exit in moves {
+                    self.add(tile, entry, *exit, 0);
+                    let (dx, dy) = exit.offset();
+                    tile = (tile.0 + dx, tile.1 + dy);
+                    entry = exit.opposite();
+                }
+            }
+
+            fn add(&mut self, tile: (i32, i32), a: Direction, b: Direction, rise: i8) {
+                let segment = TrackSegment::new(a, b, rise).unwrap();
+                self.tracks.entry(tile).or_default().insert(segment);
+            }
+
+            fn graph(&self) -> RailGraph {
+                RailGraph::build(self, self.tracks.keys().copied())
+            }
+        }
+
+        impl TrackLayout for Layout {
+            fn tracks(&self, x: i32, y: i32) -> TrackSet {
+                self.tracks.get(&(x, y)).copied().unwrap_or_default()
+            }
+        }
+
+        /// A line east from (0, 0) to (10, 0), with a branch leaving (5, 0) to the
+        /// north-east and ending at (8, -3).
+        fn junction() -> Layout {
+            let mut layout = Layout::default();
+            layout.lay((0, 0), &[East; 11]);
+            layout.add((5, 0), West, NorthEast, 0);
+            layout.lay((6, -1), &[NorthEast; 3]);
+            layout
+        }
+
+        fn sorted_nodes(graph: &RailGraph) -> Vec<(i32, i32)> {
+            let mut nodes: Vec<_> = graph.nodes().copied().collect();
+            nodes.sort();
+            nodes
+        }
+
+        #[test]
+        fn straight_line_is_one_edge() {
+            let mut layout = Layout::default();
+            layout.lay((0, 0), &[East; 5]);
+            let graph = layout.graph();
+
+            assert_eq!(sorted_nodes(&graph), vec![(0, 0), (4, 0)]);
+            let edges: Vec<_> = graph.edges().collect();
+            assert_eq!(edges.len(), 1);
+            assert_eq!(edges[0].tiles.len(), 3);
+            assert_eq!(edges[0].length, 4.0);
+            assert_eq!(edges[0].rise, 0);
+        }
+
+        #[test]
+        fn diagonal_track_is_longer() {
+            let mut layout = Layout::default();
+            layout.lay((0, 0), &[SouthEast; 4]);
+            let graph = layout.graph();
+
+            assert_eq!(sorted_nodes(&graph), vec![(0, 0), (3, 3)]);
+            let edge = graph.edges().next().unwrap();
+            assert!((edge.length - 3.0 * std::f32::consts::SQRT_2).abs() < 1e-5);
+        }
+
+        #[test]
+        fn slopes_count_towards_the_edge_rise() {
+            let mut layout = Layout::default();
+            layout.lay((0, 0), &[East; 5]);
+            layout.tracks.insert((2, 0), TrackSet::default());
+            layout.add((2, 0), West, East, 1);
+            let graph = layout.graph();
+
+            let edge = graph.edges().next().unwrap();
+            let rise = match edge.a {
+                (0, 0) => 1,
+                _ => -1,
+            };
+            assert_eq!(edge.rise, rise);
+            assert_eq!(edge.gradient(), rise as f32 / 4.0);
+        }
+
+        #[test]
+        fn route_along_one_edge() {
+            let mut layout = Layout::default();
+            layout.lay((0, 0), &[East; 10]);
+            let graph = layout.graph();
+
+            let route = graph.route(&layout, (6, 0), (2, 0)).unwrap();
+            assert_eq!(route.tiles, vec![(6, 0), (5, 0), (4, 0), (3, 0), (2, 0)]);
+            assert_eq!(route.length, 4.0);
+
+            let route = graph.route(&layout, (3, 0), (3, 0)).unwrap();
+            assert_eq!(route.tiles, vec![(3, 0)]);
+        }
+
+        #[test]
+        fn route_through_a_junction() {
+            let layout = junction();
+            let graph = layout.graph();
+
+            let route = graph.route(&layout, (7, -2), (2, 0)).unwrap();
+            assert_eq!(route.tiles.first(), Some(&(7, -2)));
+            assert_eq!(route.tiles.last(), Some(&(2, 0)));
+            assert!(route.tiles.contains(&(5, 0)));
+            assert!(!route.tiles.contains(&(6, 0)));
+            let expected = 2.0 * std::f32::consts::SQRT_2 + 3.0;
+            assert!((route.length - expected).abs() < 1e-5);
+        }
+
+        #[test]
+        fn route_never_reverses_at_a_junction() {
+            let layout = junction();
+            let graph = layout.graph();
+
+            // The branch only joins the line heading west.
+            assert!(graph.route(&layout, (7, -2), (8, 0)).is_none());
+            assert!(graph.route(&layout, (9, 0), (7, -2)).is_none());
+            assert!(graph.route(&layout, (1, 0), (7, -2)).is_some());
+        }
+
+        #[test]
+        fn route_between_disconnected_track() {
+            let mut layout = Layout::default();
+            layout.lay((0, 0), &[East; 4]);
+            layout.lay((0, 5), &[East; 4]);
+            let graph = layout.graph();
+
+            assert!(graph.route(&layout, (1, 0), (1, 5)).is_none());
+            assert!(graph.route(&layout, (1, 0), (20, 20)).is_none());
+        }
+
+        #[test]
+        fn update_matches_a_fresh_build() {
+            let mut layout = Layout::default();
+            layout.lay((0, 0), &[East; 11]);
+            let mut graph = layout.graph();
+
+            // Add the branch a tile at a time, as a player would.
+            layout.add((5, 0), West, NorthEast, 0);
+            graph.update(&layout, 5, 0);
+            for (i, tile) in [(6, -1), (7, -2), (8, -3)].into_iter().enumerate() {
+                layout.lay(tile, &[NorthEast]);
+                graph.update(&layout, tile.0, tile.1);
+                assert_eq!(sorted_nodes(&graph), sorted_nodes(&layout.graph()), "{}", i);
+            }
+            assert_eq!(graph.edges().count(), 3);
+            let updated = graph.route(&layout, (7, -2), (2, 0)).unwrap();
+            let built = layout.graph().route(&layout, (7, -2), (2, 0)).unwrap();
+            assert_eq!(updated.tiles, built.tiles);
+            assert!((updated.length - built.length).abs() < 1e-5);
+
+            // Cutting the line splits its edge in two.
+            layout.tracks.remove(&(2, 0));
+            graph.update(&layout, 2, 0);
+            assert_eq!(sorted_nodes(&graph), sorted_nodes(&layout.graph()));
+            assert_eq!(graph.edges().count(), 4);
+            assert!(graph.route(&layout, (0, 0), (4, 0)).is_none());
+        }
+    }
+}
@@ -0,0 +1,110 @@
+pub mod render_order {
+    use std::cmp::Ordering;
+
+    /// What kind of thing is being drawn. Where two things are at the same depth and
+    /// height, lower layers are drawn first.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Layer {
+        Ground,
+        /// Roads and track, which lie flat on the ground.
+        Surface,
+        Entity,
+        /// Trains, and things like signals that stand on the track.
+        Vehicle,
+    }
+
+    /// Where something comes in the back-to-front drawing order: by isometric depth
+    /// (x + y in tiles), then height, then layer.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct DepthKey {
+        pub depth: f32,
+        pub z: f32,
+        pub layer: Layer,
+    }
+
+    impl DepthKey {
+        /// A flat tile such as ground or track. It goes in at its back corner, so that
+        /// anything standing on the tile behind is drawn over it.
+        pub fn tile(x: i32, y: i32, z: i32, layer: Layer) -> DepthKey {
+            DepthKey {
+                depth: (x + y) as f32,
+                z: z as f32,
+                layer,
+            }
+        }
+
+        /// An entity covering tiles `x0..=x1` and `y1..=y0`. It goes in at the middle of
+        /// its front tile, so that the whole sprite is drawn after the ground under it.
+        pub fn entity(x1: i32, y0: i32, z: i32) -> DepthKey {
+            DepthKey {
+                depth: (x1 + y0) as f32 + 1.0,
+                z: z as f32,
+                layer: Layer::Entity,
+            }
+        }
+
+        /// Something standing at a fractional map position, such as a train car.
+        pub fn point(x: f32, y: f32, z: f32, layer: Layer) -> DepthKey {
+            DepthKey {
+                depth: x + y,
+                z,
+                layer,
+            }
+        }
+    }
+
+    impl Eq for DepthKey {}
+
+    impl PartialOrd for DepthKey {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for DepthKey {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.depth
+                .total_cmp(&other.depth)
+                .then(self.z.total_cmp(&other.z))
+                .then(self.layer.cmp(&other.layer))
+        }
+    }
+
+    /// Things to draw this frame, collected in any order and handed back from the back
+    /// of the scene to the front.
+    #[derive(Debug)]
+    pub struct RenderQueue<T> {
+        items: Vec<(DepthKey, T)>,
+    }
+
+    impl<T> Default for RenderQueue<T> {
+        fn default() -> Self {
+            RenderQueue { items: Vec::new() }
+        }
+    }
+
+    impl<T> RenderQueue<T> {
+        pub fn new() -> RenderQueue<T> {
+            RenderQueue::default()
+        }
+
+        pub fn push(&mut self, key: DepthKey, item: T) {
+            self.items.push((key, item));
+        }
+
+        pub fn len(&self) -> usize {
+            self.items.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.items.is_empty()
+        }
+
+        /// Empty the queue in drawing order. Things with equal keys keep the order they
+        /// were pushed in.
+        pub fn drain_sorted(&mut self) -> impl Iterator<Item = T> + '_ {
+            self.items.sort_by_key(|x| x.0);
+            self.items.drain(..).map(|(_, item)| item)
+        }
+    }
+}
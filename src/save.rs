@@ -0,0 +1,900 @@
+pub mod save {
+    use crate::{
+        clock::clock::{GameClock, GameSpeed},
+        contribution_registry::contribution_registry::{
+            ContributionHandle, ContributionKey, ContributionRegistry,
+        },
+        tilemap_manager::tilemap_manager::{terrain_fits, EntityInfo, TileMap},
+        track::track::{Direction, TrackSet, TRACK_SEGMENTS},
+        train_manager::train_manager::{
+            TrackTile, TrainCatalogue, TrainSimulation, TrainSnapshot, TrainState,
+        },
+    };
+    use serde_json::{json, Value};
+    use std::{collections::HashMap, fmt};
+
+    /// Written at the top of every save so other JSON files aren't mistaken for one.
+    pub const SAVE_FORMAT: &str = "openft-save";
+
+    /// The version saves are written in. Older saves are brought up to it by `MIGRATIONS`.
+    pub const SAVE_VERSION: u64 = 2;
+
+    /// Rewrites a save in place into the next version's format.
+    type Migration = fn(&mut Value) -> Result<(), LoadError>;
+
+    /// `MIGRATIONS[i]` turns a version `i + 1` save into a version `i + 2` one.
+    const MIGRATIONS: &[Migration] = &[add_flat_terrain];
+
+    /// Version 2 added terrain heights. Older maps were flat.
+    fn add_flat_terrain(save: &mut Value) -> Result<(), LoadError> {
+        let size = array(&save["size"], "size")?;
+        let (size_x, size_y) = match &size[..] {
+            [x, y] => (uint(x, "size")? as usize, uint(y, "size")? as usize),
+            _ => return Err(LoadError::BadData("size".to_string())),
+        };
+        // Check the size against the ground before trusting it with an allocation. A map
+        // with no tiles but a long side would still need a huge row of heights.
+        let tiles = array(&save["ground"], "ground")?.len();
+        if size_x.checked_mul(size_y) != Some(tiles) || (tiles == 0 && size_x + size_y > 0) {
+            return Err(LoadError::BadData(format!(
+                "ground has {} tiles for a {}x{} map",
+                tiles, size_x, size_y
+            )));
+        }
+        let vertices = (size_x + 1)
+            .checked_mul(size_y + 1)
+            .ok_or_else(|| LoadError::BadData("size".to_string()))?;
+        save["heights"] = json!(vec![0; vertices]);
+        Ok(())
+    }
+
+    /// A building read from a save, before it is put back on the map. The station fields
+    /// are only used if it turns out to be a station.
+    struct SavedEntity {
+        x: usize,
+        y: usize,
+        entity_type: usize,
+        entity_info: Option<EntityInfo>,
+        station_name: Option<String>,
+        passengers: u32,
+        freight: u32,
+    }
+
+    /// The game state that lives outside the map.
+    #[derive(Debug)]
+    pub struct LoadedGame {
+        pub clock: GameClock,
+        pub simulation: TrainSimulation,
+        /// Things in the save that couldn't be put back, such as an entity whose tiles are
+        /// now taken.
+        pub warnings: Vec<String>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum SaveError {
+        /// Something on the map didn't come from a registered contribution, so there's no
+        /// way to refer to it that would survive a reload.
+        Unregistered(String),
+    }
+
+    impl fmt::Display for SaveError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                SaveError::Unregistered(what) => {
+                    write!(f, "{} isn't from any registered contribution", what)
+                }
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum LoadError {
+        Json(serde_json::Error),
+        NotASave,
+        /// The save was written by a newer version of the game.
+        TooNew(u64),
+        /// A part of the save is missing or has the wrong type.
+        BadData(String),
+        /// Contributions the save uses that aren't loaded. `plugins` lists the plugins
+        /// that aren't installed at all; `contributions` the ones missing from plugins
+        /// that are.
+        Missing {
+            plugins: Vec<String>,
+            contributions: Vec<ContributionKey>,
+        },
+        /// A contribution is loaded but isn't the kind of thing the save uses it as.
+        WrongKind {
+            key: ContributionKey,
+            expected: &'static str,
+        },
+    }
+
+    impl fmt::Display for LoadError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                LoadError::Json(err) => write!(f, "the save isn't valid JSON: {}", err),
+                LoadError::NotASave => write!(f, "this isn't an OpenFT save"),
+                LoadError::TooNew(version) => write!(
+                    f,
+                    "the save is version {}, but this game only reads up to version {}",
+                    version, SAVE_VERSION
+                ),
+                LoadError::BadData(what) => write!(f, "the save is damaged: {}", what),
+                LoadError::Missing {
+                    plugins,
+                    contributions,
+                } => {
+                    if !plugins.is_empty() {
+                        write!(
+                            f,
+                            "the save needs plugins that aren't installed: {}",
+                            plugins.join(", ")
+                        )?;
+                    }
+                    if !contributions.is_empty() {
+                        if !plugins.is_empty() {
+                            write!(f, "; ")?;
+                        }
+                        let names: Vec<_> = contributions.iter().map(|x| x.to_string()).collect();
+                        write!(
+                            f,
+                            "the save uses contributions that are no longer in their plugins: {}",
+                            names.join(", ")
+                        )?;
+                    }
+                    Ok(())
+                }
+                LoadError::WrongKind { key, expected } => {
+                    write!(
+                        f,
+                        "the save uses {} as a {}, but it isn't one",
+                        key, expected
+                    )
+                }
+            }
+        }
+    }
+
+    /// The contributions a save refers to. Everything else in the save points into this
+    /// list rather than at runtime type ids, which change with plugin load order.
+    #[derive(Default)]
+    struct KeyTable<'r> {
+        keys: Vec<&'r ContributionKey>,
+        index: HashMap<&'r ContributionKey, usize>,
+    }
+
+    impl<'r> KeyTable<'r> {
+        fn add(&mut self, key: &'r ContributionKey) -> usize {
+            *self.index.entry(key).or_insert_with(|| {
+                self.keys.push(key);
+                self.keys.len() - 1
+            })
+        }
+    }
+
+    /// Write the map and simulation as a save.
+    pub fn save_game(
+        map: &TileMap,
+        registry: &ContributionRegistry,
+        clock: &GameClock,
+        simulation: &TrainSimulation,
+    ) -> Result<String, SaveError> {
+        let mut table = KeyTable::default();
+        let key_for = |handle: ContributionHandle, what: String| {
+            registry
+                .key_for(&handle)
+                .ok_or(SaveError::Unregistered(what))
+        };
+
+        let (size_x, size_y) = map.size();
+        let ground_keys = (0..map.ground_type_count())
+            .map(|x| {
+                key_for(
+                    ContributionHandle::GroundType(x),
+                    format!("ground type {}", x),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut ground = Vec::with_capacity(size_x * size_y);
+        let mut roads = Vec::new();
+        let mut rails = Vec::new();
+        for x in 0..size_x {
+            for y in 0..size_y {
+                let tile = map.get(x, y);
+                ground.push(table.add(ground_keys[tile.ground.render_id]));
+
+                if let Some(road) = tile.road {
+                    let handle = ContributionHandle::RoadType(road.road_type);
+                    let key = key_for(handle, format!("road type {}", road.road_type))?;
+                    roads.push(json!({ "type": table.add(key), "x": x, "y": y }));
+                }
+                if let Some(rail) = tile.rail {
+                    let handle = ContributionHandle::RailType(rail.rail_type);
+                    let key = key_for(handle, format!("rail type {}", rail.rail_type))?;
+                    rails.push(json!({
+                        "type": table.add(key),
+                        "x": x,
+                        "y": y,
+                        "tracks": rail.tracks.0,
+                        "signals": rail.signals,
+                    }));
+                }
+            }
+        }
+
+        let mut heights = Vec::with_capacity((size_x + 1) * (size_y + 1));
+        for x in 0..=size_x {
+            for y in 0..=size_y {
+                heights.push(map.vertex_height(x, y));
+            }
+        }
+
+        let stations: HashMap<_, _> = map.stations().map(|(_, x)| (x.entity_id, x)).collect();
+        let mut entities = Vec::new();
+        for entity in map.entities() {
+            let (key, variant) =
+                registry
+                    .entity_type_key(entity.entity_type_id)
+                    .ok_or_else(|| {
+                        SaveError::Unregistered(format!("entity type {}", entity.entity_type_id))
+                    })?;
+            let mut saved = json!({
+                "type": table.add(key),
+                "variant": variant,
+                "x": entity.x0,
+                "y": entity.y0,
+            });
+            if let Some(info) = &entity.entity_info {
+                saved["height"] = json!(info.height);
+            }
+            let id = map.get(entity.x0, entity.y0).entity_id;
+            if let Some(station) = stations.get(&id) {
+                saved["station"] = json!({
+                    "name": station.name,
+                    "passengers": station.passengers_waiting,
+                    "freight": station.freight_waiting,
+                });
+            }
+            entities.push(saved);
+        }
+
+        let mut trains = Vec::new();
+        for train in &simulation.trains {
+            let snapshot = train.snapshot();
+            let handle = ContributionHandle::TrainType(snapshot.train_type);
+            let key = key_for(handle, format!("train type {}", snapshot.train_type))?;
+            let train_type = table.add(key);
+            let mut cars = Vec::new();
+            for car in &snapshot.cars {
+                let key = key_for(
+                    ContributionHandle::CarType(*car),
+                    format!("car type {}", car),
+                )?;
+                cars.push(table.add(key));
+            }
+            let waiting = match snapshot.state {
+                TrainState::Running => Value::Null,
+                TrainState::Waiting(seconds) => json!(seconds),
+            };
+            trains.push(json!({
+                "type": train_type,
+                "cars": cars,
+                "variant": snapshot.variant,
+                "path": snapshot
+                    .path
+                    .iter()
+                    .map(|x| json!([x.x, x.y, x.segment.index(), x.entry.index()]))
+                    .collect::<Vec<_>>(),
+                "progress": snapshot.progress,
+                "speed": snapshot.speed,
+                "waiting": waiting,
+                "route": snapshot.route,
+            }));
+        }
+
+        let mut plugins: Vec<_> = table.keys.iter().map(|x| x.namespace.as_str()).collect();
+        plugins.sort_unstable();
+        plugins.dedup();
+
+        let save = json!({
+            "format": SAVE_FORMAT,
+            "version": SAVE_VERSION,
+            "size": [size_x, size_y],
+            "plugins": plugins,
+            "contributions": table
+                .keys
+                .iter()
+                .map(|x| json!({ "plugin": x.namespace, "id": x.id }))
+                .collect::<Vec<_>>(),
+            "heights": heights,
+            "ground": ground,
+            "entities": entities,
+            "roads": roads,
+            "rails": rails,
+            "clock": {
+                "start": clock.start(),
+                "ticks": clock.ticks(),
+                "speed": clock.speed.multiplier(),
+                "paused": clock.paused,
+            },
+            "trains": trains,
+        });
+        Ok(save.to_string())
+    }
+
+    /// Replace the map with the one in a save, and return the rest of the saved game. The
+    /// whole save is read before the map is touched, so on an error the map is unchanged.
+    pub fn load_game(
+        text: &str,
+        map: &mut TileMap,
+        registry: &ContributionRegistry,
+        catalogue: &TrainCatalogue,
+    ) -> Result<LoadedGame, LoadError> {
+        let mut save: Value = serde_json::from_str(text).map_err(LoadError::Json)?;
+        if save["format"] != SAVE_FORMAT {
+            return Err(LoadError::NotASave);
+        }
+        let version = uint(&save["version"], "version")?;
+        if version == 0 {
+            return Err(LoadError::BadData("version 0".to_string()));
+        }
+        if version > SAVE_VERSION {
+            return Err(LoadError::TooNew(version));
+        }
+        for migrate in MIGRATIONS.iter().skip(version as usize - 1) {
+            migrate(&mut save)?;
+        }
+
+        let handles = resolve_contributions(&save, registry)?;
+        let handle = |value: &Value, what: &str| -> Result<ContributionHandle, LoadError> {
+            let i = uint(value, what)? as usize;
+            handles
+                .get(i)
+                .map(|(_, handle)| (*handle).clone())
+                .ok_or_else(|| LoadError::BadData(format!("{} refers to contribution {}", what, i)))
+        };
+        let wrong_kind = |value: &Value, expected: &'static str| {
+            let i = value.as_u64().unwrap_or_default() as usize;
+            LoadError::WrongKind {
+                key: handles[i].0.clone(),
+                expected,
+            }
+        };
+
+        let size = array(&save["size"], "size")?;
+        let (size_x, size_y) = match &size[..] {
+            [x, y] => (uint(x, "size")? as usize, uint(y, "size")? as usize),
+            _ => return Err(LoadError::BadData("size".to_string())),
+        };
+        // The sizes come from the file, so the tile counts could overflow.
+        let area = |x: usize, y: usize| {
+            x.checked_mul(y).ok_or_else(|| {
+                LoadError::BadData(format!("a {}x{} map is too big", size_x, size_y))
+            })
+        };
+        let tile_count = area(size_x, size_y)?;
+        let vertex_count = area(size_x.saturating_add(1), size_y.saturating_add(1))?;
+        let ground = array(&save["ground"], "ground")?;
+        if ground.len() != tile_count {
+            return Err(LoadError::BadData(format!(
+                "ground has {} tiles for a {}x{} map",
+                ground.len(),
+                size_x,
+                size_y
+            )));
+        }
+        let mut ground_types = Vec::with_capacity(ground.len());
+        for value in ground {
+            match handle(value, "ground")? {
+                ContributionHandle::GroundType(t) if t < map.ground_type_count() => {
+                    ground_types.push(t)
+                }
+                _ => return Err(wrong_kind(value, "ground type")),
+            }
+        }
+        let saved_heights = array(&save["heights"], "heights")?;
+        if saved_heights.len() != vertex_count {
+            return Err(LoadError::BadData("heights".to_string()));
+        }
+        let mut heights = vec![Vec::with_capacity(size_y + 1); size_x + 1];
+        for (i, h) in saved_heights.iter().enumerate() {
+            let h = u8::try_from(uint(h, "heights")?)
+                .map_err(|_| LoadError::BadData("heights".to_string()))?;
+            heights[i / (size_y + 1)].push(h);
+        }
+        if !terrain_fits(&heights, size_x, size_y) {
+            return Err(LoadError::BadData("heights are too steep".to_string()));
+        }
+        let clock = load_clock(&save["clock"])?;
+
+        // Everything is read before the map is reset, so a damaged save leaves the map as
+        // it was.
+        let mut warnings = Vec::new();
+        let mut entities = Vec::new();
+        for entity in array(&save["entities"], "entities")? {
+            let types = match handle(&entity["type"], "entity type")? {
+                ContributionHandle::EntityTypes(types) => types,
+                _ => return Err(wrong_kind(&entity["type"], "building")),
+            };
+            let variant = uint(&entity["variant"], "entity variant")? as usize;
+            let (x, y) = (
+                uint(&entity["x"], "entity x")? as usize,
+                uint(&entity["y"], "entity y")? as usize,
+            );
+            let entity_type = types.start + variant;
+            if !types.contains(&entity_type) {
+                warnings.push(format!(
+                    "no variant {} for the building at {} {}",
+                    variant, x, y
+                ));
+                continue;
+            }
+            let entity_info = match &entity["height"] {
+                Value::Null => None,
+                height => Some(EntityInfo {
+                    height: uint(height, "entity height")? as usize,
+                }),
+            };
+            let station = &entity["station"];
+            entities.push(SavedEntity {
+                x,
+                y,
+                entity_type,
+                entity_info,
+                station_name: station["name"].as_str().map(|x| x.to_string()),
+                passengers: station["passengers"].as_u64().unwrap_or(0) as u32,
+                freight: station["freight"].as_u64().unwrap_or(0) as u32,
+            });
+        }
+
+        let mut roads = Vec::new();
+        for road in array(&save["roads"], "roads")? {
+            let road_type = match handle(&road["type"], "road type")? {
+                ContributionHandle::RoadType(t) => t,
+                _ => return Err(wrong_kind(&road["type"], "road")),
+            };
+            roads.push((
+                uint(&road["x"], "road x")?,
+                uint(&road["y"], "road y")?,
+                road_type,
+            ));
+        }
+
+        let mut rails = Vec::new();
+        for rail in array(&save["rails"], "rails")? {
+            let rail_type = match handle(&rail["type"], "rail type")? {
+                ContributionHandle::RailType(t) => t,
+                _ => return Err(wrong_kind(&rail["type"], "rail")),
+            };
+            rails.push((
+                uint(&rail["x"], "rail x")?,
+                uint(&rail["y"], "rail y")?,
+                rail_type,
+                TrackSet(uint(&rail["tracks"], "rail tracks")? as u16),
+                uint(&rail["signals"], "rail signals")? as u8,
+            ));
+        }
+
+        let mut trains = Vec::new();
+        for train in array(&save["trains"], "trains")? {
+            let train_type = match handle(&train["type"], "train type")? {
+                ContributionHandle::TrainType(t) => t,
+                _ => return Err(wrong_kind(&train["type"], "train")),
+            };
+            let mut cars = Vec::new();
+            for car in array(&train["cars"], "train cars")? {
+                match handle(car, "car type")? {
+                    ContributionHandle::CarType(t) => cars.push(t),
+                    _ => return Err(wrong_kind(car, "train car")),
+                }
+            }
+            trains.push(TrainSnapshot {
+                train_type,
+                cars,
+                variant: uint(&train["variant"], "train variant")? as usize,
+                path: load_path(&train["path"])?,
+                progress: float(&train["progress"], "train progress")?,
+                speed: float(&train["speed"], "train speed")?,
+                state: match &train["waiting"] {
+                    Value::Null => TrainState::Running,
+                    seconds => TrainState::Waiting(float(seconds, "train waiting")?),
+                },
+                route: array(&train["route"], "train route")?
+                    .iter()
+                    .map(|x| tile(x, "train route"))
+                    .collect::<Result<_, _>>()?,
+            });
+        }
+
+        map.reset(size_x, size_y);
+        map.set_terrain(heights);
+        for (i, t) in ground_types.into_iter().enumerate() {
+            map.set_ground(i / size_y, i % size_y, t);
+        }
+
+        for entity in entities {
+            let (x, y, t) = (entity.x, entity.y, entity.entity_type);
+            if map.station_type(t).is_some() {
+                match map.place_station(x, y, t, entity.entity_info) {
+                    Some(id) => {
+                        let station = map.station_mut(id).unwrap();
+                        if let Some(name) = entity.station_name {
+                            station.name = name;
+                        }
+                        station.passengers_waiting = entity.passengers;
+                        station.freight_waiting = entity.freight;
+                    }
+                    None => warnings.push(format!("couldn't put back the station at {} {}", x, y)),
+                }
+            } else if !map.set_entity(x, y, t, entity.entity_info) {
+                warnings.push(format!("couldn't put back the building at {} {}", x, y));
+            }
+        }
+
+        for (x, y, road_type) in roads {
+            if !map.in_bounds(x as i32, y as i32)
+                || !map.set_road(x as usize, y as usize, road_type)
+            {
+                warnings.push(format!("couldn't put back the road at {} {}", x, y));
+            }
+        }
+
+        // All the track goes down before any signals, since a signal needs track on its
+        // side of the tile.
+        let mut signals = Vec::new();
+        for (x, y, rail_type, tracks, bits) in rails {
+            if !map.in_bounds(x as i32, y as i32) {
+                warnings.push(format!("couldn't put back the track at {} {}", x, y));
+                continue;
+            }
+            let (x, y) = (x as usize, y as usize);
+            for segment in tracks.iter() {
+                if !map.add_track(x, y, rail_type, segment) {
+                    warnings.push(format!("couldn't put back the track at {} {}", x, y));
+                }
+            }
+            signals.push((x, y, bits));
+        }
+        for (x, y, bits) in signals {
+            for side in Direction::ALL {
+                if bits & (1 << side.index()) != 0 && !map.add_signal(x, y, side) {
+                    warnings.push(format!("couldn't put back the signal at {} {}", x, y));
+                }
+            }
+        }
+
+        let mut simulation = TrainSimulation::new();
+        for (i, snapshot) in trains.into_iter().enumerate() {
+            if let Err(err) = simulation.restore(map, catalogue, snapshot) {
+                warnings.push(format!("couldn't put back train {}: {}", i, err));
+            }
+        }
+
+        Ok(LoadedGame {
+            clock,
+            simulation,
+            warnings,
+        })
+    }
+
+    /// Look up every contribution the save refers to, reporting all the missing ones at
+    /// once.
+    fn resolve_contributions<'r>(
+        save: &Value,
+        registry: &'r ContributionRegistry,
+    ) -> Result<Vec<(ContributionKey, &'r ContributionHandle)>, LoadError> {
+        let mut handles = Vec::new();
+        let mut missing_plugins = Vec::new();
+        let mut missing_contributions = Vec::new();
+
+        for contribution in array(&save["contributions"], "contributions")? {
+            let (plugin, id) = match (contribution["plugin"].as_str(), contribution["id"].as_str())
+            {
+                (Some(plugin), Some(id)) => (plugin, id),
+                _ => return Err(LoadError::BadData("contributions".to_string())),
+            };
+            let key = ContributionKey::new(plugin, id);
+            match registry.get(&key) {
+                Some(handle) => handles.push((key, handle)),
+                None if registry.has_namespace(plugin) => missing_contributions.push(key),
+                None => {
+                    if !missing_plugins.iter().any(|x| x == plugin) {
+                        missing_plugins.push(plugin.to_string());
+                    }
+                }
+            }
+        }
+
+        match missing_plugins.is_empty() && missing_contributions.is_empty() {
+            true => Ok(handles),
+            false => Err(LoadError::Missing {
+                plugins: missing_plugins,
+                contributions: missing_contributions,
+            }),
+        }
+    }
+
+    fn load_clock(saved: &Value) -> Result<GameClock, LoadError> {
+        let mut clock = GameClock::resume(
+            uint(&saved["start"], "clock start")?,
+            uint(&saved["ticks"], "clock ticks")?,
+        );
+        let multiplier = uint(&saved["speed"], "clock speed")?;
+        clock.speed = GameSpeed::ALL
+            .into_iter()
+            .find(|x| x.multiplier() as u64 == multiplier)
+            .unwrap_or(GameSpeed::Normal);
+        clock.paused = saved["paused"].as_bool().unwrap_or(false);
+        Ok(clock)
+    }
+
+    fn load_path(saved: &Value) -> Result<Vec<TrackTile>, LoadError> {
+        let bad = || LoadError::BadData("train path".to_string());
+        let mut path: Vec<TrackTile> = Vec::new();
+        for tile in array(saved, "train path")? {
+            let parts = array(tile, "train path")?;
+            let [x, y, segment, entry] = &parts[..] else {
+                return Err(bad());
+            };
+            let segment = *TRACK_SEGMENTS
+                .get(uint(segment, "train path")? as usize)
+                .ok_or_else(bad)?;
+            let entry = uint(entry, "train path")? as usize;
+            if entry >= Direction::ALL.len() || !segment.connects(Direction::from_index(entry)) {
+                return Err(bad());
+            }
+            let tile = TrackTile {
+                x: int(x, "train path")?,
+                y: int(y, "train path")?,
+                segment,
+                entry: Direction::from_index(entry),
+            };
+
+            // The path is front first, so each tile leads on into the one before it.
+            if let Some(ahead) = path.last() {
+                let (dx, dy) = tile.exit().offset();
+                if (tile.x + dx, tile.y + dy) != (ahead.x, ahead.y)
+                    || ahead.entry != tile.exit().opposite()
+                {
+                    return Err(bad());
+                }
+            }
+            path.push(tile);
+        }
+        Ok(path)
+    }
+
+    fn array<'v>(value: &'v Value, what: &str) -> Result<&'v Vec<Value>, LoadError> {
+        value
+            .as_array()
+            .ok_or_else(|| LoadError::BadData(what.to_string()))
+    }
+
+    fn uint(value: &Value, what: &str) -> Result<u64, LoadError> {
+        value
+            .as_u64()
+            .ok_or_else(|| LoadError::BadData(what.to_string()))
+    }
+
+    fn int(value: &Value, what: &str) -> Result<i32, LoadError> {
+        value
+            .as_i64()
+            .map(|x| x as i32)
+            .ok_or_else(|| LoadError::BadData(what.to_string()))
+    }
+
+    fn float(value: &Value, what: &str) -> Result<f32, LoadError> {
+        value
+            .as_f64()
+            .map(|x| x as f32)
+            .ok_or_else(|| LoadError::BadData(what.to_string()))
+    }
+
+    fn tile(value: &Value, what: &str) -> Result<(i32, i32), LoadError> {
+        match &array(value, what)?[..] {
+            [x, y] => Ok((int(x, what)?, int(y, what)?)),
+            _ => Err(LoadError::BadData(what.to_string())),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{
+            texture_manager::texture_manager::{Drawable, DrawableTileData, ImageData},
+            tilemap_manager::tilemap_manager::Tile,
+            track::track::TrackSegment,
+        };
+        use macroquad::{math::Vec2, texture::Texture2D};
+
+        /// A map with one ground and one rail type, both registered.
+        fn world(texture: &Texture2D, size: usize) -> (TileMap<'_>, ContributionRegistry) {
+            let drawable = |image_data| DrawableTileData {
+                texture,
+                night_texture: None,
+                image_data,
+                size: Tile { x: 1, y: 1, z: 1 },
+            };
+            let mut map = TileMap::new(size, size);
+            let ground = map.create_ground_type(drawable(ImageData::SingleDrawable(Drawable {
+                offset: Vec2::ZERO,
+                origin: Vec2::ZERO,
+                width: 32.0,
+                height: 16.0,
+            })));
+            let rail = map.create_rail_type(drawable(ImageData::Track(vec![])));
+
+            let mut registry = ContributionRegistry::new();
+            let key = |id| ContributionKey::new("Test", id);
+            let plugin = std::path::PathBuf::from("test");
+            registry
+                .register(
+                    key("ground"),
+                    plugin.clone(),
+                    ContributionHandle::GroundType(ground),
+                )
+                .unwrap();
+            registry
+                .register(key("rail"), plugin, ContributionHandle::RailType(rail))
+                .unwrap();
+            (map, registry)
+        }
+
+        /// A save of a small flat map, to be damaged.
+        fn small_save(texture: &Texture2D) -> Value {
+            let (map, registry) = world(texture, 3);
+            let text = save_game(
+                &map,
+                &registry,
+                &GameClock::resume(0, 0),
+                &TrainSimulation::new(),
+            )
+            .unwrap();
+            serde_json::from_str(&text).unwrap()
+        }
+
+        #[test]
+        fn damaged_save_leaves_the_map_alone() {
+            let texture = Texture2D::empty();
+            let (mut map, registry) = world(&texture, 6);
+            let segment = TrackSegment::new(Direction::West, Direction::East, 0).unwrap();
+            assert!(map.add_track(4, 4, 0, segment));
+            assert!(map.set_vertex_height(0, 0, 1));
+
+            let catalogue = TrainCatalogue::new();
+            let mut damage = |what: &str, change: &dyn Fn(&mut Value)| {
+                let mut save = small_save(&texture);
+                change(&mut save);
+                let err = load_game(&save.to_string(), &mut map, &registry, &catalogue)
+                    .err()
+                    .unwrap_or_else(|| panic!("{} loaded", what));
+                assert_eq!(map.size(), (6, 6), "{}", what);
+                assert!(map.get_tracks(4, 4).contains(segment), "{}", what);
+                assert_eq!(map.vertex_height(0, 0), 1, "{}", what);
+                err.to_string()
+            };
+
+            let err = damage("size", &|save| save["size"] = json!([u64::MAX, 2]));
+            assert!(err.ends_with("is too big"), "{}", err);
+            let err = damage("heights", &|save| save["heights"][0] = json!(3));
+            assert!(err.ends_with("heights are too steep"), "{}", err);
+            damage("rails", &|save| {
+                save["rails"] = json!([{ "type": 0, "x": 0, "y": 0, "tracks": 2 }])
+            });
+            damage("trains", &|save| save["trains"] = json!([{ "type": 0 }]));
+            let err = damage("train path", &|save| {
+                save["contributions"]
+                    .as_array_mut()
+                    .unwrap()
+                    .push(json!({ "plugin": "Test", "id": "rail" }));
+                save["rails"] = json!([{ "type": 1, "x": 0, "y": 0, "tracks": 2, "signals": 0 }]);
+                save["trains"] = json!([{ "type": 1, "path": "nowhere" }]);
+            });
+            assert!(err.contains("train"), "{}", err);
+        }
+
+        /// Turn a save back into version 1, from before terrain heights.
+        fn version_1(mut save: Value) -> Value {
+            save["version"] = json!(1);
+            save.as_object_mut().unwrap().remove("heights");
+            save
+        }
+
+        #[test]
+        fn version_1_save_migrates_to_flat_terrain() {
+            let texture = Texture2D::empty();
+            let (mut map, registry) = world(&texture, 6);
+            assert!(map.set_vertex_height(0, 0, 1));
+
+            let save = version_1(small_save(&texture));
+            let mut migrated = save.clone();
+            for migrate in MIGRATIONS {
+                migrate(&mut migrated).unwrap();
+            }
+            assert_eq!(migrated["heights"], json!(vec![0; 16]));
+
+            let loaded = load_game(
+                &save.to_string(),
+                &mut map,
+                &registry,
+                &TrainCatalogue::new(),
+            )
+            .unwrap();
+            assert!(loaded.warnings.is_empty());
+            assert_eq!(map.size(), (3, 3));
+            assert_eq!(map.max_height(), 0);
+        }
+
+        #[test]
+        fn damaged_version_1_save_is_rejected_before_migrating() {
+            let texture = Texture2D::empty();
+            for size in [
+                json!([u64::MAX, u64::MAX]),
+                json!([1u64 << 40, 1u64 << 40]),
+                json!([u64::MAX, 0]),
+                json!([4, 3]),
+            ] {
+                let mut save = version_1(small_save(&texture));
+                save["size"] = size;
+                assert!(matches!(
+                    add_flat_terrain(&mut save),
+                    Err(LoadError::BadData(_))
+                ));
+                assert!(save.get("heights").is_none());
+            }
+        }
+
+        #[test]
+        fn small_save_loads() {
+            let texture = Texture2D::empty();
+            let (mut map, registry) = world(&texture, 6);
+            let text = small_save(&texture).to_string();
+            let loaded = load_game(&text, &mut map, &registry, &TrainCatalogue::new()).unwrap();
+            assert!(loaded.warnings.is_empty());
+            assert_eq!(map.size(), (3, 3));
+        }
+
+        /// A saved path tile, entered from `entry`.
+        fn saved(x: i32, y: i32, a: Direction, b: Direction, entry: Direction) -> Value {
+            let segment = TrackSegment::new(a, b, 0).unwrap();
+            json!([x, y, segment.index(), entry.index()])
+        }
+
+        #[test]
+        fn load_path_reads_a_joined_up_path() {
+            use Direction::*;
+            let path = json!([
+                saved(2, 1, West, SouthEast, West),
+                saved(1, 1, East, NorthWest, NorthWest),
+                saved(0, 0, NorthWest, SouthEast, NorthWest),
+            ]);
+            let path = load_path(&path).unwrap();
+            assert_eq!(path.len(), 3);
+            assert_eq!((path[1].x, path[1].y, path[1].exit()), (1, 1, East));
+        }
+
+        #[test]
+        fn load_path_rejects_broken_paths() {
+            use Direction::*;
+            let bad = |path: Value| matches!(load_path(&path), Err(LoadError::BadData(_)));
+
+            // The segment doesn't touch the entry side.
+            assert!(bad(json!([saved(0, 0, West, East, North)])));
+            // A gap between tiles.
+            assert!(bad(json!([
+                saved(3, 0, West, East, West),
+                saved(1, 0, West, East, West),
+            ])));
+            // Next to each other, but the track doesn't join up.
+            assert!(bad(json!([
+                saved(1, 0, West, East, East),
+                saved(0, 0, West, East, West),
+            ])));
+            // In the wrong order, back first.
+            assert!(bad(json!([
+                saved(0, 0, West, East, West),
+                saved(1, 0, West, East, West),
+            ])));
+        }
+    }
+}
@@ -0,0 +1,297 @@
+pub mod signal {
+    use crate::track::track::{Direction, TrackLayout};
+    use std::{
+        collections::{HashMap, VecDeque},
+        fmt,
+    };
+
+    /// Which signal block each tile of track belongs to. Blocks are runs of connected track
+    /// split wherever a signal stands on a tile side; only one train may be in a block at a
+    /// time. Block ids are only meaningful until the next rebuild or update.
+    #[derive(Debug, Default, Clone)]
+    pub struct BlockMap {
+        blocks: HashMap<(i32, i32), usize>,
+        /// The tiles in each block.
+        members: HashMap<usize, Vec<(i32, i32)>>,
+        count: usize,
+    }
+
+    impl BlockMap {
+        pub fn new() -> BlockMap {
+            BlockMap::default()
+        }
+
+        /// Work out the blocks for every tile in `tiles`.
+        pub fn build(
+            layout: &impl TrackLayout,
+            tiles: impl Iterator<Item = (i32, i32)>,
+        ) -> BlockMap {
+            let mut map = BlockMap::new();
+            map.fill(layout, tiles);
+            map
+        }
+
+        /// Bring the blocks up to date after the track or signals on a tile changed. Only
+        /// blocks reaching the tile or its neighbours can be merged or split, so only those
+        /// are worked out again, under new ids.
+        pub fn update(&mut self, layout: &impl TrackLayout, x: i32, y: i32) {
+            let affected: Vec<(i32, i32)> = [(0, 0)]
+                .into_iter()
+                .chain(Direction::ALL.map(|d| d.offset()))
+                .map(|(dx, dy)| (x + dx, y + dy))
+                .collect();
+
+            let mut stale = Vec::new();
+            for tile in &affected {
+                if let Some(block) = self.blocks.get(tile).copied() {
+                    stale.extend(self.members.remove(&block).unwrap_or_default());
+                }
+            }
+            for tile in &stale {
+                self.blocks.remove(tile);
+            }
+
+            stale.extend(affected);
+            self.fill(layout, stale.into_iter());
+        }
+
+        /// Give every tile in `tiles` that has track but no block a new block, flooding out
+        /// along connected track up to signals.
+        fn fill(&mut self, layout: &impl TrackLayout, tiles: impl Iterator<Item = (i32, i32)>) {
+            for start in tiles {
+                if self.blocks.contains_key(&start) || layout.tracks(start.0, start.1).is_empty() {
+                    continue;
+                }
+
+                let block = self.count;
+                self.count += 1;
+                self.blocks.insert(start, block);
+                let mut members = vec![start];
+
+                let mut open = VecDeque::from([start]);
+                while let Some((x, y)) = open.pop_front() {
+                    let tracks = layout.tracks(x, y);
+                    for side in Direction::ALL {
+                        let (dx, dy) = side.offset();
+                        let next = (x + dx, y + dy);
+                        if !tracks.connects(side)
+                            || !layout.tracks(next.0, next.1).connects(side.opposite())
+                            || has_boundary(layout, (x, y), side)
+                            || self.blocks.contains_key(&next)
+                        {
+                            continue;
+                        }
+                        self.blocks.insert(next, block);
+                        members.push(next);
+                        open.push_back(next);
+                    }
+                }
+                self.members.insert(block, members);
+            }
+        }
+
+        pub fn block_at(&self, x: i32, y: i32) -> Option<usize> {
+            self.blocks.get(&(x, y)).copied()
+        }
+
+        /// One more than the highest block id handed out. Ids aren't reused by `update`,
+        /// so this can be more than the number of blocks.
+        pub fn block_count(&self) -> usize {
+            self.count
+        }
+    }
+
+    /// Whether a signal on either side of the join between a tile and its neighbour
+    /// splits them into different blocks.
+    pub fn has_boundary(layout: &impl TrackLayout, tile: (i32, i32), side: Direction) -> bool {
+        let (dx, dy) = side.offset();
+        layout.signal_at(tile.0, tile.1, side)
+            || layout.signal_at(tile.0 + dx, tile.1 + dy, side.opposite())
+    }
+
+    /// Trains that are all waiting for blocks held by each other, so none can move.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Deadlock {
+        /// Each train in the cycle, with the block it is waiting for. The block is held by
+        /// the next train in the list, and the last train's by the first.
+        pub waits: Vec<(usize, usize)>,
+    }
+
+    impl fmt::Display for Deadlock {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "deadlock:")?;
+            for (i, (train, block)) in self.waits.iter().enumerate() {
+                let holder = self.waits[(i + 1) % self.waits.len()].0;
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+                write!(
+                    f,
+                    " train {} waits for block {} held by train {}",
+                    train, block, holder
+                )?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Find cycles of trains waiting on each other. `waiting_for` gives the block each
+    /// train is stopped in front of, and `owners` which train holds each block.
+    pub fn find_deadlocks(
+        waiting_for: &[Option<usize>],
+        owners: &HashMap<usize, usize>,
+    ) -> Vec<Deadlock> {
+        let waits_on = |train: usize| -> Option<(usize, usize)> {
+            let block = waiting_for.get(train).copied().flatten()?;
+            let holder = *owners.get(&block)?;
+            (holder != train).then_some((block, holder))
+        };
+
+        let mut deadlocks = Vec::new();
+        let mut seen = vec![false; waiting_for.len()];
+        for start in 0..waiting_for.len() {
+            if seen[start] {
+                continue;
+            }
+
+            // Each train waits on at most one other, so follow the chain until it ends or
+            // comes back on itself.
+            let mut chain = Vec::new();
+            let mut train = start;
+            while !seen[train] {
+                seen[train] = true;
+                chain.push(train);
+                match waits_on(train) {
+                    Some((_, holder)) => train = holder,
+                    None => break,
+                }
+            }
+
+            if let Some(i) = chain.iter().position(|x| *x == train) {
+                if waits_on(train).is_some() {
+                    let waits = chain[i..]
+                        .iter()
+                        .map(|x| (*x, waits_on(*x).unwrap().0))
+                        .collect();
+                    deadlocks.push(Deadlock { waits });
+                }
+            }
+        }
+        deadlocks
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::track::track::{TrackSegment, TrackSet};
+        use std::collections::HashSet;
+        use Direction::*;
+
+        #[derive(Default)]
+        struct Layout {
+            tracks: HashMap<(i32, i32), TrackSet>,
+            signals: HashSet<((i32, i32), Direction)>,
+        }
+
+        impl Layout {
+            fn line(length: i32) -> Layout {
+                let mut layout = Layout::default();
+                let segment = TrackSegment::new(West, East, 0).unwrap();
+                for x in 0..length {
+                    layout.tracks.entry((x, 0)).or_default().insert(segment);
+                }
+                layout
+            }
+
+            fn blocks(&self) -> BlockMap {
+                BlockMap::build(self, self.tracks.keys().copied())
+            }
+        }
+
+        impl TrackLayout for Layout {
+            fn tracks(&self, x: i32, y: i32) -> TrackSet {
+                self.tracks.get(&(x, y)).copied().unwrap_or_default()
+            }
+
+            fn signal_at(&self, x: i32, y: i32, side: Direction) -> bool {
+                self.signals.contains(&((x, y), side))
+            }
+        }
+
+        /// Whether two block maps split the same tiles the same way, whatever their ids.
+        fn same_blocks(a: &BlockMap, b: &BlockMap) -> bool {
+            let pairs: HashSet<_> = a
+                .blocks
+                .iter()
+                .map(|(tile, block)| (*block, b.block_at(tile.0, tile.1)))
+                .collect();
+            let ids: HashSet<_> = pairs.iter().map(|x| x.0).collect();
+            let other_ids: HashSet<_> = pairs.iter().map(|x| x.1).collect();
+            a.blocks.len() == b.blocks.len()
+                && pairs.len() == ids.len()
+                && pairs.len() == other_ids.len()
+                && !other_ids.contains(&None)
+        }
+
+        #[test]
+        fn signals_split_blocks() {
+            let mut layout = Layout::line(10);
+            let blocks = layout.blocks();
+            assert_eq!(blocks.block_at(0, 0), blocks.block_at(9, 0));
+            assert_eq!(blocks.block_at(10, 0), None);
+
+            // A signal on either side of the join makes a boundary.
+            layout.signals.insert(((4, 0), East));
+            layout.signals.insert(((7, 0), West));
+            let blocks = layout.blocks();
+            assert!(has_boundary(&layout, (5, 0), West));
+            assert_eq!(blocks.block_at(0, 0), blocks.block_at(4, 0));
+            assert_ne!(blocks.block_at(4, 0), blocks.block_at(5, 0));
+            assert_eq!(blocks.block_at(5, 0), blocks.block_at(6, 0));
+            assert_ne!(blocks.block_at(6, 0), blocks.block_at(7, 0));
+            assert_eq!(blocks.block_count(), 3);
+        }
+
+        #[test]
+        fn update_matches_a_fresh_build() {
+            let mut layout = Layout::line(20);
+            let mut blocks = layout.blocks();
+
+            layout.signals.insert(((8, 0), East));
+            blocks.update(&layout, 8, 0);
+            assert!(same_blocks(&blocks, &layout.blocks()));
+
+            layout.tracks.remove(&(14, 0));
+            blocks.update(&layout, 14, 0);
+            assert!(same_blocks(&blocks, &layout.blocks()));
+            assert_eq!(blocks.block_at(14, 0), None);
+
+            layout.signals.remove(&((8, 0), East));
+            blocks.update(&layout, 8, 0);
+            assert!(same_blocks(&blocks, &layout.blocks()));
+            assert_eq!(blocks.block_at(0, 0), blocks.block_at(13, 0));
+            assert_ne!(blocks.block_at(13, 0), blocks.block_at(15, 0));
+        }
+
+        #[test]
+        fn trains_waiting_on_each_other_deadlock() {
+            let owners = HashMap::from([(10, 0), (11, 1), (12, 2)]);
+            let waiting = [Some(11), Some(10), Some(10)];
+            let deadlocks = find_deadlocks(&waiting, &owners);
+            assert_eq!(
+                deadlocks,
+                vec![Deadlock {
+                    waits: vec![(0, 11), (1, 10)]
+                }]
+            );
+        }
+
+        #[test]
+        fn a_queue_of_trains_is_no_deadlock() {
+            let owners = HashMap::from([(10, 0), (11, 1)]);
+            assert!(find_deadlocks(&[None, Some(10), Some(11)], &owners).is_empty());
+            // Waiting for a block it holds itself, or one nobody holds, doesn't count.
+            assert!(find_deadlocks(&[Some(10), Some(12)], &owners).is_empty());
+        }
+    }
+}
@@ -0,0 +1,76 @@
+pub mod station {
+    use crate::track::track::{Direction, TrackSegment};
+
+    /// A station on the map: a building, and the platform alongside it that trains stop at.
+    #[derive(Debug, Clone)]
+    pub struct Station {
+        pub name: String,
+        /// The entity id of the building.
+        pub entity_id: usize,
+        /// Opposite corners of the building's footprint, smallest x and y first.
+        pub footprint: ((i32, i32), (i32, i32)),
+        /// Platform tiles, in order along the platform.
+        pub platform: Vec<(i32, i32)>,
+        /// The side of the building the platform is on.
+        pub platform_side: Direction,
+        pub catchment_radius: u32,
+        pub passengers_waiting: u32,
+        pub freight_waiting: u32,
+    }
+
+    impl Station {
+        /// The straight piece of track that runs along the platform.
+        pub fn platform_track(&self) -> TrackSegment {
+            platform_track(self.platform_side)
+        }
+
+        /// Whether a tile is close enough to the station for its passengers and freight to
+        /// use it.
+        pub fn in_catchment(&self, x: i32, y: i32) -> bool {
+            let ((x0, y0), (x1, y1)) = self.footprint;
+            let r = self.catchment_radius as i32;
+            let near = |(tx, ty): (i32, i32)| (x - tx).abs() <= r && (y - ty).abs() <= r;
+
+            (x >= x0 - r && x <= x1 + r && y >= y0 - r && y <= y1 + r)
+                || self.platform.iter().copied().any(near)
+        }
+
+        /// Load up to `passengers` and `freight` units onto a train, returning how many
+        /// of each were taken.
+        pub fn board(&mut self, passengers: u32, freight: u32) -> (u32, u32) {
+            let passengers = passengers.min(self.passengers_waiting);
+            let freight = freight.min(self.freight_waiting);
+            self.passengers_waiting -= passengers;
+            self.freight_waiting -= freight;
+            (passengers, freight)
+        }
+    }
+
+    /// The track along a platform on the given side of a building.
+    pub fn platform_track(side: Direction) -> TrackSegment {
+        match side {
+            Direction::North | Direction::South => {
+                TrackSegment::new(Direction::West, Direction::East, 0).unwrap()
+            }
+            _ => TrackSegment::new(Direction::North, Direction::South, 0).unwrap(),
+        }
+    }
+
+    /// The platform tiles for a building covering `footprint`, on the given side.
+    pub fn platform_tiles(footprint: ((i32, i32), (i32, i32)), side: Direction) -> Vec<(i32, i32)> {
+        let ((x0, y0), (x1, y1)) = footprint;
+        match side {
+            Direction::North => (x0..=x1).map(|x| (x, y0 - 1)).collect(),
+            Direction::South => (x0..=x1).map(|x| (x, y1 + 1)).collect(),
+            Direction::East => (y0..=y1).map(|y| (x1 + 1, y)).collect(),
+            _ => (y0..=y1).map(|y| (x0 - 1, y)).collect(),
+        }
+    }
+
+    /// The side a platform ends up on when a building's sprite is drawn mirrored, which
+    /// swaps its x and y.
+    pub fn mirrored_side(side: Direction) -> Direction {
+        let (dx, dy) = side.offset();
+        Direction::from_offset(dy, dx).unwrap()
+    }
+}
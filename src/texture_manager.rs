@@ -1,14 +1,23 @@
 pub mod texture_manager {
     use crate::{
+        lighting::lighting::Light,
         plugin_manager::plugin_manager::{
             ColorMapping, ColorMappingChannel, Contribution, ContributionImageData,
             ContributionSprite, Plugin,
         },
-        tilemap_manager::tilemap_manager::{EntityInfo, Tile},
-        util::util::{min_xy_bounding_box_for_iso_size, TILE_H_HALF, TILE_W, TILE_W_HALF},
+        tilemap_manager::tilemap_manager::{AutotileRule, EntityInfo, Slope, Tile},
+        track::track::{TrackSet, TRACK_SEGMENTS},
+        util::util::{min_xy_bounding_box_for_iso_size, TILE_H, TILE_W, TILE_Z},
+        vfs::vfs::VirtualDir,
+    };
+    use macroquad::{
+        models::{draw_mesh, Mesh, Vertex},
+        prelude::*,
+    };
+    use std::{
+        collections::HashMap,
+        io::{self, Cursor},
     };
-    use macroquad::prelude::*;
-    use std::collections::HashMap;
 
     const TRANSPARENT_COLOR: Color = Color {
         r: 1.0,
@@ -27,6 +36,8 @@ pub mod texture_manager {
     #[derive(Debug)]
     pub struct DrawableTileData<'a> {
         pub texture: &'a Texture2D,
+        /// Drawn instead of `texture` at night, if the contribution has a night picture.
+        pub night_texture: Option<&'a Texture2D>,
         pub image_data: ImageData,
         pub size: Tile,
     }
@@ -35,6 +46,25 @@ pub mod texture_manager {
     pub enum ImageData {
         SingleDrawable(Drawable),
         MultistoreyDrawable(Drawable, Drawable, Drawable),
+        Autotile(AutotileDrawable),
+        /// One optional sprite per entry in `TRACK_SEGMENTS`.
+        Track(Vec<Option<Drawable>>),
+        /// One sprite per facing, clockwise from north. See `draw_directional`.
+        Directional(Vec<Drawable>),
+    }
+
+    /// A set of sprites, one of which is drawn depending on the neighbouring tiles.
+    #[derive(Debug)]
+    pub struct AutotileDrawable {
+        pub rule: AutotileRule,
+        pub sprites: Vec<Drawable>,
+    }
+
+    impl AutotileDrawable {
+        pub fn resolve(&self, connections: u8) -> &Drawable {
+            let i = self.rule.sprite_for(connections);
+            self.sprites.get(i).unwrap_or(&self.sprites[0])
+        }
     }
 
     #[derive(Debug, Clone, Copy)]
@@ -45,24 +75,91 @@ pub mod texture_manager {
         pub height: f32,
     }
 
+    /// Which pixels of a texture are drawn at all, for telling what's under the mouse.
+    #[derive(Debug)]
+    pub struct AlphaMask {
+        width: usize,
+        height: usize,
+        opaque: Vec<bool>,
+    }
+
+    impl AlphaMask {
+        pub fn from_image(image: &Image) -> AlphaMask {
+            AlphaMask {
+                width: image.width(),
+                height: image.height(),
+                opaque: image.bytes.chunks_exact(4).map(|p| p[3] > 0).collect(),
+            }
+        }
+
+        pub fn is_opaque(&self, x: usize, y: usize) -> bool {
+            x < self.width && y < self.height && self.opaque[y * self.width + x]
+        }
+    }
+
+    /// Alpha masks for a set of textures, looked up by texture.
+    #[derive(Debug, Default)]
+    pub struct AlphaMasks {
+        masks: HashMap<u32, AlphaMask>,
+    }
+
+    impl AlphaMasks {
+        /// Read back every texture from the GPU. This is slow, so is done once, after the
+        /// textures are loaded.
+        pub fn from_textures<'t>(textures: impl IntoIterator<Item = &'t Texture2D>) -> AlphaMasks {
+            let masks = textures
+                .into_iter()
+                .map(|t| (texture_id(t), AlphaMask::from_image(&t.get_texture_data())))
+                .collect();
+            AlphaMasks { masks }
+        }
+
+        pub fn get(&self, texture: &Texture2D) -> Option<&AlphaMask> {
+            self.masks.get(&texture_id(texture))
+        }
+    }
+
+    fn texture_id(texture: &Texture2D) -> u32 {
+        texture.raw_miniquad_texture_handle().gl_internal_id()
+    }
+
     pub async fn load_plugin_textures(plugins: &Vec<Plugin>) -> HashMap<String, Texture2D> {
         let mut plugin_textures = HashMap::<String, Texture2D>::new();
         for plugin in plugins {
             for contribution in &plugin.contributions {
-                let mut texture_path = plugin.filename.clone();
-                texture_path.push(contribution.image_ref.as_str());
-                let texture_full_path = texture_path.to_str().unwrap();
-
-                let key_base = format!("{}-{}", plugin.title, contribution.image_ref);
-
-                for (i, mapping) in contribution.color_mappings.iter().enumerate() {
-                    let key = format!("{}-{}", key_base, i);
-
-                    // Load the texture into GPU memory if it isn't already
-                    if !plugin_textures.contains_key(&key) {
-                        let mut texture = Texture2D::empty();
-                        load_process_texture(&mut texture, texture_full_path, mapping).await;
-                        plugin_textures.insert(key.to_owned(), texture);
+                let image_refs = Some(&contribution.image_ref)
+                    .into_iter()
+                    .chain(&contribution.night_image_ref);
+
+                for image_ref in image_refs {
+                    let key_base = format!("{}-{}", plugin.title, image_ref);
+
+                    for (i, mapping) in contribution.color_mappings.iter().enumerate() {
+                        let key = format!("{}-{}", key_base, i);
+
+                        // Load the texture into GPU memory if it isn't already
+                        if !plugin_textures.contains_key(&key) {
+                            let mut texture = Texture2D::empty();
+                            let res = load_process_texture(
+                                &mut texture,
+                                &plugin.files,
+                                image_ref,
+                                mapping,
+                            )
+                            .await;
+
+                            match res {
+                                Ok(_) => {
+                                    plugin_textures.insert(key.to_owned(), texture);
+                                }
+                                Err(err) => println!(
+                                    "Warning: couldn't load texture '{}' from {}: {}",
+                                    image_ref,
+                                    plugin.filename.display(),
+                                    err
+                                ),
+                            }
+                        }
                     }
                 }
             }
@@ -70,11 +167,13 @@ pub mod texture_manager {
         plugin_textures
     }
 
+    /// One drawable per colour mapping and orientation, each with the index of the colour
+    /// mapping it was loaded with. Mappings whose texture is missing are left out.
     pub fn load_drawable_tile_data_from_contribution<'a>(
         contribution: Contribution,
         title: &str,
         textures: &'a HashMap<String, Texture2D>,
-    ) -> Vec<DrawableTileData<'a>> {
+    ) -> Vec<(usize, DrawableTileData<'a>)> {
         let (w, h) = min_xy_bounding_box_for_iso_size(contribution.size.x, contribution.size.y);
 
         let key_base = format!("{}-{}", title, contribution.image_ref);
@@ -85,11 +184,18 @@ pub mod texture_manager {
 
             let texture = match textures.get(&key) {
                 Some(texture) => texture,
-                None => panic!(
-                    "Warning: couldn't retrieve texture '{}' while loading tile data",
-                    key
-                ),
+                None => {
+                    println!(
+                        "Warning: couldn't retrieve texture '{}' while loading tile data",
+                        key
+                    );
+                    continue;
+                }
             };
+            let night_texture = contribution
+                .night_image_ref
+                .as_ref()
+                .and_then(|x| textures.get(&format!("{}-{}-{}", title, x, i)));
 
             for id in &contribution.image_data {
                 let mut flip = false;
@@ -111,7 +217,41 @@ pub mod texture_manager {
                             contribution_sprite_to_drawable(&s.bottom, w, h),
                         )
                     }
-                    ContributionImageData::ContributionAutotile(_, _) => todo!(),
+                    ContributionImageData::ContributionAutotile(a) => {
+                        ImageData::Autotile(AutotileDrawable {
+                            rule: a.rule.clone(),
+                            sprites: a
+                                .sprites
+                                .iter()
+                                .map(|s| contribution_sprite_to_drawable(s, w, h))
+                                .collect(),
+                        })
+                    }
+                    ContributionImageData::ContributionTrack(t) => {
+                        let mut sprites = vec![None; TRACK_SEGMENTS.len()];
+                        for (segment, sprite) in &t.sprites {
+                            sprites[segment.index()] =
+                                Some(contribution_sprite_to_drawable(sprite, w, h));
+                        }
+                        ImageData::Track(sprites)
+                    }
+                    ContributionImageData::ContributionDirectional(d) => ImageData::Directional(
+                        d.sprites
+                            .iter()
+                            .map(|s| Drawable {
+                                offset: Vec2 {
+                                    x: (d.width / 2) as f32,
+                                    y: (d.height / 2 + s.offset) as f32,
+                                },
+                                origin: Vec2 {
+                                    x: s.origin_x as f32,
+                                    y: s.origin_y as f32,
+                                },
+                                width: d.width as f32,
+                                height: d.height as f32,
+                            })
+                            .collect(),
+                    ),
                 };
 
                 let size = match flip {
@@ -125,11 +265,15 @@ pub mod texture_manager {
 
                 if size.x != size.y {}
 
-                drawables.push(DrawableTileData {
-                    texture,
-                    image_data,
-                    size,
-                });
+                drawables.push((
+                    i,
+                    DrawableTileData {
+                        texture,
+                        night_texture,
+                        image_data,
+                        size,
+                    },
+                ));
             }
         }
         drawables
@@ -152,11 +296,15 @@ pub mod texture_manager {
 
     pub async fn load_process_texture(
         texture: &mut Texture2D,
+        files: &VirtualDir,
         filename: &str,
         mapping: &ColorMapping,
-    ) {
+    ) -> io::Result<()> {
+        let bytes = files.read(filename)?;
+
         let mut image = if filename.contains(".bmp") || filename.contains(".BMP") {
-            let bmp = bmp::open(filename).unwrap();
+            let bmp = bmp::from_reader(&mut Cursor::new(bytes))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
             let w = bmp.get_width();
             let h = bmp.get_height();
 
@@ -178,7 +326,15 @@ pub mod texture_manager {
             }
             gen_image
         } else {
-            load_image(filename).await.unwrap()
+            // Image::from_file_with_format panics on bad data, so decode it here instead.
+            let decoded = image::load_from_memory(&bytes)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?
+                .to_rgba8();
+            Image {
+                width: decoded.width() as u16,
+                height: decoded.height() as u16,
+                bytes: decoded.into_raw(),
+            }
         };
 
         map_colors(&mut image, mapping);
@@ -186,6 +342,7 @@ pub mod texture_manager {
 
         *texture = Texture2D::from_image(&image);
         texture.set_filter(FilterMode::Nearest);
+        Ok(())
     }
 
     pub fn make_transparent(image: &mut Image) {
@@ -261,37 +418,43 @@ pub mod texture_manager {
         }
     }
 
+    /// Draw an entity's whole sprite, with `destination` at its anchor tile.
     pub fn draw_entity(
         entity_info: &Option<EntityInfo>,
         tile: &DrawableTileData,
-        tile_offset: Tile,
+        connections: u8,
         destination: Vec2,
-        color: Color,
+        light: Light,
         scale: f32,
     ) {
-        match &tile.image_data {
-            ImageData::SingleDrawable(image) => {
-                let mut drawable = *image;
-
-                if tile.size.y > 1 {
-                    drawable.origin.x = drawable.origin.x + (TILE_W_HALF * tile_offset.y) as f32;
-                    drawable.offset.y = drawable.offset.y - (TILE_H_HALF * tile_offset.y) as f32;
-                    drawable.width = TILE_W as f32;
-                } else if tile.size.x > 1 {
-                    drawable.origin.x = drawable.origin.x + (TILE_W_HALF * tile_offset.x) as f32;
-                    drawable.offset.y = drawable.offset.y + (TILE_H_HALF * tile_offset.x) as f32;
-                    drawable.width = TILE_W as f32;
-                }
+        // Night pictures are already dark, so they aren't tinted.
+        let (texture, color) = match (light.night, tile.night_texture) {
+            (true, Some(night)) => (night, WHITE),
+            _ => (tile.texture, light.tint),
+        };
 
-                draw(&drawable, &tile.texture, destination, color, scale);
-            }
+        for (drawable, dest) in entity_pieces(entity_info, tile, connections, destination, scale) {
+            draw(drawable, texture, dest, color, scale);
+        }
+    }
+
+    /// The sprites that make up an entity, each with where it is drawn.
+    fn entity_pieces<'t>(
+        entity_info: &Option<EntityInfo>,
+        tile: &'t DrawableTileData,
+        connections: u8,
+        destination: Vec2,
+        scale: f32,
+    ) -> Vec<(&'t Drawable, Vec2)> {
+        match &tile.image_data {
+            ImageData::SingleDrawable(image) => vec![(image, destination)],
             ImageData::MultistoreyDrawable(top, middle, bottom) => {
                 let h = match entity_info {
                     Some(i) => i.height,
                     None => 1,
                 };
 
-                draw(&bottom, &tile.texture, destination, color, scale);
+                let mut pieces = vec![(bottom, destination)];
 
                 let mut y = destination.y;
                 for _ in 1..=h {
@@ -300,7 +463,7 @@ pub mod texture_manager {
                         x: destination.x,
                         y,
                     };
-                    draw(&middle, &tile.texture, dest, color, scale);
+                    pieces.push((middle, dest));
                 }
 
                 y -= scale * (top.height - top.offset.y);
@@ -308,20 +471,209 @@ pub mod texture_manager {
                     x: destination.x,
                     y,
                 };
-                draw(&top, &tile.texture, dest, color, scale);
+                pieces.push((top, dest));
+                pieces
+            }
+            ImageData::Autotile(autotile) => vec![(autotile.resolve(connections), destination)],
+            ImageData::Track(_) => panic!("Track must be drawn with draw_track!"),
+            ImageData::Directional(_) => panic!("Vehicles must be drawn with draw_directional!"),
+        }
+    }
+
+    /// Whether an entity drawn with `destination` at its anchor tile covers `point`, in
+    /// world xy coordinates. Transparent pixels don't count.
+    pub fn entity_hit(
+        entity_info: &Option<EntityInfo>,
+        tile: &DrawableTileData,
+        connections: u8,
+        destination: Vec2,
+        point: Vec2,
+        masks: &AlphaMasks,
+    ) -> bool {
+        let mask = masks.get(tile.texture);
+        entity_pieces(entity_info, tile, connections, destination, 1.0)
+            .into_iter()
+            .any(|(drawable, dest)| drawable_hit(drawable, mask, dest, point))
+    }
+
+    /// Whether any of the track drawn on a tile at `destination` covers `point`.
+    pub fn track_hit(
+        tile: &DrawableTileData,
+        tracks: TrackSet,
+        destination: Vec2,
+        point: Vec2,
+        masks: &AlphaMasks,
+    ) -> bool {
+        let mask = masks.get(tile.texture);
+        match &tile.image_data {
+            ImageData::Track(sprites) => tracks.iter().any(|segment| {
+                sprites[segment.index()]
+                    .as_ref()
+                    .is_some_and(|drawable| drawable_hit(drawable, mask, destination, point))
+            }),
+            _ => false,
+        }
+    }
+
+    /// Whether a sprite drawn unscaled at `destination` covers `point`. Without a mask,
+    /// the whole of the sprite's box does.
+    fn drawable_hit(
+        drawable: &Drawable,
+        mask: Option<&AlphaMask>,
+        destination: Vec2,
+        point: Vec2,
+    ) -> bool {
+        let x = point.x - (destination.x - drawable.offset.x);
+        let y = point.y - (destination.y - drawable.offset.y);
+        if x < 0.0 || y < 0.0 || x >= drawable.width || y >= drawable.height {
+            return false;
+        }
+        match mask {
+            Some(mask) => mask.is_opaque(
+                (drawable.origin.x + x) as usize,
+                (drawable.origin.y + y) as usize,
+            ),
+            None => true,
+        }
+    }
+
+    /// How far above the top of its anchor tile's box an entity's sprite reaches, in
+    /// world pixels. Multistorey sprites are as tall as `entity_info` makes them.
+    pub fn sprite_height(tile: &DrawableTileData, entity_info: &Option<EntityInfo>) -> f32 {
+        let above = |drawable: &Drawable| drawable.offset.y;
+        match &tile.image_data {
+            ImageData::SingleDrawable(image) => above(image),
+            ImageData::MultistoreyDrawable(top, middle, bottom) => {
+                let h = match entity_info {
+                    Some(i) => i.height,
+                    None => 1,
+                };
+                let storeys = h as f32 * (middle.height - middle.offset.y) + top.height;
+                storeys.max(above(bottom))
             }
+            ImageData::Autotile(autotile) => autotile.sprites.iter().map(above).fold(0.0, f32::max),
+            ImageData::Track(sprites) => sprites.iter().flatten().map(above).fold(0.0, f32::max),
+            ImageData::Directional(sprites) => sprites.iter().map(above).fold(0.0, f32::max),
         }
     }
 
-    pub fn draw_tile(tile: &DrawableTileData, destination: Vec2, color: Color, scale: f32) {
+    pub fn draw_tile(
+        tile: &DrawableTileData,
+        connections: u8,
+        destination: Vec2,
+        color: Color,
+        scale: f32,
+    ) {
         match &tile.image_data {
             ImageData::SingleDrawable(image) => {
                 draw(&image, &tile.texture, destination, color, scale);
             }
             ImageData::MultistoreyDrawable(_, _, _) => panic!("A tile cannot be multistorey!"),
+            ImageData::Track(_) => panic!("Track must be drawn with draw_track!"),
+            ImageData::Directional(_) => panic!("Vehicles must be drawn with draw_directional!"),
+            ImageData::Autotile(autotile) => {
+                draw(
+                    autotile.resolve(connections),
+                    tile.texture,
+                    destination,
+                    color,
+                    scale,
+                );
+            }
         }
     }
 
+    /// Draw a ground tile on a slope. The flat sprite is stretched over the tile with its
+    /// corners raised to match, so any ground can be drawn on any slope.
+    pub fn draw_sloped_tile(
+        tile: &DrawableTileData,
+        connections: u8,
+        slope: Slope,
+        destination: Vec2,
+        color: Color,
+        scale: f32,
+    ) {
+        let drawable = match &tile.image_data {
+            _ if slope.is_flat() => return draw_tile(tile, connections, destination, color, scale),
+            ImageData::SingleDrawable(image) => image,
+            ImageData::Autotile(autotile) => autotile.resolve(connections),
+            _ => return draw_tile(tile, connections, destination, color, scale),
+        };
+
+        // The tile's corners, clockwise from north-west at the top of the diamond, then
+        // its middle, raised halfway between its corners.
+        let [nw, ne, se, sw] = slope.corners().map(|x| x as f32);
+        let (w, h) = (TILE_W as f32, TILE_H as f32);
+        let points = [
+            (w / 2.0, 0.0, nw),
+            (w, h / 2.0, ne),
+            (w / 2.0, h, se),
+            (0.0, h / 2.0, sw),
+            (w / 2.0, h / 2.0, (nw + ne + se + sw) / 4.0),
+        ];
+
+        let (texture_w, texture_h) = (tile.texture.width(), tile.texture.height());
+        let vertices = points
+            .iter()
+            .map(|(x, y, z)| Vertex {
+                position: Vec3::new(
+                    destination.x + x * scale,
+                    destination.y + (y - z * TILE_Z as f32) * scale,
+                    0.0,
+                ),
+                uv: Vec2::new(
+                    (drawable.origin.x + drawable.offset.x + x) / texture_w,
+                    (drawable.origin.y + drawable.offset.y + y) / texture_h,
+                ),
+                color,
+            })
+            .collect();
+
+        draw_mesh(&Mesh {
+            vertices,
+            indices: vec![0, 1, 4, 1, 2, 4, 2, 3, 4, 3, 0, 4],
+            texture: Some(*tile.texture),
+        });
+    }
+
+    /// Draw every segment of track on a tile.
+    pub fn draw_track(
+        tile: &DrawableTileData,
+        tracks: TrackSet,
+        destination: Vec2,
+        color: Color,
+        scale: f32,
+    ) {
+        if let ImageData::Track(sprites) = &tile.image_data {
+            for segment in tracks.iter() {
+                if let Some(drawable) = &sprites[segment.index()] {
+                    draw(drawable, tile.texture, destination, color, scale);
+                }
+            }
+        }
+    }
+
+    /// Draw a vehicle centred on `destination`. `heading` is measured in eighths of a turn
+    /// clockwise from north, as for `Direction::index`, and is rounded to the nearest frame.
+    pub fn draw_directional(
+        tile: &DrawableTileData,
+        heading: f32,
+        destination: Vec2,
+        color: Color,
+        scale: f32,
+    ) {
+        if let ImageData::Directional(sprites) = &tile.image_data {
+            let frame = directional_frame(sprites.len(), heading);
+            draw(&sprites[frame], tile.texture, destination, color, scale);
+        }
+    }
+
+    /// Which of `frames` evenly spaced facings is closest to `heading`.
+    pub fn directional_frame(frames: usize, heading: f32) -> usize {
+        let frame = (heading * frames as f32 / 8.0).round() as i32;
+        frame.rem_euclid(frames as i32) as usize
+    }
+
     pub fn draw(
         drawable: &Drawable,
         texture: &Texture2D,
@@ -1,7 +1,24 @@
 pub mod tilemap_manager {
-    use crate::texture_manager::texture_manager::DrawableTileData;
+    use crate::{
+        clock::clock::Season,
+        plugin_manager::plugin_manager::StationProperties,
+        rail_graph::rail_graph::RailGraph,
+        render_order::render_order::{DepthKey, Layer},
+        signal::signal::BlockMap,
+        station::station::{mirrored_side, platform_tiles, platform_track, Station},
+        texture_manager::texture_manager::{
+            entity_hit, sprite_height, track_hit, AlphaMasks, DrawableTileData, ImageData,
+        },
+        track::track::{Direction, TrackLayout, TrackSegment, TrackSet},
+        util::util::{iso_to_xy, visible_iso_bounds, xy_to_iso_walking_z, TILE_Z},
+    };
+    use macroquad::prelude::Vec2;
     use obj_pool::{ObjId, ObjPool};
-    use std::vec;
+    use std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        ops::Range,
+        vec,
+    };
 
     #[derive(PartialEq, Debug, Clone, Copy)]
     pub struct Tile {
@@ -10,15 +27,206 @@ pub mod tilemap_manager {
         pub z: i32,
     }
 
+    /// The topmost thing drawn under a point, found by `TileMap::pick`.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Pick {
+        /// An entity, by its anchor tile.
+        Entity(Tile),
+        Track(Tile),
+        Ground(Tile),
+    }
+
+    impl Pick {
+        pub fn tile(self) -> Tile {
+            match self {
+                Pick::Entity(tile) | Pick::Track(tile) | Pick::Ground(tile) => tile,
+            }
+        }
+    }
+
+    /// The highest a terrain vertex can be raised.
+    pub const MAX_HEIGHT: u8 = 8;
+
+    /// Which corners of a tile are one level above its lowest corner, one bit per corner
+    /// clockwise from north-west: NW, NE, SE, SW. Neighbouring vertices never differ by
+    /// more than a level, so this is all it takes to pick a tile's shape.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Slope(pub u8);
+
+    impl Slope {
+        pub const FLAT: Slope = Slope(0);
+
+        /// The slope of a tile with these corner heights, in NW, NE, SE, SW order.
+        pub fn from_corners(corners: [u8; 4]) -> Slope {
+            let base = *corners.iter().min().unwrap();
+            Slope(
+                corners
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, x)| **x > base)
+                    .fold(0, |mask, (i, _)| mask | 1 << i),
+            )
+        }
+
+        /// The slope that climbs one level towards a side of the tile, as under a
+        /// straight piece of sloped track.
+        pub fn rising_towards(side: Direction) -> Slope {
+            match side {
+                Direction::North => Slope(0b0011),
+                Direction::East => Slope(0b0110),
+                Direction::South => Slope(0b1100),
+                Direction::West => Slope(0b1001),
+                _ => Slope::FLAT,
+            }
+        }
+
+        pub fn is_flat(self) -> bool {
+            self.0 == 0
+        }
+
+        /// How far each corner is above the lowest one, in NW, NE, SE, SW order.
+        pub fn corners(self) -> [u8; 4] {
+            [0, 1, 2, 3].map(|i| (self.0 >> i) & 1)
+        }
+    }
+
+    /// Connection bits for tiles that join up with their neighbours, such as roads.
+    /// North is up-right on screen, i.e. towards -y. The corner bits are only used by
+    /// autotiles that look at all eight neighbours.
+    pub const CONNECT_NORTH: u8 = 1;
+    pub const CONNECT_EAST: u8 = 2;
+    pub const CONNECT_SOUTH: u8 = 4;
+    pub const CONNECT_WEST: u8 = 8;
+    pub const CONNECT_NORTH_EAST: u8 = 16;
+    pub const CONNECT_SOUTH_EAST: u8 = 32;
+    pub const CONNECT_SOUTH_WEST: u8 = 64;
+    pub const CONNECT_NORTH_WEST: u8 = 128;
+
+    /// Tile offsets for each connection bit, in the same order as the bits.
+    pub const CONNECT_OFFSETS: [(u8, i32, i32); 8] = [
+        (CONNECT_NORTH, 0, -1),
+        (CONNECT_EAST, 1, 0),
+        (CONNECT_SOUTH, 0, 1),
+        (CONNECT_WEST, -1, 0),
+        (CONNECT_NORTH_EAST, 1, -1),
+        (CONNECT_SOUTH_EAST, 1, 1),
+        (CONNECT_SOUTH_WEST, -1, 1),
+        (CONNECT_NORTH_WEST, -1, -1),
+    ];
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum AutotileNeighbours {
+        Four,
+        Eight,
+    }
+
+    /// How an autotile picks its sprite from the tiles around it.
+    #[derive(Debug, Clone)]
+    pub struct AutotileRule {
+        pub neighbours: AutotileNeighbours,
+        /// Sprite index for each connection mask. Masks that aren't listed fall back to
+        /// the mask without corners, and then to sprite 0.
+        pub table: HashMap<u8, usize>,
+    }
+
+    impl AutotileRule {
+        /// A rule where the sprite index is the connection mask itself, as used by
+        /// 16-piece road sheets.
+        pub fn identity(neighbours: AutotileNeighbours, sprites: usize) -> AutotileRule {
+            AutotileRule {
+                neighbours,
+                table: (0..sprites.min(256)).map(|i| (i as u8, i)).collect(),
+            }
+        }
+
+        pub fn sprite_for(&self, connections: u8) -> usize {
+            let edges = connections & 0x0F;
+            let mask = match self.neighbours {
+                AutotileNeighbours::Four => edges,
+                AutotileNeighbours::Eight => reduce_corners(connections),
+            };
+
+            self.table
+                .get(&mask)
+                .or_else(|| self.table.get(&edges))
+                .copied()
+                .unwrap_or(0)
+        }
+    }
+
+    /// A corner only matters when both edges next to it are connected too; otherwise
+    /// the same sprite is used whether or not the diagonal tile matches.
+    fn reduce_corners(connections: u8) -> u8 {
+        let mut mask = connections & 0x0F;
+        for (corner, a, b) in [
+            (CONNECT_NORTH_EAST, CONNECT_NORTH, CONNECT_EAST),
+            (CONNECT_SOUTH_EAST, CONNECT_SOUTH, CONNECT_EAST),
+            (CONNECT_SOUTH_WEST, CONNECT_SOUTH, CONNECT_WEST),
+            (CONNECT_NORTH_WEST, CONNECT_NORTH, CONNECT_WEST),
+        ] {
+            if connections & corner != 0 && connections & a != 0 && connections & b != 0 {
+                mask |= corner;
+            }
+        }
+        mask
+    }
+
+    /// Whether one height per vertex, indexed `[x][y]`, fits a map of the given size: no
+    /// higher than `MAX_HEIGHT`, and no more than a level between neighbouring vertices.
+    pub fn terrain_fits(heights: &[Vec<u8>], size_x: usize, size_y: usize) -> bool {
+        if heights.len() != size_x + 1 || heights.iter().any(|x| x.len() != size_y + 1) {
+            return false;
+        }
+        for x in 0..=size_x {
+            for y in 0..=size_y {
+                let h = heights[x][y];
+                let steep = |nx: usize, ny: usize| {
+                    heights
+                        .get(nx)
+                        .and_then(|x| x.get(ny))
+                        .is_some_and(|n| n.abs_diff(h) > 1)
+                };
+                if h > MAX_HEIGHT
+                    || steep(x + 1, y)
+                    || steep(x, y + 1)
+                    || steep(x + 1, y + 1)
+                    || (y > 0 && steep(x + 1, y - 1))
+                {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
     #[derive(Clone, Copy, Debug)]
     pub struct MapData {
         pub ground: MapDataGround,
         pub entity_id: usize,
+        pub road: Option<MapDataRoad>,
+        pub rail: Option<MapDataRail>,
+        /// The station whose platform this tile is part of.
+        pub station: Option<usize>,
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct MapDataRail {
+        pub rail_type: usize,
+        pub tracks: TrackSet,
+        /// Sides of the tile with a signal on them, one bit per `Direction::index`.
+        pub signals: u8,
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct MapDataRoad {
+        pub road_type: usize,
+        pub connections: u8,
     }
 
     #[derive(Clone, Copy, Debug)]
     pub struct MapDataGround {
         pub render_id: usize,
+        pub connections: u8,
     }
 
     #[derive(Debug)]
@@ -27,6 +235,8 @@ pub mod tilemap_manager {
         pub y0: usize,
         pub entity_type_id: usize,
         pub entity_info: Option<EntityInfo>,
+        /// Neighbour mask for single-tile autotile entities such as fences.
+        pub connections: u8,
     }
 
     #[derive(Debug)]
@@ -36,43 +246,234 @@ pub mod tilemap_manager {
 
     pub struct TileMap<'a> {
         data: Vec<Vec<MapData>>,
-        ground_drawables: Vec<DrawableTileData<'a>>,
+        /// Terrain height at each tile corner. Vertex (x, y) is the north-west corner of
+        /// tile (x, y), so there is one more row and column than there are tiles.
+        heights: Vec<Vec<u8>>,
+        max_height: u8,
+        /// The furthest any entity sprite reaches above its anchor tile, in world pixels.
+        max_sprite_height: f32,
+        /// Each ground type is either one drawable used all year, or one per season with
+        /// spring first.
+        ground_drawables: Vec<Vec<DrawableTileData<'a>>>,
+        /// The season ground is drawn for.
+        season: Season,
         entity_drawables: Vec<DrawableTileData<'a>>,
+        road_drawables: Vec<DrawableTileData<'a>>,
+        rail_drawables: Vec<DrawableTileData<'a>>,
+        rail_graph: RailGraph,
+        blocks: BlockMap,
         entities: ObjPool<Entity>,
+        /// Station properties for each entity type that is a station building.
+        station_types: HashMap<usize, StationProperties>,
+        stations: BTreeMap<usize, Station>,
+        next_station: usize,
     }
 
     impl<'a> TileMap<'a> {
         pub fn new(size_x: usize, size_y: usize) -> TileMap<'a> {
-            let empty = MapData {
-                ground: MapDataGround { render_id: 0 },
-                entity_id: 0,
-            };
-
             let mut t = TileMap {
-                data: vec![vec!(empty; size_x); size_y],
+                data: vec![],
+                heights: vec![],
+                max_height: 0,
+                max_sprite_height: 0.0,
                 ground_drawables: vec![],
+                season: Season::Spring,
                 entity_drawables: vec![],
+                road_drawables: vec![],
+                rail_drawables: vec![],
+                rail_graph: RailGraph::new(),
+                blocks: BlockMap::new(),
                 entities: ObjPool::new(),
+                station_types: HashMap::new(),
+                stations: BTreeMap::new(),
+                next_station: 0,
+            };
+            t.reset(size_x, size_y);
+            t
+        }
+
+        /// Empty the map and give it a new size. Ground, entity, road and rail types are
+        /// kept.
+        pub fn reset(&mut self, size_x: usize, size_y: usize) {
+            let empty = MapData {
+                ground: MapDataGround {
+                    render_id: 0,
+                    connections: 0,
+                },
+                entity_id: 0,
+                road: None,
+                rail: None,
+                station: None,
             };
+            self.data = vec![vec!(empty; size_y); size_x];
+            self.heights = vec![vec![0; size_y + 1]; size_x + 1];
+            self.max_height = 0;
 
-            t.entities.insert(Entity {
+            // Entity id 0 means "no entity", so the first slot is taken up.
+            self.entities = ObjPool::new();
+            self.entities.insert(Entity {
                 x0: 0,
                 y0: 0,
                 entity_type_id: 0,
                 entity_info: None,
+                connections: 0,
             });
 
-            t
+            self.stations.clear();
+            self.next_station = 0;
+            self.rail_graph = RailGraph::new();
+            self.blocks = BlockMap::new();
+        }
+
+        pub fn size(&self) -> (usize, usize) {
+            (self.data.len(), self.data.first().map_or(0, |x| x.len()))
         }
 
         #[inline]
-        fn get(&self, x: usize, y: usize) -> MapData {
+        pub fn get(&self, x: usize, y: usize) -> MapData {
             self.data[x][y]
         }
 
-        pub fn get_ground(&self, x: usize, y: usize) -> &DrawableTileData<'a> {
-            let idx = self.get(x, y).ground.render_id;
-            &self.ground_drawables[idx]
+        #[inline]
+        pub fn in_bounds(&self, x: i32, y: i32) -> bool {
+            x >= 0 && y >= 0 && (x as usize) < self.data.len() && (y as usize) < self.data[0].len()
+        }
+
+        /// Terrain height at a tile corner, in levels.
+        pub fn vertex_height(&self, vx: usize, vy: usize) -> u8 {
+            self.heights[vx][vy]
+        }
+
+        /// The highest vertex on the map.
+        pub fn max_height(&self) -> u8 {
+            self.max_height
+        }
+
+        /// The tallest an entity sprite can be: the tallest entity type at its contribution's
+        /// height, or any entity placed taller than that.
+        pub fn max_sprite_height(&self) -> f32 {
+            self.max_sprite_height
+        }
+
+        /// The heights of a tile's corners, in NW, NE, SE, SW order.
+        pub fn corner_heights(&self, x: usize, y: usize) -> [u8; 4] {
+            [(x, y), (x + 1, y), (x + 1, y + 1), (x, y + 1)].map(|(x, y)| self.heights[x][y])
+        }
+
+        /// The level a tile sits at: the height of its lowest corner.
+        pub fn tile_height(&self, x: usize, y: usize) -> u8 {
+            *self.corner_heights(x, y).iter().min().unwrap()
+        }
+
+        pub fn slope(&self, x: usize, y: usize) -> Slope {
+            Slope::from_corners(self.corner_heights(x, y))
+        }
+
+        /// The height of the ground at a fractional map position, in levels. Positions off
+        /// the map are at level 0.
+        pub fn surface_height(&self, x: f32, y: f32) -> f32 {
+            let (tx, ty) = (x.floor() as i32, y.floor() as i32);
+            if !self.in_bounds(tx, ty) {
+                return 0.0;
+            }
+            let [nw, ne, se, sw] = self
+                .corner_heights(tx as usize, ty as usize)
+                .map(|x| x as f32);
+            let (u, v) = (x - tx as f32, y - ty as f32);
+            let north = nw + (ne - nw) * u;
+            let south = sw + (se - sw) * u;
+            north + (south - north) * v
+        }
+
+        /// Raise or lower a terrain vertex. Neighbouring vertices are dragged along so
+        /// that none differ by more than a level. Nothing changes if any tile that would
+        /// move has something built on it.
+        pub fn set_vertex_height(&mut self, vx: usize, vy: usize, height: u8) -> bool {
+            if vx >= self.heights.len() || vy >= self.heights[0].len() || height > MAX_HEIGHT {
+                return false;
+            }
+
+            let mut changed = BTreeMap::from([((vx, vy), height)]);
+            let mut queue = vec![(vx, vy)];
+            while let Some((x, y)) = queue.pop() {
+                let h = changed[&(x, y)];
+                for (_, dx, dy) in CONNECT_OFFSETS {
+                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                    if nx < 0
+                        || ny < 0
+                        || nx as usize >= self.heights.len()
+                        || ny as usize >= self.heights[0].len()
+                    {
+                        continue;
+                    }
+                    let n = (nx as usize, ny as usize);
+                    let current = changed.get(&n).copied().unwrap_or(self.heights[n.0][n.1]);
+                    let clamped = current.clamp(h.saturating_sub(1), h + 1);
+                    if clamped != current {
+                        changed.insert(n, clamped);
+                        queue.push(n);
+                    }
+                }
+            }
+            changed.retain(|(x, y), h| self.heights[*x][*y] != *h);
+
+            // Every tile touching a moved vertex has to be clear.
+            for (x, y) in changed.keys() {
+                for (tx, ty) in [(*x, *y), (x.wrapping_sub(1), *y)]
+                    .into_iter()
+                    .flat_map(|(x, y)| [(x, y), (x, y.wrapping_sub(1))])
+                {
+                    if !self.in_bounds(tx as i32, ty as i32) {
+                        continue;
+                    }
+                    let tile = self.data[tx][ty];
+                    if tile.entity_id != 0
+                        || tile.road.is_some()
+                        || tile.rail.is_some()
+                        || tile.station.is_some()
+                    {
+                        return false;
+                    }
+                }
+            }
+
+            for ((x, y), h) in changed {
+                self.heights[x][y] = h;
+            }
+            self.update_max_height();
+            true
+        }
+
+        /// Replace the whole terrain, one height per vertex indexed `[x][y]`. Meant for
+        /// an empty map, such as one being loaded; fails unless `terrain_fits`.
+        pub fn set_terrain(&mut self, heights: Vec<Vec<u8>>) -> bool {
+            let (size_x, size_y) = self.size();
+            if !terrain_fits(&heights, size_x, size_y) {
+                return false;
+            }
+            self.heights = heights;
+            self.update_max_height();
+            true
+        }
+
+        fn update_max_height(&mut self) {
+            self.max_height = self.heights.iter().flatten().copied().max().unwrap_or(0);
+        }
+
+        /// Get the ground drawable for a tile, with its autotile connections.
+        pub fn get_ground(&self, x: usize, y: usize) -> (&DrawableTileData<'a>, u8) {
+            let ground = self.get(x, y).ground;
+            let variants = &self.ground_drawables[ground.render_id];
+            let drawable = variants.get(self.season.index()).unwrap_or(&variants[0]);
+            (drawable, ground.connections)
+        }
+
+        pub fn season(&self) -> Season {
+            self.season
+        }
+
+        pub fn set_season(&mut self, season: Season) {
+            self.season = season;
         }
 
         pub fn get_entity(
@@ -100,27 +501,157 @@ pub mod tilemap_manager {
             None
         }
 
+        /// Every entity on the map.
+        pub fn entities(&self) -> impl Iterator<Item = &Entity> {
+            self.entities
+                .iter()
+                .filter(|(id, _)| ObjPool::<Entity>::obj_id_to_index(*id) != 0)
+                .map(|(_, entity)| entity)
+        }
+
+        /// The topmost entity, track or ground drawn under a point in world xy coordinates,
+        /// going by sprite bounds and, where `masks` has the texture, which pixels are
+        /// transparent. Things are tested in drawing order, so whatever is drawn last over
+        /// the point wins. Returns `None` if the point isn't over anything on the map.
+        pub fn pick(&self, point: Vec2, masks: &AlphaMasks) -> Option<Pick> {
+            let mut hits = Vec::new();
+
+            // Anything that could be drawn over the point stands on a tile at most this
+            // far below it on the screen.
+            let reach = (self.max_height as i32 * TILE_Z) as f32 + self.max_sprite_height;
+            let (lower, upper) = visible_iso_bounds(
+                (
+                    Vec2 {
+                        x: point.x - 1.0,
+                        y: point.y,
+                    },
+                    Vec2 {
+                        x: point.x + 1.0,
+                        y: point.y,
+                    },
+                ),
+                reach,
+            );
+            let (size_x, size_y) = self.size();
+            let xs = lower.x.max(0) as usize..upper.x.clamp(0, size_x as i32) as usize;
+            let ys = lower.y.max(0) as usize..upper.y.clamp(0, size_y as i32) as usize;
+
+            let mut seen_entities = HashSet::new();
+            for tx in xs {
+                for ty in ys.clone() {
+                    let tile = Tile {
+                        x: tx as i32,
+                        y: ty as i32,
+                        z: self.tile_height(tx, ty) as i32,
+                    };
+
+                    if let Some((rail, tracks)) = self.get_rail(tx, ty) {
+                        if track_hit(rail, tracks, iso_to_xy(&tile), point, masks) {
+                            hits.push((
+                                DepthKey::tile(tile.x, tile.y, tile.z, Layer::Surface),
+                                Pick::Track(tile),
+                            ));
+                        }
+                    }
+
+                    if let Some((entity, drawable, _)) = self.get_entity(tx, ty) {
+                        if seen_entities.insert(self.get(tx, ty).entity_id) {
+                            let anchor = Tile {
+                                x: entity.x0 as i32,
+                                y: entity.y0 as i32,
+                                z: self.tile_height(entity.x0, entity.y0) as i32,
+                            };
+                            let hit = entity_hit(
+                                &entity.entity_info,
+                                drawable,
+                                entity.connections,
+                                iso_to_xy(&anchor),
+                                point,
+                                masks,
+                            );
+                            if hit {
+                                let x1 = anchor.x + drawable.size.x - 1;
+                                hits.push((
+                                    DepthKey::entity(x1, anchor.y, anchor.z),
+                                    Pick::Entity(anchor),
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+
+            let ground = xy_to_iso_walking_z(point, self.max_height as i32, |t| {
+                self.in_bounds(t.x, t.y)
+                    && self.tile_height(t.x as usize, t.y as usize) as i32 == t.z
+            });
+            if self.in_bounds(ground.x, ground.y) {
+                hits.push((
+                    DepthKey::tile(ground.x, ground.y, ground.z, Layer::Ground),
+                    Pick::Ground(ground),
+                ));
+            }
+
+            hits.into_iter().max_by_key(|hit| hit.0).map(|hit| hit.1)
+        }
+
+        /// How an entity type is drawn.
+        pub fn entity_drawable(&self, entity_type: usize) -> Option<&DrawableTileData<'a>> {
+            self.entity_drawables.get(entity_type)
+        }
+
         pub fn entity_type_count(&self) -> usize {
             self.entity_drawables.len()
         }
 
         pub fn create_ground_type(&mut self, drawable: DrawableTileData<'a>) -> usize {
-            self.ground_drawables.push(drawable);
+            self.ground_drawables.push(vec![drawable]);
             self.ground_drawables.len() - 1
         }
 
+        /// Create a ground type drawn differently each season, from one drawable per
+        /// season starting with spring. Seasons without a drawable use the first.
+        pub fn create_seasonal_ground_type(
+            &mut self,
+            drawables: Vec<DrawableTileData<'a>>,
+        ) -> Option<usize> {
+            if drawables.is_empty() {
+                return None;
+            }
+            self.ground_drawables.push(drawables);
+            Some(self.ground_drawables.len() - 1)
+        }
+
+        pub fn ground_type_count(&self) -> usize {
+            self.ground_drawables.len()
+        }
+
         pub fn set_ground(&mut self, x: usize, y: usize, ground_id: usize) -> bool {
             match self.ground_drawables.get(ground_id) {
                 Some(_) => {
                     self.data[x][y].ground.render_id = ground_id;
+                    self.refresh_autotiles(x, y);
                     true
                 }
                 None => false,
             }
         }
 
-        pub fn create_entity_types(&mut self, drawables: &mut Vec<DrawableTileData<'a>>) {
+        /// Add entity types, returning the range of type ids they were given.
+        pub fn create_entity_types(
+            &mut self,
+            drawables: &mut Vec<DrawableTileData<'a>>,
+        ) -> Range<usize> {
+            let start = self.entity_drawables.len();
+            for drawable in drawables.iter() {
+                let storeys = Some(EntityInfo {
+                    height: drawable.size.z.max(1) as usize,
+                });
+                let height = sprite_height(drawable, &storeys);
+                self.max_sprite_height = self.max_sprite_height.max(height);
+            }
             self.entity_drawables.append(drawables);
+            start..self.entity_drawables.len()
         }
 
         fn create_entity(&mut self, entity: Entity) -> usize {
@@ -128,6 +659,8 @@ pub mod tilemap_manager {
             ObjPool::<usize>::obj_id_to_index(id) as usize
         }
 
+        /// Place an entity with its anchor tile at (x0, y0). Station buildings get their
+        /// platform too; see `place_station`.
         pub fn set_entity(
             &mut self,
             x0: usize,
@@ -135,37 +668,70 @@ pub mod tilemap_manager {
             entity_type: usize,
             entity_info: Option<EntityInfo>,
         ) -> bool {
-            let drawable = &self.entity_drawables[entity_type];
+            match self.station_types.contains_key(&entity_type) {
+                true => self
+                    .place_station(x0, y0, entity_type, entity_info)
+                    .is_some(),
+                false => self
+                    .insert_entity(x0, y0, entity_type, entity_info)
+                    .is_some(),
+            }
+        }
 
-            let x1 = match x0.checked_add((drawable.size.x - 1) as usize) {
-                Some(x1) => x1,
-                None => return false,
-            };
+        /// The tiles an entity of this type would cover with its anchor at (x0, y0), as
+        /// `(x0, x1, y1, y0)`, or `None` if that runs off the map.
+        fn footprint(
+            &self,
+            x0: usize,
+            y0: usize,
+            entity_type: usize,
+        ) -> Option<(usize, usize, usize, usize)> {
+            let drawable = &self.entity_drawables[entity_type];
+            let x1 = x0.checked_add((drawable.size.x - 1) as usize)?;
+            let y1 = y0.checked_sub((drawable.size.y - 1) as usize)?;
+            match self.in_bounds(x1 as i32, y0 as i32) {
+                true => Some((x0, x1, y1, y0)),
+                false => None,
+            }
+        }
 
-            let y1 = match y0.checked_sub((drawable.size.y - 1) as usize) {
-                Some(y1) => y1,
-                None => return false,
-            };
+        fn insert_entity(
+            &mut self,
+            x0: usize,
+            y0: usize,
+            entity_type: usize,
+            entity_info: Option<EntityInfo>,
+        ) -> Option<usize> {
+            let (x0, x1, y1, y0) = self.footprint(x0, y0, entity_type)?;
 
-            // Check that there is no existing entity within the area
+            // Check that the area is flat and there is no existing entity within it
             for x in x0..=x1 {
                 for y in y1..=y0 {
-                    if x >= self.data.len() || y >= self.data[0].len() {
-                        return false;
+                    if !self.slope(x, y).is_flat() {
+                        return None;
                     }
-
-                    match self.data[x][y].entity_id {
-                        0 => continue,
-                        _ => return false,
+                    match self.data[x][y] {
+                        MapData {
+                            entity_id: 0,
+                            road: None,
+                            rail: None,
+                            station: None,
+                            ..
+                        } => continue,
+                        _ => return None,
                     }
                 }
             }
 
+            let height = sprite_height(&self.entity_drawables[entity_type], &entity_info);
+            self.max_sprite_height = self.max_sprite_height.max(height);
+
             let entity = Entity {
                 x0,
                 y0,
                 entity_type_id: entity_type,
                 entity_info,
+                connections: 0,
             };
             let id = self.create_entity(entity);
 
@@ -175,7 +741,537 @@ pub mod tilemap_manager {
                     self.data[x][y].entity_id = id;
                 }
             }
+            for x in x0..=x1 {
+                for y in y1..=y0 {
+                    self.refresh_autotiles(x, y);
+                }
+            }
+            Some(id)
+        }
+
+        /// Remove the entity covering a tile, clearing its whole footprint. A station
+        /// building takes its station with it; track on the platform stays.
+        pub fn remove_entity(&mut self, x: usize, y: usize) -> bool {
+            if !self.in_bounds(x as i32, y as i32) || self.data[x][y].entity_id == 0 {
+                return false;
+            }
+            let id = self.data[x][y].entity_id;
+            let entity = self.entities.remove(ObjId::from_index(id as u32)).unwrap();
+            let (x0, x1, y1, y0) = self
+                .footprint(entity.x0, entity.y0, entity.entity_type_id)
+                .unwrap();
+
+            for x in x0..=x1 {
+                for y in y1..=y0 {
+                    self.data[x][y].entity_id = 0;
+                }
+            }
+            for x in x0..=x1 {
+                for y in y1..=y0 {
+                    self.refresh_autotiles(x, y);
+                }
+            }
+
+            let station = self
+                .stations
+                .iter()
+                .find(|(_, x)| x.entity_id == id)
+                .map(|(station, _)| *station);
+            if let Some(station) = station {
+                let station = self.stations.remove(&station).unwrap();
+                for (x, y) in station.platform {
+                    self.data[x as usize][y as usize].station = None;
+                    self.update_rail_graph(x as usize, y as usize);
+                }
+            }
+            true
+        }
+
+        /// Bulldoze the entities and roads in the rectangle between two corner tiles,
+        /// returning how many were removed. Track is left for `remove_track`, since trains
+        /// may be on it.
+        pub fn remove_area(&mut self, from: (i32, i32), to: (i32, i32)) -> usize {
+            let (size_x, size_y) = self.size();
+            let x0 = from.0.min(to.0).max(0);
+            let x1 = from.0.max(to.0).min(size_x as i32 - 1);
+            let y0 = from.1.min(to.1).max(0);
+            let y1 = from.1.max(to.1).min(size_y as i32 - 1);
+
+            let mut removed = 0;
+            for x in x0..=x1 {
+                for y in y0..=y1 {
+                    let (x, y) = (x as usize, y as usize);
+                    removed += self.remove_entity(x, y) as usize;
+                    removed += self.remove_road(x, y) as usize;
+                }
+            }
+            removed
+        }
+
+        /// Add the entity types for a station building. `size` is the building's size as
+        /// given by its contribution; variants drawn mirrored have their platform on the
+        /// mirrored side.
+        pub fn create_station_types(
+            &mut self,
+            drawables: &mut Vec<DrawableTileData<'a>>,
+            size: Tile,
+            properties: &StationProperties,
+        ) -> Range<usize> {
+            let mirrored: Vec<bool> = drawables
+                .iter()
+                .map(|x| x.size.x != size.x || x.size.y != size.y)
+                .collect();
+            let types = self.create_entity_types(drawables);
+
+            for (t, mirrored) in types.clone().zip(mirrored) {
+                let mut properties = properties.clone();
+                if mirrored {
+                    properties.platform_side = mirrored_side(properties.platform_side);
+                }
+                self.station_types.insert(t, properties);
+            }
+            types
+        }
+
+        pub fn station_type(&self, entity_type: usize) -> Option<&StationProperties> {
+            self.station_types.get(&entity_type)
+        }
+
+        /// Place a station building and its platform. The platform tiles must be clear of
+        /// roads, entities and other stations, and any track already on them must run
+        /// along the platform. Returns the new station's id.
+        pub fn place_station(
+            &mut self,
+            x0: usize,
+            y0: usize,
+            entity_type: usize,
+            entity_info: Option<EntityInfo>,
+        ) -> Option<usize> {
+            let properties = self.station_types.get(&entity_type)?.clone();
+            let (x0, x1, y1, y0) = self.footprint(x0, y0, entity_type)?;
+            let footprint = ((x0 as i32, y1 as i32), (x1 as i32, y0 as i32));
+
+            let track = platform_track(properties.platform_side);
+            let platform = platform_tiles(footprint, properties.platform_side);
+            for (x, y) in &platform {
+                if !self.in_bounds(*x, *y) {
+                    return None;
+                }
+                let tile = self.get(*x as usize, *y as usize);
+                let tracks = tile.rail.map(|x| x.tracks).unwrap_or_default();
+                if tile.entity_id != 0
+                    || tile.road.is_some()
+                    || tile.station.is_some()
+                    || !self.slope(*x as usize, *y as usize).is_flat()
+                    || tracks.iter().any(|x| x != track)
+                {
+                    return None;
+                }
+            }
+
+            let entity_id = self.insert_entity(x0, y0, entity_type, entity_info)?;
+
+            let id = self.next_station;
+            self.next_station += 1;
+            for (x, y) in &platform {
+                self.data[*x as usize][*y as usize].station = Some(id);
+                self.update_rail_graph(*x as usize, *y as usize);
+            }
+
+            self.stations.insert(
+                id,
+                Station {
+                    name: properties.name,
+                    entity_id,
+                    footprint,
+                    platform,
+                    platform_side: properties.platform_side,
+                    catchment_radius: properties.catchment_radius,
+                    passengers_waiting: 0,
+                    freight_waiting: 0,
+                },
+            );
+            Some(id)
+        }
+
+        pub fn station(&self, id: usize) -> Option<&Station> {
+            self.stations.get(&id)
+        }
+
+        pub fn station_mut(&mut self, id: usize) -> Option<&mut Station> {
+            self.stations.get_mut(&id)
+        }
+
+        pub fn stations(&self) -> impl Iterator<Item = (usize, &Station)> {
+            self.stations.iter().map(|(id, x)| (*id, x))
+        }
+
+        /// Add a road type. Its drawable is normally an autotile so that it joins up with
+        /// neighbouring roads.
+        pub fn create_road_type(&mut self, drawable: DrawableTileData<'a>) -> usize {
+            self.road_drawables.push(drawable);
+            self.road_drawables.len() - 1
+        }
+
+        pub fn road_type_count(&self) -> usize {
+            self.road_drawables.len()
+        }
+
+        /// Get the road drawable at a tile and its connections, if there is a road there.
+        pub fn get_road(&self, x: usize, y: usize) -> Option<(&DrawableTileData<'a>, u8)> {
+            let road = self.get(x, y).road?;
+            Some((&self.road_drawables[road.road_type], road.connections))
+        }
+
+        /// Lay a road on a tile. The tile and its neighbours are reconnected so that each
+        /// picks the piece matching the roads around it.
+        pub fn set_road(&mut self, x: usize, y: usize, road_type: usize) -> bool {
+            if road_type >= self.road_drawables.len()
+                || !self.in_bounds(x as i32, y as i32)
+                || self.data[x][y].entity_id != 0
+                || self.data[x][y].rail.is_some()
+                || !self.slope(x, y).is_flat()
+            {
+                return false;
+            }
+
+            self.data[x][y].road = Some(MapDataRoad {
+                road_type,
+                connections: 0,
+            });
+            self.refresh_autotiles(x, y);
+            true
+        }
+
+        pub fn remove_road(&mut self, x: usize, y: usize) -> bool {
+            if !self.in_bounds(x as i32, y as i32) || self.data[x][y].road.is_none() {
+                return false;
+            }
+            self.data[x][y].road = None;
+            self.refresh_autotiles(x, y);
+            true
+        }
+
+        pub fn create_rail_type(&mut self, drawable: DrawableTileData<'a>) -> usize {
+            self.rail_drawables.push(drawable);
+            self.rail_drawables.len() - 1
+        }
+
+        pub fn rail_type_count(&self) -> usize {
+            self.rail_drawables.len()
+        }
+
+        /// Get the rail drawable and the track segments on a tile, if it has any track.
+        pub fn get_rail(&self, x: usize, y: usize) -> Option<(&DrawableTileData<'a>, TrackSet)> {
+            let rail = self.get(x, y).rail?;
+            Some((&self.rail_drawables[rail.rail_type], rail.tracks))
+        }
+
+        pub fn get_tracks(&self, x: usize, y: usize) -> TrackSet {
+            match self.get(x, y).rail {
+                Some(rail) => rail.tracks,
+                None => TrackSet::default(),
+            }
+        }
+
+        /// Add a segment of track to a tile. Track can't share a tile with a road or an
+        /// entity, and a sloped segment has to be alone on its tile, on ground sloping
+        /// the same way.
+        pub fn add_track(
+            &mut self,
+            x: usize,
+            y: usize,
+            rail_type: usize,
+            segment: TrackSegment,
+        ) -> bool {
+            if rail_type >= self.rail_drawables.len()
+                || !self.in_bounds(x as i32, y as i32)
+                || self.data[x][y].entity_id != 0
+                || self.data[x][y].road.is_some()
+            {
+                return false;
+            }
+
+            let mut tracks = self.get_tracks(x, y);
+            if !tracks.can_insert(segment) {
+                return false;
+            }
+
+            // Flat track needs flat ground, and sloped track a slope climbing the same way.
+            let slope = match segment.is_sloped() {
+                true => Slope::rising_towards(segment.b),
+                false => Slope::FLAT,
+            };
+            if self.slope(x, y) != slope {
+                return false;
+            }
+
+            // Platforms only take the straight track running along them.
+            if let Some(station) = self.data[x][y].station {
+                if segment != self.stations[&station].platform_track() {
+                    return false;
+                }
+            }
+            tracks.insert(segment);
+
+            let signals = self.data[x][y].rail.map(|x| x.signals).unwrap_or(0);
+            self.data[x][y].rail = Some(MapDataRail {
+                rail_type,
+                tracks,
+                signals,
+            });
+            self.update_rail_graph(x, y);
+            true
+        }
+
+        pub fn remove_track(&mut self, x: usize, y: usize, segment: TrackSegment) -> bool {
+            if !self.in_bounds(x as i32, y as i32) {
+                return false;
+            }
+            let rail = match &mut self.data[x][y].rail {
+                Some(rail) if rail.tracks.contains(segment) => rail,
+                _ => return false,
+            };
+
+            rail.tracks.remove(segment);
+            for side in Direction::ALL {
+                if !rail.tracks.connects(side) {
+                    rail.signals &= !(1 << side.index());
+                }
+            }
+            if rail.tracks.is_empty() {
+                self.data[x][y].rail = None;
+            }
+            self.update_rail_graph(x, y);
+            true
+        }
+
+        /// The connectivity of all the track on the map, kept up to date as track is laid
+        /// and removed.
+        pub fn rail_graph(&self) -> &RailGraph {
+            &self.rail_graph
+        }
+
+        fn update_rail_graph(&mut self, x: usize, y: usize) {
+            let mut graph = std::mem::take(&mut self.rail_graph);
+            graph.update(self, x as i32, y as i32);
+            self.rail_graph = graph;
+            self.update_blocks(x, y);
+        }
+
+        /// Put a signal on the side of a tile where its track leaves. The signal splits
+        /// the track into separate blocks either side of it.
+        pub fn add_signal(&mut self, x: usize, y: usize, side: Direction) -> bool {
+            if !self.in_bounds(x as i32, y as i32) {
+                return false;
+            }
+            match &mut self.data[x][y].rail {
+                Some(rail) if rail.tracks.connects(side) => rail.signals |= 1 << side.index(),
+                _ => return false,
+            }
+            self.update_blocks(x, y);
+            true
+        }
+
+        pub fn remove_signal(&mut self, x: usize, y: usize, side: Direction) -> bool {
+            if !self.in_bounds(x as i32, y as i32) {
+                return false;
+            }
+            match &mut self.data[x][y].rail {
+                Some(rail) if rail.signals & (1 << side.index()) != 0 => {
+                    rail.signals &= !(1 << side.index())
+                }
+                _ => return false,
+            }
+            self.update_blocks(x, y);
             true
         }
+
+        /// The sides of a tile that have a signal.
+        pub fn get_signals(&self, x: usize, y: usize) -> Vec<Direction> {
+            let signals = self.get(x, y).rail.map(|x| x.signals).unwrap_or(0);
+            Direction::ALL
+                .into_iter()
+                .filter(|d| signals & (1 << d.index()) != 0)
+                .collect()
+        }
+
+        /// The signal blocks the track is divided into.
+        pub fn blocks(&self) -> &BlockMap {
+            &self.blocks
+        }
+
+        fn update_blocks(&mut self, x: usize, y: usize) {
+            let mut blocks = std::mem::take(&mut self.blocks);
+            blocks.update(self, x as i32, y as i32);
+            self.blocks = blocks;
+        }
+
+        /// Recompute the connections of every autotile in the 3x3 block around a tile.
+        /// This must be called whenever a tile's ground, road or entity changes, since any
+        /// of its neighbours may be drawn differently as a result.
+        fn refresh_autotiles(&mut self, x: usize, y: usize) {
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                    if self.in_bounds(nx, ny) {
+                        self.refresh_autotile(nx as usize, ny as usize);
+                    }
+                }
+            }
+        }
+
+        fn refresh_autotile(&mut self, x: usize, y: usize) {
+            let tile = self.get(x, y);
+
+            // Every season's variant of a ground type joins up the same way.
+            let ground = &self.ground_drawables[tile.ground.render_id][0];
+            if let ImageData::Autotile(autotile) = &ground.image_data {
+                let render_id = tile.ground.render_id;
+                let connections = self.connections(x, y, autotile.rule.neighbours, |t| {
+                    t.ground.render_id == render_id
+                });
+                self.data[x][y].ground.connections = connections;
+            }
+
+            // Roads join up with any kind of road.
+            if let Some(road) = tile.road {
+                let neighbours = match &self.road_drawables[road.road_type].image_data {
+                    ImageData::Autotile(autotile) => autotile.rule.neighbours,
+                    _ => AutotileNeighbours::Four,
+                };
+                let connections = self.connections(x, y, neighbours, |t| t.road.is_some());
+                if let Some(road) = &mut self.data[x][y].road {
+                    road.connections = connections;
+                }
+            }
+
+            if tile.entity_id > 0 {
+                let id = ObjId::from_index(tile.entity_id as u32);
+                let entity_type = self.entities[id].entity_type_id;
+                if let ImageData::Autotile(autotile) =
+                    &self.entity_drawables[entity_type].image_data
+                {
+                    let entities = &self.entities;
+                    let connections = self.connections(x, y, autotile.rule.neighbours, |t| {
+                        t.entity_id > 0
+                            && entities[ObjId::from_index(t.entity_id as u32)].entity_type_id
+                                == entity_type
+                    });
+                    self.entities[id].connections = connections;
+                }
+            }
+        }
+
+        /// Build the connection mask for a tile from the neighbours that `connects` accepts.
+        fn connections(
+            &self,
+            x: usize,
+            y: usize,
+            neighbours: AutotileNeighbours,
+            connects: impl Fn(&MapData) -> bool,
+        ) -> u8 {
+            let offsets = match neighbours {
+                AutotileNeighbours::Four => &CONNECT_OFFSETS[..4],
+                AutotileNeighbours::Eight => &CONNECT_OFFSETS[..],
+            };
+
+            let mut connections = 0;
+            for (bit, dx, dy) in offsets {
+                let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                if self.in_bounds(nx, ny) && connects(&self.data[nx as usize][ny as usize]) {
+                    connections |= bit;
+                }
+            }
+            connections
+        }
+    }
+
+    impl TrackLayout for TileMap<'_> {
+        fn tracks(&self, x: i32, y: i32) -> TrackSet {
+            match self.in_bounds(x, y) {
+                true => self.get_tracks(x as usize, y as usize),
+                false => TrackSet::default(),
+            }
+        }
+
+        fn station_at(&self, x: i32, y: i32) -> Option<usize> {
+            match self.in_bounds(x, y) {
+                true => self.get(x as usize, y as usize).station,
+                false => None,
+            }
+        }
+
+        fn signal_at(&self, x: i32, y: i32, side: Direction) -> bool {
+            match self.in_bounds(x, y) {
+                true => self
+                    .get(x as usize, y as usize)
+                    .rail
+                    .is_some_and(|rail| rail.signals & (1 << side.index()) != 0),
+                false => false,
+            }
+        }
+
+        fn block_at(&self, x: i32, y: i32) -> Option<usize> {
+            self.blocks.block_at(x, y)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use macroquad::texture::Texture2D;
+
+        /// A map with one rail type and straight track along y = 2 from x = 0 to 7.
+        fn map_with_line(texture: &Texture2D) -> TileMap<'_> {
+            let mut map = TileMap::new(8, 8);
+            let rail = map.create_rail_type(DrawableTileData {
+                texture,
+                night_texture: None,
+                image_data: ImageData::Track(vec![]),
+                size: Tile { x: 1, y: 1, z: 1 },
+            });
+            let segment = TrackSegment::new(Direction::West, Direction::East, 0).unwrap();
+            for x in 0..8 {
+                assert!(map.add_track(x, 2, rail, segment));
+            }
+            map
+        }
+
+        #[test]
+        fn rail_edits_off_the_map_fail() {
+            let texture = Texture2D::empty();
+            let mut map = map_with_line(&texture);
+            let segment = TrackSegment::new(Direction::West, Direction::East, 0).unwrap();
+
+            assert!(!map.add_track(8, 2, 0, segment));
+            assert!(!map.remove_track(8, 2, segment));
+            assert!(!map.remove_track(usize::MAX, 2, segment));
+            assert!(!map.add_signal(2, 8, Direction::East));
+            assert!(!map.remove_signal(9, 9, Direction::East));
+        }
+
+        #[test]
+        fn signals_and_track_edits_update_blocks() {
+            let texture = Texture2D::empty();
+            let mut map = map_with_line(&texture);
+            assert_eq!(map.block_at(0, 2), map.block_at(7, 2));
+
+            assert!(map.add_signal(3, 2, Direction::East));
+            assert!(!map.add_signal(3, 2, Direction::North));
+            assert_eq!(map.block_at(0, 2), map.block_at(3, 2));
+            assert_ne!(map.block_at(3, 2), map.block_at(4, 2));
+            assert_eq!(map.block_at(4, 2), map.block_at(7, 2));
+
+            let segment = TrackSegment::new(Direction::West, Direction::East, 0).unwrap();
+            assert!(map.remove_track(5, 2, segment));
+            assert_eq!(map.block_at(5, 2), None);
+            assert_ne!(map.block_at(4, 2), map.block_at(6, 2));
+
+            assert!(map.remove_signal(3, 2, Direction::East));
+            assert!(!map.remove_signal(3, 2, Direction::East));
+            assert_eq!(map.block_at(0, 2), map.block_at(4, 2));
+            assert_eq!(map.get_signals(3, 2), vec![]);
+        }
     }
 }
@@ -0,0 +1,520 @@
+pub mod timetable {
+    use crate::clock::clock::MINUTES_PER_DAY;
+    use std::fmt;
+
+    /// Where a timetabled vehicle stops: a tile, or a station by name.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum StopLocation {
+        Tile(i32, i32),
+        Named(String),
+    }
+
+    impl StopLocation {
+        /// Whether a vehicle on `tile`, at the station called `station` if any, is at
+        /// this stop.
+        pub fn matches(&self, tile: (i32, i32), station: Option<&str>) -> bool {
+            match self {
+                StopLocation::Tile(x, y) => (*x, *y) == tile,
+                StopLocation::Named(name) => station == Some(name.as_str()),
+            }
+        }
+    }
+
+    impl fmt::Display for StopLocation {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                StopLocation::Tile(x, y) => write!(f, "{},{}", x, y),
+                StopLocation::Named(name) => {
+                    // Station names come from plugins, so they may hold quotes themselves.
+                    let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+                    write!(f, "\"{}\"", name)
+                }
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct TimetableStop {
+        pub location: StopLocation,
+        /// The least time to stay, in game minutes.
+        pub dwell: f32,
+        /// Times of day the vehicle may leave, in minutes since midnight. If there are
+        /// any, the vehicle waits for the first one after it arrives.
+        pub departures: Vec<u32>,
+        /// Stay until the vehicle is fully loaded.
+        pub wait_for_full_load: bool,
+    }
+
+    /// What a vehicle is doing, as far as its timetable is concerned.
+    #[derive(Debug, Clone, Copy)]
+    pub struct VehicleStatus<'s> {
+        pub tile: (i32, i32),
+        /// The name of the station the vehicle is at, if any.
+        pub station: Option<&'s str>,
+        /// Whether the vehicle has come to a stop.
+        pub stopped: bool,
+        pub full: bool,
+    }
+
+    /// What a timetable tells a vehicle to do.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Order {
+        /// Keep heading for this stop.
+        HeadFor(usize),
+        /// Stay at the current stop.
+        Wait,
+        /// Leave now for this stop.
+        Depart(usize),
+    }
+
+    /// How far through its timetable a vehicle is.
+    #[derive(Debug, Clone, Copy, Default, PartialEq)]
+    pub struct TimetableState {
+        /// The stop the vehicle is heading for or standing at.
+        pub stop: usize,
+        /// When the vehicle arrived at the stop, in game minutes.
+        pub arrived_at: Option<f32>,
+    }
+
+    /// An ordered list of stops that a vehicle goes round in a loop.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct Timetable {
+        pub stops: Vec<TimetableStop>,
+    }
+
+    impl Timetable {
+        pub fn new() -> Timetable {
+            Timetable::default()
+        }
+
+        /// Decide what a vehicle should do at time `now`, in game minutes since the game
+        /// began. `state` is updated as the vehicle arrives at and leaves stops.
+        pub fn evaluate(
+            &self,
+            state: &mut TimetableState,
+            vehicle: &VehicleStatus,
+            now: f32,
+        ) -> Order {
+            if self.stops.is_empty() {
+                return Order::Wait;
+            }
+            state.stop %= self.stops.len();
+
+            let stop = &self.stops[state.stop];
+            if !vehicle.stopped || !stop.location.matches(vehicle.tile, vehicle.station) {
+                state.arrived_at = None;
+                return Order::HeadFor(state.stop);
+            }
+
+            let arrived = *state.arrived_at.get_or_insert(now);
+            let ready = match next_departure(&stop.departures, arrived) {
+                Some(departure) => departure.max(arrived + stop.dwell),
+                None => arrived + stop.dwell,
+            };
+            if now < ready || (stop.wait_for_full_load && !vehicle.full) {
+                return Order::Wait;
+            }
+
+            state.stop = (state.stop + 1) % self.stops.len();
+            state.arrived_at = None;
+            Order::Depart(state.stop)
+        }
+
+        /// Read a timetable from text. Each line is one stop: a location, either `x,y` or
+        /// a station name in quotes, then any of `dwell <minutes>`, `depart <hh:mm>` (more
+        /// than once for several departures) and `full`. Quotes and backslashes in a name
+        /// are written `\"` and `\\`. Blank lines and lines starting with `#` are skipped.
+        pub fn parse(text: &str) -> Result<Timetable, TimetableError> {
+            let mut timetable = Timetable::new();
+
+            for (i, line) in text.lines().enumerate() {
+                let line_number = i + 1;
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                let (location, rest) = parse_location(line, line_number)?;
+                let mut stop = TimetableStop {
+                    location,
+                    dwell: 0.0,
+                    departures: Vec::new(),
+                    wait_for_full_load: false,
+                };
+
+                let mut words = rest.split_whitespace();
+                while let Some(word) = words.next() {
+                    let mut value = |field: &str| {
+                        words.next().ok_or_else(|| TimetableError::MissingValue {
+                            line: line_number,
+                            field: field.to_string(),
+                        })
+                    };
+                    match word {
+                        "dwell" => {
+                            let value = value("dwell")?;
+                            stop.dwell = match value.parse::<f32>() {
+                                Ok(x) if x >= 0.0 => x,
+                                _ => {
+                                    return Err(TimetableError::BadNumber {
+                                        line: line_number,
+                                        field: "dwell".to_string(),
+                                        value: value.to_string(),
+                                    })
+                                }
+                            }
+                        }
+                        "depart" => {
+                            let value = value("depart")?;
+                            let time =
+                                parse_time(value).ok_or_else(|| TimetableError::BadTime {
+                                    line: line_number,
+                                    value: value.to_string(),
+                                })?;
+                            stop.departures.push(time);
+                        }
+                        "full" => stop.wait_for_full_load = true,
+                        _ => {
+                            return Err(TimetableError::UnknownOption {
+                                line: line_number,
+                                option: word.to_string(),
+                            })
+                        }
+                    }
+                }
+
+                timetable.stops.push(stop);
+            }
+
+            Ok(timetable)
+        }
+    }
+
+    /// Writes the timetable in the format `Timetable::parse` reads.
+    impl fmt::Display for Timetable {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for stop in &self.stops {
+                write!(f, "{}", stop.location)?;
+                if stop.dwell > 0.0 {
+                    write!(f, " dwell {}", stop.dwell)?;
+                }
+                for departure in &stop.departures {
+                    write!(f, " depart {}", format_time(*departure))?;
+                }
+                if stop.wait_for_full_load {
+                    write!(f, " full")?;
+                }
+                writeln!(f)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum TimetableError {
+        BadLocation {
+            line: usize,
+            text: String,
+        },
+        MissingValue {
+            line: usize,
+            field: String,
+        },
+        BadNumber {
+            line: usize,
+            field: String,
+            value: String,
+        },
+        BadTime {
+            line: usize,
+            value: String,
+        },
+        UnknownOption {
+            line: usize,
+            option: String,
+        },
+    }
+
+    impl fmt::Display for TimetableError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                TimetableError::BadLocation { line, text } => {
+                    write!(f, "line {}: '{}' is not a tile or station name", line, text)
+                }
+                TimetableError::MissingValue { line, field } => {
+                    write!(f, "line {}: '{}' needs a value", line, field)
+                }
+                TimetableError::BadNumber { line, field, value } => {
+                    write!(f, "line {}: bad {} '{}'", line, field, value)
+                }
+                TimetableError::BadTime { line, value } => {
+                    write!(f, "line {}: '{}' is not a time like 08:30", line, value)
+                }
+                TimetableError::UnknownOption { line, option } => {
+                    write!(f, "line {}: unknown option '{}'", line, option)
+                }
+            }
+        }
+    }
+
+    /// Split the location off the front of a line, returning it and the rest of the line.
+    fn parse_location(
+        line: &str,
+        line_number: usize,
+    ) -> Result<(StopLocation, &str), TimetableError> {
+        let bad = |text: &str| TimetableError::BadLocation {
+            line: line_number,
+            text: text.to_string(),
+        };
+
+        if let Some(quoted) = line.strip_prefix('"') {
+            // A backslash makes the next character part of the name, for `\"` and `\\`.
+            let mut name = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next().ok_or_else(|| bad(line))? {
+                    (_, '\\') => name.push(chars.next().ok_or_else(|| bad(line))?.1),
+                    (i, '"') => break i,
+                    (_, c) => name.push(c),
+                }
+            };
+            if name.is_empty() {
+                return Err(bad(line));
+            }
+            return Ok((StopLocation::Named(name), &quoted[end + 1..]));
+        }
+
+        let (first, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let (x, y) = first.split_once(',').ok_or_else(|| bad(first))?;
+        match (x.trim().parse(), y.trim().parse()) {
+            (Ok(x), Ok(y)) => Ok((StopLocation::Tile(x, y), rest)),
+            _ => Err(bad(first)),
+        }
+    }
+
+    /// Parse a time of day like `08:30` into minutes since midnight.
+    pub fn parse_time(text: &str) -> Option<u32> {
+        let (hours, minutes) = text.split_once(':')?;
+        let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+        (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+    }
+
+    pub fn format_time(minutes: u32) -> String {
+        format!("{:02}:{:02}", minutes / 60 % 24, minutes % 60)
+    }
+
+    /// The first of the daily `departures` at or after `after`, in game minutes since the
+    /// game began.
+    pub fn next_departure(departures: &[u32], after: f32) -> Option<f32> {
+        let day = (after / MINUTES_PER_DAY as f32).floor() * MINUTES_PER_DAY as f32;
+        departures
+            .iter()
+            .map(|x| {
+                let time = day + *x as f32;
+                match time < after {
+                    true => time + MINUTES_PER_DAY as f32,
+                    false => time,
+                }
+            })
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const DAY: f32 = MINUTES_PER_DAY as f32;
+
+        fn at(tile: (i32, i32), station: Option<&str>) -> VehicleStatus<'_> {
+            VehicleStatus {
+                tile,
+                station,
+                stopped: true,
+                full: false,
+            }
+        }
+
+        #[test]
+        fn parse_reads_every_option() {
+            let text =
+                "# Morning run\n\n3,-4 dwell 2.5\n\"Central\" depart 08:30 depart 17:05 full\n";
+            let timetable = Timetable::parse(text).unwrap();
+            assert_eq!(
+                timetable.stops,
+                vec![
+                    TimetableStop {
+                        location: StopLocation::Tile(3, -4),
+                        dwell: 2.5,
+                        departures: vec![],
+                        wait_for_full_load: false,
+                    },
+                    TimetableStop {
+                        location: StopLocation::Named("Central".to_string()),
+                        dwell: 0.0,
+                        departures: vec![8 * 60 + 30, 17 * 60 + 5],
+                        wait_for_full_load: true,
+                    },
+                ]
+            );
+            assert_eq!(Timetable::parse(&timetable.to_string()), Ok(timetable));
+        }
+
+        #[test]
+        fn station_names_with_quotes_round_trip() {
+            let names = ["The \"Flyer\" Halt", "C:\\Depot\\", "\"", "\\\""];
+            let timetable = Timetable {
+                stops: names
+                    .iter()
+                    .map(|name| TimetableStop {
+                        location: StopLocation::Named(name.to_string()),
+                        dwell: 1.0,
+                        departures: vec![],
+                        wait_for_full_load: false,
+                    })
+                    .collect(),
+            };
+            let text = timetable.to_string();
+            assert!(text.starts_with("\"The \\\"Flyer\\\" Halt\" dwell 1\n"));
+            assert_eq!(Timetable::parse(&text), Ok(timetable));
+
+            assert!(matches!(
+                Timetable::parse("\"Unfinished\\\""),
+                Err(TimetableError::BadLocation { line: 1, .. })
+            ));
+        }
+
+        #[test]
+        fn parse_reports_the_line_of_an_error() {
+            let error = |text| Timetable::parse(text).unwrap_err();
+            assert_eq!(
+                error("1,1\nsomewhere"),
+                TimetableError::BadLocation {
+                    line: 2,
+                    text: "somewhere".to_string()
+                }
+            );
+            assert!(matches!(
+                error("\"Central"),
+                TimetableError::BadLocation { line: 1, .. }
+            ));
+            assert!(matches!(
+                error("1,1 dwell"),
+                TimetableError::MissingValue { line: 1, .. }
+            ));
+            assert!(matches!(
+                error("1,1 dwell -3"),
+                TimetableError::BadNumber { line: 1, .. }
+            ));
+            assert!(matches!(
+                error("1,1 depart 24:00"),
+                TimetableError::BadTime { line: 1, .. }
+            ));
+            assert!(matches!(
+                error("1,1 fast"),
+                TimetableError::UnknownOption { line: 1, .. }
+            ));
+        }
+
+        #[test]
+        fn times_of_day() {
+            assert_eq!(parse_time("00:00"), Some(0));
+            assert_eq!(parse_time("23:59"), Some(MINUTES_PER_DAY as u32 - 1));
+            assert_eq!(parse_time("12:60"), None);
+            assert_eq!(parse_time("noon"), None);
+            assert_eq!(format_time(8 * 60 + 5), "08:05");
+            assert_eq!(format_time(MINUTES_PER_DAY as u32 + 61), "01:01");
+        }
+
+        #[test]
+        fn next_departure_wraps_to_the_next_day() {
+            let departures = [6 * 60, 18 * 60];
+            assert_eq!(next_departure(&departures, 5.0 * 60.0), Some(6.0 * 60.0));
+            assert_eq!(next_departure(&departures, 6.0 * 60.0), Some(6.0 * 60.0));
+            assert_eq!(
+                next_departure(&departures, 19.0 * 60.0),
+                Some(DAY + 6.0 * 60.0)
+            );
+            assert_eq!(
+                next_departure(&departures, 3.0 * DAY + 7.0 * 60.0),
+                Some(3.0 * DAY + 18.0 * 60.0)
+            );
+            assert_eq!(next_departure(&[], 0.0), None);
+        }
+
+        #[test]
+        fn vehicle_heads_for_each_stop_in_turn() {
+            let timetable = Timetable::parse("1,1 dwell 5\n\"Central\"").unwrap();
+            let mut state = TimetableState::default();
+
+            let moving = VehicleStatus {
+                stopped: false,
+                ..at((1, 1), None)
+            };
+            assert_eq!(
+                timetable.evaluate(&mut state, &moving, 0.0),
+                Order::HeadFor(0)
+            );
+            assert_eq!(
+                timetable.evaluate(&mut state, &at((2, 2), None), 0.0),
+                Order::HeadFor(0)
+            );
+
+            // The dwell counts from when the vehicle arrived.
+            assert_eq!(
+                timetable.evaluate(&mut state, &at((1, 1), None), 10.0),
+                Order::Wait
+            );
+            assert_eq!(state.arrived_at, Some(10.0));
+            assert_eq!(
+                timetable.evaluate(&mut state, &at((1, 1), None), 14.0),
+                Order::Wait
+            );
+            assert_eq!(
+                timetable.evaluate(&mut state, &at((1, 1), None), 15.0),
+                Order::Depart(1)
+            );
+
+            let central = at((9, 9), Some("Central"));
+            assert_eq!(
+                timetable.evaluate(&mut state, &central, 20.0),
+                Order::Depart(0)
+            );
+        }
+
+        #[test]
+        fn vehicle_waits_for_departure_time_and_full_load() {
+            let timetable = Timetable::parse("1,1 depart 23:30 full").unwrap();
+            let mut state = TimetableState::default();
+            let mut vehicle = at((1, 1), None);
+
+            // Arriving just after the departure means waiting for tomorrow's.
+            let arrival = DAY + 23.0 * 60.0 + 45.0;
+            assert_eq!(
+                timetable.evaluate(&mut state, &vehicle, arrival),
+                Order::Wait
+            );
+            let tomorrow = 2.0 * DAY + 23.0 * 60.0 + 30.0;
+            assert_eq!(
+                timetable.evaluate(&mut state, &vehicle, tomorrow - 1.0),
+                Order::Wait
+            );
+            assert_eq!(
+                timetable.evaluate(&mut state, &vehicle, tomorrow),
+                Order::Wait
+            );
+
+            vehicle.full = true;
+            assert_eq!(
+                timetable.evaluate(&mut state, &vehicle, tomorrow + 1.0),
+                Order::Depart(0)
+            );
+        }
+
+        #[test]
+        fn empty_timetable_waits() {
+            let mut state = TimetableState::default();
+            let order = Timetable::new().evaluate(&mut state, &at((0, 0), None), 0.0);
+            assert_eq!(order, Order::Wait);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+pub mod track {
+    use std::fmt;
+
+    /// The eight FreeTrain directions. Cardinal directions cross a tile edge into the
+    /// neighbouring tile; diagonal ones cross a tile corner. North is up-right on screen.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    pub enum Direction {
+        North,
+        NorthEast,
+        East,
+        SouthEast,
+        South,
+        SouthWest,
+        West,
+        NorthWest,
+    }
+
+    impl Direction {
+        pub const ALL: [Direction; 8] = [
+            Direction::North,
+            Direction::NorthEast,
+            Direction::East,
+            Direction::SouthEast,
+            Direction::South,
+            Direction::SouthWest,
+            Direction::West,
+            Direction::NorthWest,
+        ];
+
+        pub fn index(self) -> usize {
+            self as usize
+        }
+
+        pub fn from_index(i: usize) -> Direction {
+            Direction::ALL[i % 8]
+        }
+
+        pub fn opposite(self) -> Direction {
+            Direction::from_index(self.index() + 4)
+        }
+
+        /// Turn by 45 degree steps, clockwise for positive steps.
+        pub fn turn(self, steps: i32) -> Direction {
+            Direction::from_index((self.index() as i32 + steps).rem_euclid(8) as usize)
+        }
+
+        pub fn is_diagonal(self) -> bool {
+            self.index() % 2 == 1
+        }
+
+        /// The tile offset of the neighbour in this direction.
+        pub fn offset(self) -> (i32, i32) {
+            match self {
+                Direction::North => (0, -1),
+                Direction::NorthEast => (1, -1),
+                Direction::East => (1, 0),
+                Direction::SouthEast => (1, 1),
+                Direction::South => (0, 1),
+                Direction::SouthWest => (-1, 1),
+                Direction::West => (-1, 0),
+                Direction::NorthWest => (-1, -1),
+            }
+        }
+
+        pub fn from_offset(dx: i32, dy: i32) -> Option<Direction> {
+            Direction::ALL
+                .into_iter()
+                .find(|x| x.offset() == (dx.signum(), dy.signum()) && (dx, dy) != (0, 0))
+        }
+
+        pub fn parse(s: &str) -> Option<Direction> {
+            match s.trim() {
+                "N" | "north" => Some(Direction::North),
+                "NE" | "northEast" => Some(Direction::NorthEast),
+                "E" | "east" => Some(Direction::East),
+                "SE" | "southEast" => Some(Direction::SouthEast),
+                "S" | "south" => Some(Direction::South),
+                "SW" | "southWest" => Some(Direction::SouthWest),
+                "W" | "west" => Some(Direction::West),
+                "NW" | "northWest" => Some(Direction::NorthWest),
+                _ => None,
+            }
+        }
+
+        pub fn name(self) -> &'static str {
+            match self {
+                Direction::North => "N",
+                Direction::NorthEast => "NE",
+                Direction::East => "E",
+                Direction::SouthEast => "SE",
+                Direction::South => "S",
+                Direction::SouthWest => "SW",
+                Direction::West => "W",
+                Direction::NorthWest => "NW",
+            }
+        }
+    }
+
+    impl fmt::Display for Direction {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.name())
+        }
+    }
+
+    /// A piece of track crossing one tile, joining the `a` side to the `b` side. `rise` is
+    /// how many height levels the `b` end is above the `a` end; only straight cardinal
+    /// track can be sloped.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct TrackSegment {
+        pub a: Direction,
+        pub b: Direction,
+        pub rise: i8,
+    }
+
+    /// Every kind of track segment a tile can hold. A segment's position in this list is
+    /// its index in a `TrackSet`. Track can go straight or bend by 45 degrees; sharper
+    /// turns aren't allowed, as in FreeTrain.
+    pub const TRACK_SEGMENTS: [TrackSegment; 16] = [
+        // Straight
+        segment(Direction::North, Direction::South, 0),
+        segment(Direction::East, Direction::West, 0),
+        segment(Direction::NorthEast, Direction::SouthWest, 0),
+        segment(Direction::SouthEast, Direction::NorthWest, 0),
+        // Gentle curves between a cardinal and a diagonal direction
+        segment(Direction::North, Direction::SouthEast, 0),
+        segment(Direction::North, Direction::SouthWest, 0),
+        segment(Direction::East, Direction::SouthWest, 0),
+        segment(Direction::East, Direction::NorthWest, 0),
+        segment(Direction::South, Direction::NorthEast, 0),
+        segment(Direction::South, Direction::NorthWest, 0),
+        segment(Direction::West, Direction::NorthEast, 0),
+        segment(Direction::West, Direction::SouthEast, 0),
+        // Slopes, rising towards `b`
+        segment(Direction::South, Direction::North, 1),
+        segment(Direction::North, Direction::South, 1),
+        segment(Direction::West, Direction::East, 1),
+        segment(Direction::East, Direction::West, 1),
+    ];
+
+    const fn segment(a: Direction, b: Direction, rise: i8) -> TrackSegment {
+        TrackSegment { a, b, rise }
+    }
+
+    impl TrackSegment {
+        /// Find the segment joining two sides, in either order. A positive `rise` means
+        /// the track climbs going from `a` to `b`.
+        pub fn new(a: Direction, b: Direction, rise: i8) -> Option<TrackSegment> {
+            TRACK_SEGMENTS.into_iter().find(|x| {
+                (x.a == a && x.b == b && x.rise == rise)
+                    || (x.a == b && x.b == a && x.rise == -rise)
+            })
+        }
+
+        pub fn index(&self) -> usize {
+            TRACK_SEGMENTS.iter().position(|x| x == self).unwrap()
+        }
+
+        pub fn is_sloped(&self) -> bool {
+            self.rise != 0
+        }
+
+        pub fn is_straight(&self) -> bool {
+            self.a.opposite() == self.b
+        }
+
+        pub fn connects(&self, side: Direction) -> bool {
+            self.a == side || self.b == side
+        }
+
+        /// The side a train leaves from after entering through `side`.
+        pub fn exit(&self, side: Direction) -> Option<Direction> {
+            if self.a == side {
+                Some(self.b)
+            } else if self.b == side {
+                Some(self.a)
+            } else {
+                None
+            }
+        }
+
+        /// Length of the segment in tiles. Diagonal track crosses a tile corner to corner.
+        pub fn length(&self) -> f32 {
+            let half = |d: Direction| match d.is_diagonal() {
+                true => std::f32::consts::SQRT_2 / 2.0,
+                false => 0.5,
+            };
+            half(self.a) + half(self.b)
+        }
+
+        /// Height gained travelling through the segment, entering at `side`.
+        pub fn rise_from(&self, side: Direction) -> i8 {
+            match self.a == side {
+                true => self.rise,
+                false => -self.rise,
+            }
+        }
+    }
+
+    /// The track segments on one tile, stored as a bitset over `TRACK_SEGMENTS`.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub struct TrackSet(pub u16);
+
+    impl TrackSet {
+        pub fn is_empty(&self) -> bool {
+            self.0 == 0
+        }
+
+        pub fn contains(&self, segment: TrackSegment) -> bool {
+            self.0 & (1 << segment.index()) != 0
+        }
+
+        pub fn insert(&mut self, segment: TrackSegment) {
+            self.0 |= 1 << segment.index();
+        }
+
+        pub fn remove(&mut self, segment: TrackSegment) {
+            self.0 &= !(1 << segment.index());
+        }
+
+        pub fn len(&self) -> usize {
+            self.0.count_ones() as usize
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = TrackSegment> + '_ {
+            TRACK_SEGMENTS
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| self.0 & (1 << i) != 0)
+                .map(|(_, x)| x)
+        }
+
+        pub fn is_sloped(&self) -> bool {
+            self.iter().any(|x| x.is_sloped())
+        }
+
+        /// Whether any segment leaves the tile on this side.
+        pub fn connects(&self, side: Direction) -> bool {
+            self.iter().any(|x| x.connects(side))
+        }
+
+        /// A slope has to be the only track on its tile, and flat track can't be added to
+        /// a sloped tile.
+        pub fn can_insert(&self, segment: TrackSegment) -> bool {
+            if self.contains(segment) {
+                return false;
+            }
+            self.is_empty() || (!segment.is_sloped() && !self.is_sloped())
+        }
+    }
+
+    /// Where track is, as seen by anything that moves along it. `TileMap` implements this;
+    /// tests and tools can implement it without a window or textures.
+    pub trait TrackLayout {
+        /// The track on a tile. Tiles off the map have no track.
+        fn tracks(&self, x: i32, y: i32) -> TrackSet;
+
+        /// The station a tile's track belongs to, if it is a platform.
+        fn station_at(&self, _x: i32, _y: i32) -> Option<usize> {
+            None
+        }
+
+        /// Whether a signal stands on the given side of a tile.
+        fn signal_at(&self, _x: i32, _y: i32, _side: Direction) -> bool {
+            false
+        }
+
+        /// The signal block a tile's track is in. Without blocks, trains don't reserve
+        /// track and nothing stops them running into each other.
+        fn block_at(&self, _x: i32, _y: i32) -> Option<usize> {
+            None
+        }
+    }
+}
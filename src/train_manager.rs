@@ -0,0 +1,1070 @@
+pub mod train_manager {
+    use crate::{
+        clock::clock::TICK_SECONDS,
+        contribution_registry::contribution_registry::ContributionKey,
+        plugin_manager::plugin_manager::{TrainCarProperties, TrainProperties},
+        rail_graph::rail_graph::Route,
+        signal::signal::{find_deadlocks, Deadlock},
+        texture_manager::texture_manager::DrawableTileData,
+        track::track::{Direction, TrackLayout, TrackSegment},
+        util::util::iso_point_to_xy,
+    };
+    use macroquad::prelude::Vec2;
+    use std::{
+        collections::{HashMap, VecDeque},
+        fmt,
+    };
+
+    /// Game scale: a train at 120 km/h covers four tiles a second.
+    pub const KMH_TO_TILES_PER_SECOND: f32 = 1.0 / 30.0;
+
+    /// Length of one car, in tiles.
+    pub const CAR_LENGTH: f32 = 1.0;
+
+    /// Seconds a train waits at a station.
+    pub const STATION_DWELL: f32 = 5.0;
+
+    /// Seconds a train waits at a dead end before reversing.
+    pub const REVERSE_PAUSE: f32 = 1.0;
+
+    /// The most tiles ahead a train looks for somewhere to stop.
+    const MAX_LOOKAHEAD: usize = 1000;
+
+    /// A kind of car, from a `trainCar` contribution.
+    #[derive(Debug)]
+    pub struct CarType<'a> {
+        pub key: ContributionKey,
+        pub name: String,
+        pub capacity: u32,
+        /// One set of directional sprites per colour variant.
+        pub variants: Vec<DrawableTileData<'a>>,
+    }
+
+    /// A kind of train, from a `train` contribution.
+    #[derive(Debug)]
+    pub struct TrainType {
+        pub key: ContributionKey,
+        pub properties: TrainProperties,
+    }
+
+    /// A car id in a train's composition that no loaded `trainCar` contribution has.
+    #[derive(Debug, Clone)]
+    pub struct MissingCar {
+        pub train: ContributionKey,
+        pub car: String,
+    }
+
+    impl fmt::Display for MissingCar {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "train {} uses unknown car '{}'", self.train, self.car)
+        }
+    }
+
+    /// Every train and car type available to build. Trains refer to their cars by
+    /// contribution id, which may belong to another plugin, so cars are looked up when a
+    /// train is composed rather than when it is added.
+    #[derive(Debug, Default)]
+    pub struct TrainCatalogue<'a> {
+        cars: Vec<CarType<'a>>,
+        trains: Vec<TrainType>,
+    }
+
+    impl<'a> TrainCatalogue<'a> {
+        pub fn new() -> TrainCatalogue<'a> {
+            TrainCatalogue {
+                cars: Vec::new(),
+                trains: Vec::new(),
+            }
+        }
+
+        pub fn add_car(
+            &mut self,
+            key: ContributionKey,
+            properties: TrainCarProperties,
+            variants: Vec<DrawableTileData<'a>>,
+        ) -> usize {
+            self.cars.push(CarType {
+                key,
+                name: properties.name,
+                capacity: properties.capacity,
+                variants,
+            });
+            self.cars.len() - 1
+        }
+
+        pub fn add_train(&mut self, key: ContributionKey, properties: TrainProperties) -> usize {
+            self.trains.push(TrainType { key, properties });
+            self.trains.len() - 1
+        }
+
+        pub fn car(&self, car: usize) -> &CarType<'a> {
+            &self.cars[car]
+        }
+
+        pub fn train(&self, train: usize) -> &TrainType {
+            &self.trains[train]
+        }
+
+        pub fn cars(&self) -> &[CarType<'a>] {
+            &self.cars
+        }
+
+        pub fn trains(&self) -> &[TrainType] {
+            &self.trains
+        }
+
+        /// Find a car type by contribution id, preferring one from the given plugin.
+        pub fn find_car(&self, namespace: &str, id: &str) -> Option<usize> {
+            self.cars
+                .iter()
+                .position(|x| x.key.namespace == namespace && x.key.id == id)
+                .or_else(|| self.cars.iter().position(|x| x.key.id == id))
+        }
+
+        /// The car types making up a train of `length` cars, front to back.
+        pub fn compose(&self, train: usize, length: usize) -> Result<Vec<usize>, MissingCar> {
+            let train = &self.trains[train];
+            train
+                .properties
+                .composition
+                .cars(length)
+                .into_iter()
+                .map(|id| {
+                    self.find_car(&train.key.namespace, id)
+                        .ok_or_else(|| MissingCar {
+                            train: train.key.clone(),
+                            car: id.to_string(),
+                        })
+                })
+                .collect()
+        }
+
+        /// Check that every car each train refers to has been loaded.
+        pub fn missing_cars(&self) -> Vec<MissingCar> {
+            let mut missing = Vec::new();
+            for train in &self.trains {
+                let composition = &train.properties.composition;
+                let ids = composition
+                    .head
+                    .iter()
+                    .chain(Some(&composition.body))
+                    .chain(composition.tail.iter());
+
+                for id in ids {
+                    if self.find_car(&train.key.namespace, id).is_none() {
+                        missing.push(MissingCar {
+                            train: train.key.clone(),
+                            car: id.clone(),
+                        });
+                    }
+                }
+            }
+            missing
+        }
+    }
+
+    /// A tile a train is on, and which of its track segments, entered from which side.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct TrackTile {
+        pub x: i32,
+        pub y: i32,
+        pub segment: TrackSegment,
+        pub entry: Direction,
+    }
+
+    impl TrackTile {
+        pub fn exit(&self) -> Direction {
+            self.segment.exit(self.entry).unwrap()
+        }
+
+        pub fn length(&self) -> f32 {
+            self.segment.length()
+        }
+
+        /// The same piece of track, travelled the other way.
+        pub fn reversed(&self) -> TrackTile {
+            TrackTile {
+                entry: self.exit(),
+                ..*self
+            }
+        }
+
+        /// The tile this one leads to. Where track branches, straight on is preferred.
+        pub fn next(&self, layout: &impl TrackLayout) -> Option<TrackTile> {
+            self.next_towards(layout, None)
+        }
+
+        /// The tile this one leads to. Where track branches, the branch heading for the
+        /// tile `toward` is taken, or failing that straight on.
+        pub fn next_towards(
+            &self,
+            layout: &impl TrackLayout,
+            toward: Option<(i32, i32)>,
+        ) -> Option<TrackTile> {
+            let exit = self.exit();
+            let (dx, dy) = exit.offset();
+            let (x, y) = (self.x + dx, self.y + dy);
+            let entry = exit.opposite();
+
+            let tracks = layout.tracks(x, y);
+            let leads_to = |s: &TrackSegment, tile: (i32, i32)| {
+                let (dx, dy) = s.exit(entry).unwrap().offset();
+                (x + dx, y + dy) == tile
+            };
+            let candidates: Vec<_> = tracks.iter().filter(|s| s.connects(entry)).collect();
+            let segment = toward
+                .and_then(|t| candidates.iter().find(|s| leads_to(s, t)))
+                .or_else(|| candidates.iter().find(|s| s.exit(entry) == Some(exit)))
+                .or(candidates.first())?;
+
+            Some(TrackTile {
+                x,
+                y,
+                segment: *segment,
+                entry,
+            })
+        }
+
+        /// The point `distance` along the segment, in tile coordinates, and the heading
+        /// there in eighths of a turn clockwise from north. Curves are drawn as two straight
+        /// halves meeting at the tile centre.
+        pub fn point_at(&self, distance: f32) -> (f32, f32, f32) {
+            let exit = self.exit();
+            let side = |d: Direction| {
+                let (dx, dy) = d.offset();
+                (0.5 + dx as f32 * 0.5, 0.5 + dy as f32 * 0.5)
+            };
+            let half = |d: Direction| match d.is_diagonal() {
+                true => std::f32::consts::SQRT_2 / 2.0,
+                false => 0.5,
+            };
+
+            let (start, end) = (side(self.entry), side(exit));
+            let first_half = half(self.entry);
+            let (x, y) = if distance < first_half {
+                let t = distance / first_half;
+                (start.0 + (0.5 - start.0) * t, start.1 + (0.5 - start.1) * t)
+            } else {
+                let t = ((distance - first_half) / half(exit)).min(1.0);
+                (0.5 + (end.0 - 0.5) * t, 0.5 + (end.1 - 0.5) * t)
+            };
+
+            let heading_in = self.entry.opposite().index() as f32;
+            let turn =
+                (exit.index() as i32 - self.entry.opposite().index() as i32 + 4).rem_euclid(8) - 4;
+            let heading = heading_in + turn as f32 * (distance / self.length()).clamp(0.0, 1.0);
+
+            (self.x as f32 + x, self.y as f32 + y, heading)
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum TrainState {
+        Running,
+        /// Waiting at a station or dead end for this many more seconds.
+        Waiting(f32),
+    }
+
+    /// Why a train is stopping up ahead.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Stop {
+        Station(usize),
+        DeadEnd,
+        /// A signal in front of a block held by another train.
+        Signal(usize),
+    }
+
+    /// A train on the map: a list of cars following the track behind the head car.
+    #[derive(Debug, Clone)]
+    pub struct Train {
+        pub train_type: usize,
+        /// Car types, front to back.
+        pub cars: Vec<usize>,
+        pub variant: usize,
+        /// The tiles the train is on, front first.
+        path: VecDeque<TrackTile>,
+        /// How far the front of the train is along the first tile of `path`.
+        progress: f32,
+        /// Current speed in tiles per second.
+        pub speed: f32,
+        pub top_speed: f32,
+        pub acceleration: f32,
+        pub state: TrainState,
+        /// The station last stopped at, until the train has left it.
+        last_station: Option<usize>,
+        /// Tiles still to pass through on the way to a destination, next first.
+        route: VecDeque<(i32, i32)>,
+        /// The first tile of each block ahead that the train has reserved but not entered.
+        reserved: Vec<(i32, i32)>,
+        /// The block the train is stopped at a red signal for.
+        pub waiting_for: Option<usize>,
+    }
+
+    /// The parts of a train that change as it runs, for saving and loading. Reservations
+    /// and the last station stopped at are worked out again once the train moves.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct TrainSnapshot {
+        pub train_type: usize,
+        pub cars: Vec<usize>,
+        pub variant: usize,
+        /// The tiles the train is on, front first.
+        pub path: Vec<TrackTile>,
+        pub progress: f32,
+        pub speed: f32,
+        pub state: TrainState,
+        pub route: Vec<(i32, i32)>,
+    }
+
+    /// Where to draw one car.
+    #[derive(Debug, Clone, Copy)]
+    pub struct CarPosition {
+        pub car: usize,
+        pub variant: usize,
+        /// Position in world xy coordinates, as from `iso_to_xy`, with the track at level
+        /// 0. Raise it by the terrain height to draw it.
+        pub xy: Vec2,
+        /// Facing in eighths of a turn clockwise from north, for `draw_directional`.
+        pub heading: f32,
+    }
+
+    impl Train {
+        pub fn length(&self) -> f32 {
+            self.cars.len() as f32 * CAR_LENGTH
+        }
+
+        pub fn head(&self) -> TrackTile {
+            self.path[0]
+        }
+
+        /// The tiles the train is on, front first.
+        pub fn tiles(&self) -> impl Iterator<Item = &TrackTile> {
+            self.path.iter()
+        }
+
+        /// Find the tile and offset `distance` behind the front of the train.
+        fn locate(&self, distance: f32) -> (TrackTile, f32) {
+            let mut remaining = distance - self.progress;
+            if remaining <= 0.0 {
+                return (self.path[0], -remaining);
+            }
+            for tile in self.path.iter().skip(1) {
+                if remaining <= tile.length() {
+                    return (*tile, tile.length() - remaining);
+                }
+                remaining -= tile.length();
+            }
+            (*self.path.back().unwrap(), 0.0)
+        }
+
+        pub fn car_positions(&self) -> Vec<CarPosition> {
+            self.cars
+                .iter()
+                .enumerate()
+                .map(|(i, car)| {
+                    let (tile, offset) = self.locate((i as f32 + 0.5) * CAR_LENGTH);
+                    let (x, y, heading) = tile.point_at(offset);
+                    CarPosition {
+                        car: *car,
+                        variant: self.variant,
+                        xy: iso_point_to_xy(x, y, 0.0),
+                        heading,
+                    }
+                })
+                .collect()
+        }
+
+        /// Follow a route from `RailGraph::route`. The route should start where the train
+        /// is; at junctions the train takes the branch the route goes down.
+        pub fn set_route(&mut self, route: &Route) {
+            self.route = route.tiles.iter().copied().collect();
+            let head = self.path[0];
+            self.pass_route_tile((head.x, head.y));
+        }
+
+        pub fn route(&self) -> impl Iterator<Item = &(i32, i32)> {
+            self.route.iter()
+        }
+
+        pub fn snapshot(&self) -> TrainSnapshot {
+            TrainSnapshot {
+                train_type: self.train_type,
+                cars: self.cars.clone(),
+                variant: self.variant,
+                path: self.path.iter().copied().collect(),
+                progress: self.progress,
+                speed: self.speed,
+                state: self.state,
+                route: self.route.iter().copied().collect(),
+            }
+        }
+
+        fn pass_route_tile(&mut self, tile: (i32, i32)) {
+            if let Some(i) = self.route.iter().position(|x| *x == tile) {
+                self.route.drain(..=i);
+            }
+        }
+
+        /// The tile after `tile`, taking the branch the route wants at junctions.
+        fn next_tile(&self, tile: &TrackTile, layout: &impl TrackLayout) -> Option<TrackTile> {
+            let (dx, dy) = tile.exit().offset();
+            let next = (tile.x + dx, tile.y + dy);
+            let toward = self
+                .route
+                .iter()
+                .position(|x| *x == next)
+                .and_then(|i| self.route.get(i + 1))
+                .copied();
+            tile.next_towards(layout, toward)
+        }
+
+        /// Drop tiles from the back that the whole train has passed.
+        fn trim(&mut self) {
+            let mut covered = self.progress;
+            let mut keep = 1;
+            while keep < self.path.len() && covered < self.length() {
+                covered += self.path[keep].length();
+                keep += 1;
+            }
+            self.path.truncate(keep);
+        }
+
+        /// The distance to the next place the train has to stop, if it is within `limit`
+        /// and `MAX_LOOKAHEAD` tiles. Blocks within `limit` are reserved in `owners` on the
+        /// way, up to the first one another train holds.
+        fn next_stop(
+            &mut self,
+            layout: &impl TrackLayout,
+            limit: f32,
+            owners: &mut HashMap<usize, usize>,
+            id: usize,
+        ) -> Option<(f32, Stop)> {
+            self.reserved.clear();
+            let mut tile = self.path[0];
+            let mut distance = tile.length() - self.progress;
+            for _ in 0..MAX_LOOKAHEAD {
+                let next = self.next_tile(&tile, layout);
+                let station = layout.station_at(tile.x, tile.y);
+                let next_station = next.and_then(|n| layout.station_at(n.x, n.y));
+
+                // Stop with the front of the train at the far end of the platform.
+                if let Some(station) = station {
+                    if Some(station) != self.last_station && next_station != Some(station) {
+                        return Some((distance, Stop::Station(station)));
+                    }
+                }
+
+                let previous = tile;
+                tile = match next {
+                    Some(next) => next,
+                    None => return Some((distance, Stop::DeadEnd)),
+                };
+                if distance > limit {
+                    return None;
+                }
+
+                // Stop at the signal unless the block beyond is free or already ours.
+                let block = layout.block_at(tile.x, tile.y);
+                if let Some(block) =
+                    block.filter(|b| layout.block_at(previous.x, previous.y) != Some(*b))
+                {
+                    match owners.get(&block) {
+                        Some(owner) if *owner != id => {
+                            return Some((distance, Stop::Signal(block)))
+                        }
+                        _ => {
+                            owners.insert(block, id);
+                            self.reserved.push((tile.x, tile.y));
+                        }
+                    }
+                }
+                distance += tile.length();
+            }
+            None
+        }
+
+        /// Turn the train around where it stands, so the last car leads.
+        fn reverse(&mut self) {
+            let (tail, offset) = self.locate(self.length());
+            let end = self.path.iter().position(|x| *x == tail).unwrap();
+            self.path.truncate(end + 1);
+
+            self.path = self.path.iter().rev().map(|x| x.reversed()).collect();
+            self.progress = tail.length() - offset;
+            self.cars.reverse();
+        }
+
+        /// Advance the train by one tick of `TICK_SECONDS`. `owners` holds which train has
+        /// each signal block, and `id` is this train's index.
+        pub fn tick(
+            &mut self,
+            layout: &impl TrackLayout,
+            owners: &mut HashMap<usize, usize>,
+            id: usize,
+        ) {
+            let dt = TICK_SECONDS;
+
+            if let TrainState::Waiting(time) = self.state {
+                self.state = match time - dt {
+                    t if t > 0.0 => TrainState::Waiting(t),
+                    _ => TrainState::Running,
+                };
+                return;
+            }
+
+            let head = self.path[0];
+            if self.last_station.is_some() && layout.station_at(head.x, head.y) != self.last_station
+            {
+                self.last_station = None;
+            }
+
+            let braking = self.top_speed * self.top_speed / (2.0 * self.acceleration);
+            let stop = self.next_stop(layout, braking + self.top_speed * dt + 1.0, owners, id);
+            self.waiting_for = None;
+
+            // Speed up towards top speed, but never faster than can be braked from in time.
+            let mut speed = (self.speed + self.acceleration * dt).min(self.top_speed);
+            if let Some((distance, _)) = stop {
+                speed = speed.min((2.0 * self.acceleration * distance).sqrt());
+            }
+            self.speed = speed;
+
+            let mut step = speed * dt;
+            if let Some((distance, kind)) = stop {
+                if distance - step < 1e-3 {
+                    step = distance;
+                    self.speed = 0.0;
+                    match kind {
+                        Stop::Station(station) => {
+                            self.last_station = Some(station);
+                            self.state = TrainState::Waiting(STATION_DWELL);
+                        }
+                        Stop::DeadEnd => {
+                            self.advance(layout, step);
+                            self.reverse();
+                            self.reserved.clear();
+                            self.state = TrainState::Waiting(REVERSE_PAUSE);
+                            return;
+                        }
+                        Stop::Signal(block) => self.waiting_for = Some(block),
+                    }
+                }
+            }
+
+            self.advance(layout, step);
+        }
+
+        fn advance(&mut self, layout: &impl TrackLayout, distance: f32) {
+            self.progress += distance;
+            while self.progress > self.path[0].length() {
+                match self.next_tile(&self.path[0], layout) {
+                    Some(next) => {
+                        self.progress -= self.path[0].length();
+                        self.path.push_front(next);
+                        self.pass_route_tile((next.x, next.y));
+                    }
+                    None => {
+                        self.progress = self.path[0].length();
+                        break;
+                    }
+                }
+            }
+            self.trim();
+        }
+    }
+
+    /// Why a train couldn't be placed.
+    #[derive(Debug, Clone)]
+    pub enum SpawnError {
+        NoTrack,
+        /// There isn't enough track behind the tile to fit every car.
+        NotEnoughTrack,
+        MissingCar(MissingCar),
+        /// Another train is already in one of the blocks the train would be on.
+        Occupied,
+    }
+
+    impl fmt::Display for SpawnError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                SpawnError::NoTrack => write!(f, "there is no track here"),
+                SpawnError::NotEnoughTrack => write!(f, "the train doesn't fit on the track"),
+                SpawnError::MissingCar(missing) => write!(f, "{}", missing),
+                SpawnError::Occupied => write!(f, "another train is in the way"),
+            }
+        }
+    }
+
+    /// Every train on the map, stepped on the fixed timestep set by the `GameClock`.
+    #[derive(Debug, Default)]
+    pub struct TrainSimulation {
+        pub trains: Vec<Train>,
+    }
+
+    impl TrainSimulation {
+        pub fn new() -> TrainSimulation {
+            TrainSimulation::default()
+        }
+
+        /// Place a train with its front on the track at `at`, heading towards the first
+        /// segment's `b` side, with its cars strung out behind it.
+        pub fn spawn(
+            &mut self,
+            layout: &impl TrackLayout,
+            catalogue: &TrainCatalogue,
+            train_type: usize,
+            length: usize,
+            variant: usize,
+            at: (i32, i32),
+        ) -> Result<usize, SpawnError> {
+            let (x, y) = at;
+            let cars = catalogue
+                .compose(train_type, length)
+                .map_err(SpawnError::MissingCar)?;
+            let properties = &catalogue.train(train_type).properties;
+
+            let segment = match layout.tracks(x, y).iter().next() {
+                Some(segment) => segment,
+                None => return Err(SpawnError::NoTrack),
+            };
+            let head = TrackTile {
+                x,
+                y,
+                segment,
+                entry: segment.a,
+            };
+
+            // Walk backwards from the head to lay the rest of the train on the track.
+            let mut path = VecDeque::from([head]);
+            let mut covered = head.length();
+            let mut tile = head.reversed();
+            while covered < cars.len() as f32 * CAR_LENGTH {
+                tile = match tile.next(layout) {
+                    Some(next) if !path.iter().any(|x| (x.x, x.y) == (next.x, next.y)) => next,
+                    _ => return Err(SpawnError::NotEnoughTrack),
+                };
+                covered += tile.length();
+                path.push_back(tile.reversed());
+            }
+
+            if self.occupied(layout, path.iter()) {
+                return Err(SpawnError::Occupied);
+            }
+
+            self.trains.push(Train {
+                train_type,
+                cars,
+                variant,
+                path,
+                progress: head.length(),
+                speed: 0.0,
+                top_speed: properties.speed * KMH_TO_TILES_PER_SECOND,
+                acceleration: properties.acceleration * KMH_TO_TILES_PER_SECOND,
+                state: TrainState::Running,
+                last_station: None,
+                route: VecDeque::new(),
+                reserved: Vec::new(),
+                waiting_for: None,
+            });
+            Ok(self.trains.len() - 1)
+        }
+
+        /// Put back a train from a snapshot. Every tile it was on must still have the track
+        /// it was using.
+        pub fn restore(
+            &mut self,
+            layout: &impl TrackLayout,
+            catalogue: &TrainCatalogue,
+            snapshot: TrainSnapshot,
+        ) -> Result<usize, SpawnError> {
+            if snapshot.path.is_empty()
+                || snapshot
+                    .path
+                    .iter()
+                    .any(|x| !layout.tracks(x.x, x.y).contains(x.segment))
+            {
+                return Err(SpawnError::NoTrack);
+            }
+            if self.occupied(layout, snapshot.path.iter()) {
+                return Err(SpawnError::Occupied);
+            }
+
+            let properties = &catalogue.train(snapshot.train_type).properties;
+            self.trains.push(Train {
+                train_type: snapshot.train_type,
+                cars: snapshot.cars,
+                variant: snapshot.variant,
+                path: snapshot.path.into(),
+                progress: snapshot.progress,
+                speed: snapshot.speed,
+                top_speed: properties.speed * KMH_TO_TILES_PER_SECOND,
+                acceleration: properties.acceleration * KMH_TO_TILES_PER_SECOND,
+                state: snapshot.state,
+                last_station: None,
+                route: snapshot.route.into(),
+                reserved: Vec::new(),
+                waiting_for: None,
+            });
+            Ok(self.trains.len() - 1)
+        }
+
+        /// Whether another train holds a block any of these tiles are in.
+        fn occupied<'t>(
+            &self,
+            layout: &impl TrackLayout,
+            mut tiles: impl Iterator<Item = &'t TrackTile>,
+        ) -> bool {
+            let owners = self.block_owners(layout);
+            tiles.any(|x| {
+                layout
+                    .block_at(x.x, x.y)
+                    .is_some_and(|x| owners.contains_key(&x))
+            })
+        }
+
+        /// Run one fixed step for every train.
+        pub fn tick(&mut self, layout: &impl TrackLayout) {
+            let mut owners = self.block_owners(layout);
+            for (id, train) in self.trains.iter_mut().enumerate() {
+                train.tick(layout, &mut owners, id);
+            }
+        }
+
+        /// Which train holds each signal block, either by being in it or by having
+        /// reserved it ahead. Worked out afresh from the trains, since block ids change
+        /// whenever track or signals are edited.
+        pub fn block_owners(&self, layout: &impl TrackLayout) -> HashMap<usize, usize> {
+            let mut owners = HashMap::new();
+            for (id, train) in self.trains.iter().enumerate() {
+                let occupied = train.path.iter().map(|x| (x.x, x.y));
+                for (x, y) in occupied.chain(train.reserved.iter().copied()) {
+                    if let Some(block) = layout.block_at(x, y) {
+                        owners.entry(block).or_insert(id);
+                    }
+                }
+            }
+            owners
+        }
+
+        /// Groups of trains stuck waiting at signals for each other.
+        pub fn deadlocks(&self, layout: &impl TrackLayout) -> Vec<Deadlock> {
+            let waiting: Vec<_> = self.trains.iter().map(|x| x.waiting_for).collect();
+            find_deadlocks(&waiting, &self.block_owners(layout))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{
+            plugin_manager::plugin_manager::TrainComposition, signal::signal::BlockMap,
+            track::track::TrackSet,
+        };
+        use std::collections::HashSet;
+
+        /// Track laid out in memory, with no map or textures.
+        #[derive(Default)]
+        struct Layout {
+            tracks: HashMap<(i32, i32), TrackSet>,
+            stations: HashMap<(i32, i32), usize>,
+            signals: HashSet<((i32, i32), Direction)>,
+            blocks: Option<BlockMap>,
+        }
+
+        impl Layout {
+            /// Lay track starting at `start` and making each move in turn. A closed run
+            /// joins its last tile back to its first.
+            fn lay(&mut self, start: (i32, i32), moves: &[Direction], closed: bool) {
+                let mut tile = start;
+                for (i, exit) in moves.iter().enumerate() {
+                    let entry = match (i, closed) {
+                        (0, false) => exit.opposite(),
+                        (0, true) => moves[moves.len() - 1].opposite(),
+                        _ => moves[i - 1].opposite(),
+                    };
+                    let segment = TrackSegment::new(entry, *exit, 0).unwrap();
+                    self.tracks.entry(tile).or_default().insert(segment);
+                    let (dx, dy) = exit.offset();
+                    tile = (tile.0 + dx, tile.1 + dy);
+                }
+            }
+
+            /// Put up signals and split the track into blocks. Without this, trains run
+            /// without reserving anything.
+            fn signal(&mut self, signals: &[((i32, i32), Direction)]) {
+                self.signals.extend(signals);
+                self.blocks = Some(BlockMap::build(self, self.tracks.keys().copied()));
+            }
+        }
+
+        impl TrackLayout for Layout {
+            fn tracks(&self, x: i32, y: i32) -> TrackSet {
+                self.tracks.get(&(x, y)).copied().unwrap_or_default()
+            }
+
+            fn station_at(&self, x: i32, y: i32) -> Option<usize> {
+                self.stations.get(&(x, y)).copied()
+            }
+
+            fn signal_at(&self, x: i32, y: i32, side: Direction) -> bool {
+                self.signals.contains(&((x, y), side))
+            }
+
+            fn block_at(&self, x: i32, y: i32) -> Option<usize> {
+                self.blocks.as_ref()?.block_at(x, y)
+            }
+        }
+
+        fn catalogue() -> TrainCatalogue<'static> {
+            let mut catalogue = TrainCatalogue::new();
+            let key = |id: &str| ContributionKey::new("Test", id);
+            let car = TrainCarProperties {
+                name: "Car".to_string(),
+                capacity: 10,
+            };
+            catalogue.add_car(key("car"), car, vec![]);
+            let train = TrainProperties {
+                name: "Train".to_string(),
+                company: String::new(),
+                description: String::new(),
+                price: 0,
+                speed: 90.0,
+                acceleration: 30.0,
+                composition: TrainComposition {
+                    head: None,
+                    body: "car".to_string(),
+                    tail: None,
+                    min_length: 1,
+                    max_length: 10,
+                },
+            };
+            catalogue.add_train(key("train"), train);
+            catalogue
+        }
+
+        /// Spawn a two car train at `tile`, heading `heading`.
+        fn spawn(
+            simulation: &mut TrainSimulation,
+            layout: &Layout,
+            tile: (i32, i32),
+            heading: Direction,
+        ) -> usize {
+            let id = simulation
+                .spawn(layout, &catalogue(), 0, 2, 0, tile)
+                .unwrap();
+            if simulation.trains[id].head().exit() != heading {
+                simulation.trains[id].reverse();
+            }
+            id
+        }
+
+        fn line(length: usize) -> Layout {
+            let mut layout = Layout::default();
+            layout.lay((0, 0), &vec![Direction::East; length], false);
+            layout
+        }
+
+        /// A closed loop with no stations or junctions, and so nowhere to stop.
+        fn ring() -> Layout {
+            use Direction::*;
+            let mut moves = Vec::new();
+            for (side, corner) in [(East, SouthEast), (South, SouthWest), (West, NorthWest)] {
+                moves.extend([side; 4]);
+                moves.push(corner);
+            }
+            moves.extend([North; 4]);
+            moves.push(NorthEast);
+
+            let mut layout = Layout::default();
+            layout.lay((0, 0), &moves, true);
+            layout
+        }
+
+        #[test]
+        fn train_speeds_up_to_its_top_speed() {
+            let layout = line(400);
+            let mut simulation = TrainSimulation::new();
+            simulation
+                .spawn(&layout, &catalogue(), 0, 3, 0, (200, 0))
+                .unwrap();
+
+            let start = simulation.trains[0].head().x;
+            for _ in 0..400 {
+                simulation.tick(&layout);
+            }
+            let train = &simulation.trains[0];
+            assert_eq!(train.speed, train.top_speed);
+            assert!((train.head().x - start).abs() > 20);
+        }
+
+        #[test]
+        fn train_cars_follow_the_head() {
+            let layout = line(100);
+            let mut simulation = TrainSimulation::new();
+            simulation
+                .spawn(&layout, &catalogue(), 0, 4, 0, (50, 0))
+                .unwrap();
+            for _ in 0..50 {
+                simulation.tick(&layout);
+            }
+
+            let positions = simulation.trains[0].car_positions();
+            assert_eq!(positions.len(), 4);
+            for pair in positions.windows(2) {
+                let gap = pair[0].xy.distance(pair[1].xy);
+                let one_car = iso_point_to_xy(CAR_LENGTH, 0.0, 0.0).length();
+                assert!((gap - one_car).abs() < 1e-3, "cars {} apart", gap);
+            }
+        }
+
+        #[test]
+        fn train_reverses_at_a_dead_end() {
+            let layout = line(12);
+            let mut simulation = TrainSimulation::new();
+            simulation
+                .spawn(&layout, &catalogue(), 0, 2, 0, (6, 0))
+                .unwrap();
+            let heading = simulation.trains[0].head().exit();
+
+            let mut ticks = 0;
+            let mut tick_while = |simulation: &mut TrainSimulation, waiting: bool| {
+                while matches!(simulation.trains[0].state, TrainState::Waiting(_)) != waiting {
+                    simulation.tick(&layout);
+                    let train = &simulation.trains[0];
+                    assert!(train
+                        .tiles()
+                        .all(|x| layout.tracks(x.x, x.y).contains(x.segment)));
+                    ticks += 1;
+                    assert!(ticks < 1000, "the train never reached the end");
+                }
+            };
+            tick_while(&mut simulation, false);
+            assert_eq!(simulation.trains[0].speed, 0.0);
+            tick_while(&mut simulation, true);
+            assert_eq!(simulation.trains[0].head().exit(), heading.opposite());
+        }
+
+        #[test]
+        fn train_stops_at_a_station() {
+            let mut layout = line(100);
+            for x in 40..43 {
+                layout.stations.insert((x, 0), 7);
+            }
+            let mut simulation = TrainSimulation::new();
+            spawn(&mut simulation, &layout, (10, 0), Direction::East);
+
+            let stopped = (0..400).find_map(|_| {
+                simulation.tick(&layout);
+                let train = &simulation.trains[0];
+                matches!(train.state, TrainState::Waiting(_)).then(|| train.head())
+            });
+            let head = stopped.expect("the train never stopped");
+            assert_eq!((head.x, head.y), (42, 0));
+            assert_eq!(simulation.trains[0].speed, 0.0);
+        }
+
+        #[test]
+        fn train_runs_round_a_loop_with_nowhere_to_stop() {
+            let layout = ring();
+            let mut simulation = TrainSimulation::new();
+            simulation
+                .spawn(&layout, &catalogue(), 0, 3, 0, (2, 0))
+                .unwrap();
+            for _ in 0..1000 {
+                simulation.tick(&layout);
+            }
+            let train = &simulation.trains[0];
+            assert_eq!(train.speed, train.top_speed);
+            assert!(train
+                .tiles()
+                .all(|x| layout.tracks(x.x, x.y).contains(x.segment)));
+        }
+
+        #[test]
+        fn same_ticks_give_the_same_result() {
+            let layout = ring();
+            let run = || {
+                let mut simulation = TrainSimulation::new();
+                simulation
+                    .spawn(&layout, &catalogue(), 0, 3, 0, (2, 0))
+                    .unwrap();
+                for _ in 0..321 {
+                    simulation.tick(&layout);
+                }
+                simulation.trains[0].snapshot()
+            };
+            assert_eq!(run(), run());
+        }
+
+        #[test]
+        fn spawn_needs_room_for_every_car() {
+            let layout = line(3);
+            let mut simulation = TrainSimulation::new();
+            let res = simulation.spawn(&layout, &catalogue(), 0, 5, 0, (1, 0));
+            assert!(matches!(res, Err(SpawnError::NotEnoughTrack)));
+            let res = simulation.spawn(&layout, &catalogue(), 0, 1, 0, (10, 10));
+            assert!(matches!(res, Err(SpawnError::NoTrack)));
+        }
+
+        #[test]
+        fn train_reserves_the_block_ahead() {
+            let mut layout = line(40);
+            layout.signal(&[((22, 0), Direction::East)]);
+            let mut simulation = TrainSimulation::new();
+            spawn(&mut simulation, &layout, (20, 0), Direction::East);
+
+            let ahead = layout.block_at(23, 0).unwrap();
+            assert!(!simulation.block_owners(&layout).contains_key(&ahead));
+            simulation.tick(&layout);
+            assert_eq!(simulation.block_owners(&layout).get(&ahead), Some(&0));
+            assert!(!simulation.trains[0].tiles().any(|x| x.x > 22));
+
+            // Nothing else can be put in a block a train has reserved.
+            let res = simulation.spawn(&layout, &catalogue(), 0, 1, 0, (30, 0));
+            assert!(matches!(res, Err(SpawnError::Occupied)));
+        }
+
+        /// Two trains heading towards each other on a single line, split in two by a
+        /// signal between (30, 0) and (31, 0).
+        fn head_on() -> (Layout, TrainSimulation) {
+            let mut layout = line(60);
+            layout.signal(&[((30, 0), Direction::East)]);
+            let mut simulation = TrainSimulation::new();
+            spawn(&mut simulation, &layout, (50, 0), Direction::East);
+            spawn(&mut simulation, &layout, (20, 0), Direction::East);
+            (layout, simulation)
+        }
+
+        #[test]
+        fn train_waits_at_a_red_signal() {
+            let (layout, mut simulation) = head_on();
+            let east = layout.block_at(31, 0).unwrap();
+
+            for _ in 0..600 {
+                simulation.tick(&layout);
+                assert!(!simulation.trains[1].tiles().any(|x| x.x > 30));
+            }
+            let train = &simulation.trains[1];
+            assert_eq!((train.head().x, train.speed), (30, 0.0));
+            assert_eq!(train.waiting_for, Some(east));
+            assert_eq!(simulation.block_owners(&layout).get(&east), Some(&0));
+        }
+
+        #[test]
+        fn trains_facing_each_other_deadlock() {
+            let (layout, mut simulation) = head_on();
+            let west = layout.block_at(30, 0).unwrap();
+            let east = layout.block_at(31, 0).unwrap();
+
+            let mut ticks = 0;
+            while simulation.deadlocks(&layout).is_empty() {
+                simulation.tick(&layout);
+                ticks += 1;
+                assert!(ticks < 2000, "the trains never met");
+            }
+            assert_eq!(simulation.trains[0].head().x, 31);
+            let mut waits = simulation.deadlocks(&layout)[0].waits.clone();
+            waits.sort();
+            assert_eq!(waits, vec![(0, west), (1, east)]);
+        }
+    }
+}
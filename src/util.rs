@@ -6,14 +6,33 @@ pub mod util {
     pub const TILE_H: i32 = 16;
     pub const TILE_W_HALF: i32 = TILE_W / 2;
     pub const TILE_H_HALF: i32 = TILE_H / 2;
+    /// How far up the screen one level of terrain height is.
+    pub const TILE_Z: i32 = TILE_H_HALF;
 
     pub fn iso_to_xy(tile: &Tile) -> Vec2 {
         Vec2 {
             x: ((tile.x - tile.y - 1) * TILE_W_HALF) as f32,
-            y: ((tile.x + tile.y) * TILE_H_HALF) as f32,
+            y: ((tile.x + tile.y) * TILE_H_HALF - tile.z * TILE_Z) as f32,
         }
     }
 
+    /// Convert a fractional map position, in tiles, and height, in levels, to world xy
+    /// coordinates.
+    pub fn iso_point_to_xy(x: f32, y: f32, z: f32) -> Vec2 {
+        Vec2 {
+            x: (x - y) * TILE_W_HALF as f32,
+            y: (x + y) * TILE_H_HALF as f32 - z * TILE_Z as f32,
+        }
+    }
+
+    /// The fractional map position, in tiles, under a point in world xy coordinates, if
+    /// the ground there is at level 0.
+    pub fn xy_to_iso_point(point: Vec2) -> (f32, f32) {
+        let u = point.x / TILE_W_HALF as f32;
+        let v = point.y / TILE_H_HALF as f32;
+        ((v + u) / 2.0, (v - u) / 2.0)
+    }
+
     // fn iso_to_xy_midpoint(tile: &Tile) -> Vec2 {
     //     Vec2 {
     //         x: ((tile.x - tile.y) * TILE_W_HALF) as f32,
@@ -29,29 +48,39 @@ pub mod util {
     }
 
     pub fn xy_to_iso(point: Vec2) -> Tile {
-        let px = point.x as i32;
-        let py = 2 * (point.y as i32);
-
-        let x = (px + py) / TILE_W;
-        let y = -(px - py) / TILE_W;
-
-        //TODO
-        // (x, y, 0) is the base location. disambiguate the location.
-        // for z in (0..NUM_Z_LEVELS).rev()
-        // {
-        //     let loc = Tile {
-        //         x: x - z,
-        //         y: y + z,
-        //         z: z
-        //     };
-        //     if isSelectable(loc) {
-        //         return loc;
-        //     }
-        // }
+        let px = point.x.floor() as i32;
+        let py = 2 * (point.y.floor() as i32);
+
+        // Round down, so points off the top or left of the map give negative tiles
+        // rather than row or column 0.
+        let x = (px + py).div_euclid(TILE_W);
+        let y = (py - px).div_euclid(TILE_W);
 
         Tile { x, y, z: 0 }
     }
 
+    /// The tile under a point when the ground isn't flat. A tile raised `z` levels shows
+    /// up where a lower tile further down the screen would be, so this walks down from
+    /// `max_z` and returns the first tile at its level that `is_selectable` accepts, or
+    /// the level 0 tile if none do.
+    pub fn xy_to_iso_walking_z(
+        point: Vec2,
+        max_z: i32,
+        is_selectable: impl Fn(&Tile) -> bool,
+    ) -> Tile {
+        for z in (1..=max_z).rev() {
+            let mut loc = xy_to_iso(Vec2 {
+                x: point.x,
+                y: point.y + (z * TILE_Z) as f32,
+            });
+            loc.z = z;
+            if is_selectable(&loc) {
+                return loc;
+            }
+        }
+        xy_to_iso(point)
+    }
+
     pub fn screen_to_xy(screen: Vec2, origin: Vec2, scale: f32) -> Vec2 {
         Vec2 {
             x: (screen.x / scale) + origin.x,
@@ -62,14 +91,21 @@ pub mod util {
     /// Calculate the minimum diamond of iso coordinates that will bound a pair of xy-points.
     /// (This is used to determine visible isos.)
     pub fn min_iso_bounding_box_for_xy(p: (Vec2, Vec2)) -> (Tile, Tile) {
-        let origin = (p.0.x.min(p.1.x) as i32, p.0.y.min(p.1.y) as i32 * 2);
-        let extent = (p.0.x.max(p.1.x) as i32, p.0.y.max(p.1.y) as i32 * 2);
+        let origin = (
+            p.0.x.min(p.1.x).floor() as i32,
+            p.0.y.min(p.1.y).floor() as i32 * 2,
+        );
+        let extent = (
+            p.0.x.max(p.1.x).floor() as i32,
+            p.0.y.max(p.1.y).floor() as i32 * 2,
+        );
 
-        // Calculate tile coordinates using the same formulas in xy_to_iso()
-        let left = (origin.0 + origin.1) / TILE_W;
-        let right = (extent.0 + extent.1) / TILE_W + 1; // +1 for safety
-        let top = -(extent.0 - origin.1) / TILE_W;
-        let bottom = -(origin.0 - extent.1) / TILE_W + 1; // +1 for safety
+        // Calculate tile coordinates using the same formulas in xy_to_iso(), rounding down
+        // the same way for points left of or above the origin.
+        let left = (origin.0 + origin.1).div_euclid(TILE_W);
+        let right = (extent.0 + extent.1).div_euclid(TILE_W) + 1; // +1 for safety
+        let top = (origin.1 - extent.0).div_euclid(TILE_W);
+        let bottom = (extent.1 - origin.0).div_euclid(TILE_W) + 1; // +1 for safety
 
         (
             Tile {
@@ -85,8 +121,137 @@ pub mod util {
         )
     }
 
+    /// The tiles to draw for a view covering a pair of xy-points. Anything standing on a
+    /// tile can reach up to `height` world pixels above it, so tiles that far below the
+    /// view are included too.
+    pub fn visible_iso_bounds(p: (Vec2, Vec2), height: f32) -> (Tile, Tile) {
+        let bottom = p.0.y.max(p.1.y) + height.max(0.0);
+        let top = p.0.y.min(p.1.y);
+        min_iso_bounding_box_for_xy((
+            Vec2 {
+                x: p.0.x.min(p.1.x),
+                y: top,
+            },
+            Vec2 {
+                x: p.0.x.max(p.1.x),
+                y: bottom,
+            },
+        ))
+    }
+
     /// Size of the x,y bounding box that will cover w x h tiles.
     pub fn min_xy_bounding_box_for_iso_size(w: i32, h: i32) -> (i32, i32) {
         ((w + h) * TILE_W_HALF, (w + h) * TILE_H_HALF)
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn point(x: f32, y: f32) -> Vec2 {
+            Vec2 { x, y }
+        }
+
+        /// The centre of a tile's diamond at level 0.
+        fn centre(x: i32, y: i32) -> Vec2 {
+            iso_to_xy(&Tile { x, y, z: 0 }) + point(TILE_W_HALF as f32, TILE_H_HALF as f32)
+        }
+
+        fn contains(bounds: (Tile, Tile), tile: &Tile) -> bool {
+            (bounds.0.x..=bounds.1.x).contains(&tile.x)
+                && (bounds.0.y..=bounds.1.y).contains(&tile.y)
+        }
+
+        /// Every point in the view, every half pixel.
+        fn points(p: (Vec2, Vec2)) -> impl Iterator<Item = Vec2> {
+            let (w, h) = ((p.1.x - p.0.x) * 2.0, (p.1.y - p.0.y) * 2.0);
+            (0..=w as i32).flat_map(move |i| {
+                (0..=h as i32).map(move |j| point(p.0.x + i as f32 / 2.0, p.0.y + j as f32 / 2.0))
+            })
+        }
+
+        #[test]
+        fn xy_to_iso_round_trips() {
+            for x in -6..6 {
+                for y in -6..6 {
+                    let tile = xy_to_iso(centre(x, y));
+                    assert_eq!((tile.x, tile.y), (x, y));
+                    assert_eq!(
+                        xy_to_iso_point(centre(x, y)),
+                        (x as f32 + 0.5, y as f32 + 0.5)
+                    );
+                    assert_eq!(
+                        iso_point_to_xy(x as f32 + 0.5, y as f32 + 0.5, 0.0),
+                        centre(x, y)
+                    );
+                }
+            }
+        }
+
+        #[test]
+        fn xy_to_iso_rounds_down_off_the_map() {
+            let tile = xy_to_iso(point(-0.5, 0.25));
+            assert_eq!((tile.x, tile.y), (-1, 0));
+            let tile = xy_to_iso(point(0.0, -0.5));
+            assert_eq!((tile.x, tile.y), (-1, -1));
+        }
+
+        #[test]
+        fn bounding_box_covers_every_point() {
+            let views = [
+                (point(0.0, 0.0), point(200.0, 120.0)),
+                (point(-150.5, -90.25), point(-10.0, -3.0)),
+                (point(-64.0, -40.0), point(64.0, 40.0)),
+                (point(-7.0, 3.0), point(5.0, 9.5)),
+            ];
+            for view in views {
+                let bounds = min_iso_bounding_box_for_xy(view);
+                for p in points(view) {
+                    let tile = xy_to_iso(p);
+                    assert!(contains(bounds, &tile), "{:?} not in {:?}", tile, bounds);
+                }
+
+                // The box is no more than a tile bigger than it needs to be.
+                let corners = [
+                    view.0,
+                    view.1,
+                    point(view.0.x, view.1.y),
+                    point(view.1.x, view.0.y),
+                ];
+                let tiles: Vec<_> = corners.into_iter().map(xy_to_iso).collect();
+                assert_eq!(bounds.0.x, tiles.iter().map(|t| t.x).min().unwrap());
+                assert_eq!(bounds.0.y, tiles.iter().map(|t| t.y).min().unwrap());
+                assert!(bounds.1.x <= tiles.iter().map(|t| t.x).max().unwrap() + 1);
+                assert!(bounds.1.y <= tiles.iter().map(|t| t.y).max().unwrap() + 1);
+            }
+        }
+
+        #[test]
+        fn bounding_box_takes_the_points_in_any_order() {
+            let (a, b) = (point(-30.0, 12.0), point(45.0, -8.0));
+            assert_eq!(
+                min_iso_bounding_box_for_xy((a, b)),
+                min_iso_bounding_box_for_xy((b, a))
+            );
+        }
+
+        #[test]
+        fn visible_bounds_reach_down_for_tall_things() {
+            let view = (point(-100.0, -60.0), point(100.0, 60.0));
+            // A tile just below the bottom left corner, whose sprite reaches up into the
+            // view.
+            let below = xy_to_iso(point(-100.0, 60.0 + 2.5 * TILE_H as f32));
+
+            assert!(!contains(visible_iso_bounds(view, 0.0), &below));
+            assert!(!contains(visible_iso_bounds(view, -50.0), &below));
+            assert!(contains(
+                visible_iso_bounds(view, 3.0 * TILE_H as f32),
+                &below
+            ));
+            assert_eq!(
+                visible_iso_bounds(view, 0.0),
+                min_iso_bounding_box_for_xy(view)
+            );
+        }
+    }
 }
@@ -0,0 +1,155 @@
+pub mod vfs {
+    use encoding_rs::{SHIFT_JIS, UTF_8};
+    use std::{
+        collections::HashMap,
+        fs::{self, File},
+        io::{self, Read},
+        path::{Path, PathBuf},
+    };
+    use zip::ZipArchive;
+
+    /// A plugin's files, either a folder on disk or a zip archive laid out like one.
+    /// Names are relative to the folder containing plugin.xml and use `/` separators.
+    #[derive(Debug, Clone)]
+    pub enum VirtualDir {
+        Disk(PathBuf),
+        Zip(ZipDir),
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct ZipDir {
+        archive: PathBuf,
+        /// Maps a normalised entry name (relative to the plugin root) to its index in the archive.
+        entries: HashMap<String, usize>,
+    }
+
+    impl VirtualDir {
+        /// Open a plugin folder or `.zip` file. Returns `None` for anything else.
+        pub fn open(path: &Path) -> io::Result<Option<VirtualDir>> {
+            if path.is_dir() {
+                return Ok(Some(VirtualDir::Disk(path.to_path_buf())));
+            }
+            if is_zip(path) {
+                return Ok(Some(VirtualDir::Zip(ZipDir::open(path)?)));
+            }
+            Ok(None)
+        }
+
+        pub fn path(&self) -> &Path {
+            match self {
+                VirtualDir::Disk(path) => path,
+                VirtualDir::Zip(zip) => &zip.archive,
+            }
+        }
+
+        pub fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+            match self {
+                VirtualDir::Disk(path) => fs::read(path.join(name)),
+                VirtualDir::Zip(zip) => zip.read(name),
+            }
+        }
+
+        pub fn exists(&self, name: &str) -> bool {
+            match self {
+                VirtualDir::Disk(path) => path.join(name).is_file(),
+                VirtualDir::Zip(zip) => zip.find(name).is_some(),
+            }
+        }
+    }
+
+    pub fn is_zip(path: &Path) -> bool {
+        path.is_file()
+            && path
+                .extension()
+                .map(|x| x.eq_ignore_ascii_case("zip"))
+                .unwrap_or(false)
+    }
+
+    impl ZipDir {
+        fn open(archive: &Path) -> io::Result<ZipDir> {
+            let mut zip = ZipArchive::new(File::open(archive)?)?;
+
+            let mut names = Vec::new();
+            for i in 0..zip.len() {
+                let file = zip.by_index_raw(i)?;
+                if !file.is_dir() {
+                    names.push((decode_entry_name(file.name_raw()), i));
+                }
+            }
+
+            // Plugins are often zipped with their folder, so plugin.xml may not be at the
+            // top level. Use the shallowest one as the plugin root.
+            let root = names
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .filter(|name| {
+                    name.rsplit('/')
+                        .next()
+                        .unwrap()
+                        .eq_ignore_ascii_case("plugin.xml")
+                })
+                .min_by_key(|name| name.matches('/').count())
+                .map(|name| name[..name.len() - "plugin.xml".len()].to_string())
+                .unwrap_or_default();
+
+            let entries = names
+                .into_iter()
+                .filter_map(|(name, i)| {
+                    name.strip_prefix(root.as_str()).map(|x| (x.to_string(), i))
+                })
+                .collect();
+
+            Ok(ZipDir {
+                archive: archive.to_path_buf(),
+                entries,
+            })
+        }
+
+        /// Look up an entry, falling back to a case-insensitive match since FreeTrain
+        /// plugins were written for Windows and don't always match the archive's casing.
+        fn find(&self, name: &str) -> Option<usize> {
+            let name = normalise(name);
+            match self.entries.get(&name) {
+                Some(i) => Some(*i),
+                None => self
+                    .entries
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(&name))
+                    .map(|(_, i)| *i),
+            }
+        }
+
+        fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+            let index = match self.find(name) {
+                Some(i) => i,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} not found in {}", name, self.archive.display()),
+                    ))
+                }
+            };
+
+            let mut zip = ZipArchive::new(File::open(&self.archive)?)?;
+            let mut file = zip.by_index(index)?;
+            let mut data = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut data)?;
+            Ok(data)
+        }
+    }
+
+    fn normalise(name: &str) -> String {
+        name.replace('\\', "/").trim_start_matches("./").to_string()
+    }
+
+    /// Entry names in older Japanese archives are Shift-JIS without the UTF-8 flag set.
+    fn decode_entry_name(raw: &[u8]) -> String {
+        let (name, _, had_errors) = UTF_8.decode(raw);
+        let name = if had_errors {
+            SHIFT_JIS.decode(raw).0
+        } else {
+            name
+        };
+        normalise(&name)
+    }
+}